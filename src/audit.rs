@@ -0,0 +1,74 @@
+use crate::runner::RunStats;
+use chrono::{Local, NaiveDateTime};
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Appends one line describing the run to the JSONL file at `path`, creating
+/// it if needed, so scheduled runs leave a history that's easy to monitor.
+pub fn append(path: &Path, stats: &RunStats) -> io::Result<()> {
+    append_at(path, stats, Local::now().naive_local())
+}
+
+fn append_at(path: &Path, stats: &RunStats, timestamp: NaiveDateTime) -> io::Result<()> {
+    let line = json!({
+        "timestamp": timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+        "files_scanned": stats.files_scanned,
+        "highlights_found": stats.highlights_found,
+        "highlights_inserted": stats.highlights_inserted,
+        "highlights_duplicate": stats.highlights_duplicate,
+        "read_errors": stats.report.read_errors,
+        "parse_errors": stats.report.parse_errors,
+    });
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::ProcessReport;
+    use serde_json::Value;
+
+    #[test]
+    fn test_runs_accumulate_as_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let first = RunStats {
+            files_scanned: 3,
+            highlights_found: 5,
+            highlights_inserted: 5,
+            ..Default::default()
+        };
+        let second = RunStats {
+            files_scanned: 3,
+            highlights_found: 6,
+            highlights_inserted: 1,
+            highlights_duplicate: 5,
+            report: ProcessReport {
+                read_errors: 0,
+                parse_errors: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let noon =
+            NaiveDateTime::parse_from_str("2026-02-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        append_at(&path, &first, noon).unwrap();
+        append(&path, &second).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["timestamp"], "2026-02-01 12:00:00");
+        assert_eq!(lines[0]["highlights_inserted"], 5);
+        assert_eq!(lines[1]["highlights_duplicate"], 5);
+        assert_eq!(lines[1]["parse_errors"], 1);
+    }
+}
@@ -0,0 +1,87 @@
+use clap::ValueEnum;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Wraps console text in ANSI codes, or passes it through untouched when
+/// color is off. Only meant for the console log, never for exports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Paint {
+    enabled: bool,
+}
+
+impl Paint {
+    pub fn new(choice: ColorChoice, is_terminal: bool, no_color: bool) -> Self {
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => is_terminal && !no_color,
+        };
+        Paint { enabled }
+    }
+
+    /// A `Paint` that never colors anything.
+    pub fn plain() -> Self {
+        Paint::default()
+    }
+
+    pub fn green(&self, text: impl Display) -> String {
+        self.wrap("32", text)
+    }
+
+    pub fn dim(&self, text: impl Display) -> String {
+        self.wrap("2", text)
+    }
+
+    fn wrap(&self, code: &str, text: impl Display) -> String {
+        if self.enabled {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Whether the `NO_COLOR` convention (https://no-color.org) asks for plain
+/// output: the variable is set to anything but an empty string.
+pub fn no_color_requested() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_paint_emits_no_escapes() {
+        let disabled = [
+            Paint::plain(),
+            Paint::new(ColorChoice::Never, true, false),
+            Paint::new(ColorChoice::Auto, false, false),
+            Paint::new(ColorChoice::Auto, true, true),
+        ];
+
+        for paint in disabled {
+            assert_eq!(paint.green(12), "12");
+            assert_eq!(paint.dim("Duplicates: 3"), "Duplicates: 3");
+        }
+    }
+
+    #[test]
+    fn test_enabled_paint_wraps_text() {
+        let paint = Paint::new(ColorChoice::Always, false, true);
+
+        assert_eq!(paint.green(12), "\x1b[32m12\x1b[0m");
+        assert_eq!(
+            Paint::new(ColorChoice::Auto, true, false).dim("x"),
+            "\x1b[2mx\x1b[0m"
+        );
+    }
+}
@@ -1,10 +1,19 @@
-use chrono::{Datelike, Duration, Local, NaiveDate, Weekday};
+use chrono::{Datelike, Days, Duration, Local, NaiveDate, Weekday};
 use clap::Parser;
+use std::path::{Path, PathBuf};
+
+use crate::color::ColorChoice;
+use crate::db::{BookMatch, DbError, DuplicatePolicy, TableName};
+use crate::export::{self, Column, Format, JsonShape, QuoteStyle, Separator};
+use crate::parser::{
+    self, AuthorFormat, BookFilter, BookSort, DateFilter, DedupStrategy, PageRange, SortOrder,
+};
 
 const DEFAULT_BOOKS_PATH: &str = "/Volumes/Kindle/livros";
 const DEFAULT_DATABASE_PATH: &str = "./highlights.db";
+const DEFAULT_PREVIEW_LEN: usize = 60;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Default)]
 #[command(name = "koreader-highlights")]
 #[command(about = "Extract highlights from KOReader metadata files")]
 pub struct CliArgs {
@@ -16,6 +25,18 @@ pub struct CliArgs {
     #[arg(short, long)]
     pub database_path: Option<String>,
 
+    /// Table to store highlights in, to keep several sets in one database
+    #[arg(long)]
+    pub table: Option<String>,
+
+    /// How to recognise a book that's already in the database
+    #[arg(long, value_enum)]
+    pub book_match: Option<BookMatch>,
+
+    /// What to do with a highlight that's already in the database
+    #[arg(long, value_enum)]
+    pub on_duplicate: Option<DuplicatePolicy>,
+
     /// Start date (YYYY-MM-DD)
     #[arg(long)]
     pub from: Option<String>,
@@ -27,22 +48,369 @@ pub struct CliArgs {
     /// Get highlights from the last N days (mutually exclusive with --from/--to)
     #[arg(short, long)]
     pub last: Option<u32>,
+
+    /// Get highlights made at or after this moment ("YYYY-MM-DD HH:MM:SS")
+    #[arg(long)]
+    pub since_datetime: Option<String>,
+
+    /// Only import books whose title contains this text (repeatable)
+    #[arg(long)]
+    pub book: Vec<String>,
+
+    /// Only import books whose author contains this text (repeatable)
+    #[arg(long)]
+    pub author: Vec<String>,
+
+    /// Skip books whose title contains this text (repeatable)
+    #[arg(long)]
+    pub exclude_book: Vec<String>,
+
+    /// Skip books whose author contains this text (repeatable)
+    #[arg(long)]
+    pub exclude_author: Vec<String>,
+
+    /// Only books of this document type, e.g. pdf (from metadata.pdf.lua)
+    #[arg(long)]
+    pub doc_format: Option<String>,
+
+    /// Only keep highlights from this page on
+    #[arg(long)]
+    pub page_from: Option<i32>,
+
+    /// Only keep highlights up to this page
+    #[arg(long)]
+    pub page_to: Option<i32>,
+
+    /// Also import bookmarks that have a note attached
+    #[arg(long)]
+    pub include_bookmarks: bool,
+
+    /// Strip section numbers and stray whitespace from chapter names
+    #[arg(long)]
+    pub clean_chapters: bool,
+
+    /// Join highlights KOReader split into two touching selections
+    #[arg(long)]
+    pub merge_adjacent: bool,
+
+    /// Guess missing chapters from the highlight position in EPUBs
+    #[arg(long)]
+    pub infer_chapters: bool,
+
+    /// How to spot repeated annotations within a book
+    #[arg(long, value_enum)]
+    pub dedup_by: Option<DedupStrategy>,
+
+    /// Keep \r\n and \r line breaks in text and notes instead of turning them into \n
+    #[arg(long)]
+    pub no_normalize_newlines: bool,
+
+    /// Store author names as they are or as "Surname, Given"
+    #[arg(long, value_enum)]
+    pub author_format: Option<AuthorFormat>,
+
+    /// Report annotations that can't be imported instead of skipping them quietly
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Name books without a title after their .sdr directory
+    #[arg(long)]
+    pub title_from_path: bool,
+
+    /// Also store every annotation field as JSON in the raw_json column
+    #[arg(long)]
+    pub store_raw: bool,
+
+    /// Tag each highlight with the language of its text, or else the book's
+    #[arg(long)]
+    pub detect_language: bool,
+
+    /// Cut stored highlight text down to this many characters
+    #[arg(long)]
+    pub max_text_len: Option<usize>,
+
+    /// Export the highlights found in the period in this format
+    #[arg(short, long, value_enum)]
+    pub format: Option<Format>,
+
+    /// Export what's already in the database instead of scanning the books path
+    #[arg(long)]
+    pub from_db: bool,
+
+    /// With --from-db, export only highlights stored after this id (the
+    /// date range is ignored) and print the new highest id
+    #[arg(long)]
+    pub since_id: Option<i64>,
+
+    /// Don't open a database at all; only parse and export
+    #[arg(long)]
+    pub no_db: bool,
+
+    /// Write the export to this file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Add a koreader:// link to each highlight in JSON and Markdown exports
+    #[arg(long)]
+    pub with_links: bool,
+
+    /// Add word counts, content hashes and reading progress to JSON exports
+    #[arg(long)]
+    pub with_computed: bool,
+
+    /// Layout of JSON exports: one object per highlight, or per book
+    #[arg(long, value_enum)]
+    pub json_shape: Option<JsonShape>,
+
+    /// Add to the end of the --output file instead of replacing it (CSV and JSON lines)
+    #[arg(long)]
+    pub append: bool,
+
+    /// Indent JSON exports instead of writing them on one line
+    #[arg(long)]
+    pub json_pretty: bool,
+
+    /// Rename JSON keys, e.g. text=content,note=annotation
+    #[arg(long)]
+    pub field_map: Option<String>,
+
+    /// In Markdown exports, render notes as footnotes at the end of each book
+    #[arg(long)]
+    pub notes_as_footnotes: bool,
+
+    /// Start a combined Markdown export with YAML frontmatter (date and counts)
+    #[arg(long)]
+    pub frontmatter: bool,
+
+    /// Start a combined Markdown export with a linked table of contents
+    #[arg(long)]
+    pub toc: bool,
+
+    /// How Markdown exports show the highlighted text
+    #[arg(long, value_enum)]
+    pub quote_style: Option<QuoteStyle>,
+
+    /// What Markdown exports put between two highlights
+    #[arg(long, value_enum)]
+    pub separator: Option<Separator>,
+
+    /// In Markdown exports, follow each quote with its page number
+    #[arg(long)]
+    pub show_pages: bool,
+
+    /// Comma-separated columns for tabular exports (e.g. title,page,text)
+    #[arg(long)]
+    pub columns: Option<String>,
+
+    /// Field separator for CSV exports: one character, or `tab`
+    #[arg(long)]
+    pub delimiter: Option<String>,
+
+    /// Leave out the CSV header line, e.g. when appending to an existing file
+    #[arg(long)]
+    pub no_header: bool,
+
+    /// With --format markdown, write one file per book into the --output directory
+    #[arg(long)]
+    pub split: bool,
+
+    /// Order of each book's highlights in the console and exports
+    #[arg(long, value_enum)]
+    pub sort: Option<SortOrder>,
+
+    /// Order of the books in exports
+    #[arg(long, value_enum)]
+    pub book_sort: Option<BookSort>,
+
+    /// List each book's highlights last-to-first on the console (after --sort)
+    #[arg(long)]
+    pub reverse: bool,
+
+    /// Stop saving after this many new highlights (handy for a trial run)
+    #[arg(long)]
+    pub limit: Option<usize>,
+
+    /// Delete stored highlights made before this date (YYYY-MM-DD)
+    #[arg(long)]
+    pub prune_before: Option<String>,
+
+    /// Delete stored highlights whose metadata file no longer exists
+    #[arg(long)]
+    pub prune_missing: bool,
+
+    /// Rebuild the full-text search index from the stored highlights
+    #[arg(long)]
+    pub reindex: bool,
+
+    /// Copy the database to this file before changing anything in it
+    #[arg(long)]
+    pub backup: Option<String>,
+
+    /// Compact and optimize the database file after importing (and pruning)
+    #[arg(long, visible_alias = "optimize")]
+    pub vacuum: bool,
+
+    /// Color the console summary
+    #[arg(long, value_enum)]
+    pub color: Option<ColorChoice>,
+
+    /// Don't show the scan progress counter
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Don't mention it when the default date range is used
+    #[arg(long)]
+    pub no_notice: bool,
+
+    /// Characters of each new highlight to show on the console (0 = all)
+    #[arg(long)]
+    pub preview_len: Option<usize>,
+
+    /// Also list the highlights skipped because they're already stored
+    #[arg(long)]
+    pub show_duplicates: bool,
+
+    /// Print database totals (highlights, books, words) after the import
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Print how many highlights were found on each day of the period
+    #[arg(long)]
+    pub by_day: bool,
+
+    /// Stop at the first file that can't be read or parsed
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Append a JSON line with the run's totals to this file
+    #[arg(long)]
+    pub audit_log: Option<String>,
+
+    /// Keep running after the import and pick up files KOReader changes
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Print every author in the database and exit
+    #[arg(long)]
+    pub list_authors: bool,
+
+    /// Print every book title in the database and exit
+    #[arg(long)]
+    pub list_books: bool,
+
+    /// Print the last N stored highlights and exit
+    #[arg(long)]
+    pub recent: Option<usize>,
+
+    /// Print the resolved configuration and exit without scanning
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Create or migrate the database, print its path and schema version, and exit
+    #[arg(long)]
+    pub init_only: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Config {
     pub books_path: String,
     pub database_path: String,
+    pub table: TableName,
+    pub book_match: BookMatch,
+    pub on_duplicate: DuplicatePolicy,
     pub from_date: NaiveDate,
     pub to_date: NaiveDate,
+    /// What the import actually filters on; `from_date`/`to_date` are the
+    /// days it covers.
+    pub date_filter: DateFilter,
+    /// No date flag was given, so `date_filter` is the default week.
+    pub date_defaulted: bool,
+    pub book_filter: BookFilter,
+    pub page_range: PageRange,
+    pub include_bookmarks: bool,
+    pub clean_chapters: bool,
+    pub merge_adjacent: bool,
+    pub infer_chapters: bool,
+    pub dedup_by: DedupStrategy,
+    pub author_format: AuthorFormat,
+    pub normalize_newlines: bool,
+    pub strict: bool,
+    pub title_from_path: bool,
+    pub store_raw: bool,
+    pub max_text_len: Option<usize>,
+    pub detect_language: bool,
+    pub format: Option<Format>,
+    pub from_db: bool,
+    pub since_id: Option<i64>,
+    pub no_db: bool,
+    pub output: Option<String>,
+    pub columns: Vec<Column>,
+    pub delimiter: char,
+    pub no_header: bool,
+    pub with_links: bool,
+    pub with_computed: bool,
+    pub frontmatter: bool,
+    pub toc: bool,
+    pub quote_style: QuoteStyle,
+    pub separator: Separator,
+    pub show_pages: bool,
+    pub notes_as_footnotes: bool,
+    pub json_pretty: bool,
+    pub json_shape: JsonShape,
+    pub append: bool,
+    pub field_map: Vec<(String, String)>,
+    pub split: bool,
+    pub sort: Option<SortOrder>,
+    pub book_sort: Option<BookSort>,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+    pub prune_before: Option<NaiveDate>,
+    pub prune_missing: bool,
+    pub reindex: bool,
+    pub backup: Option<String>,
+    pub vacuum: bool,
+    pub stats: bool,
+    pub by_day: bool,
+    pub fail_fast: bool,
+    pub audit_log: Option<String>,
+    pub color: ColorChoice,
+    pub quiet: bool,
+    pub no_notice: bool,
+    pub preview_len: usize,
+    pub show_duplicates: bool,
+    pub watch: bool,
+    pub list_authors: bool,
+    pub list_books: bool,
+    pub recent: Option<usize>,
+    pub print_config: bool,
+    pub init_only: bool,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum ConfigError {
     InvalidDateFormat(String),
+    InvalidDatetimeFormat(String),
     InvalidDateRange,
     MutuallyExclusiveFlags,
     MissingFromDate,
+    UnknownColumn(String),
+    InvalidDelimiter(String),
+    UnknownField(String),
+    SplitRequiresMarkdownOutput,
+    EpubNotesRequiresOutput,
+    AppendRequiresOutput,
+    /// `--append` with an export that can't be appended to, by format name.
+    AppendNotSupported(String),
+    FromDbRequiresFormat,
+    SinceIdRequiresFromDb,
+    FromDbWithNoDb,
+    WatchWithNoDb,
+    InvalidPageRange,
+    InvalidDefaultLast(String),
+    /// `--last` reaching back past the earliest date chrono can represent.
+    InvalidLast(u32),
+    InvalidTableName(String),
+    DatabaseNotWritable(String),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -51,15 +419,73 @@ impl std::fmt::Display for ConfigError {
             ConfigError::InvalidDateFormat(s) => {
                 write!(f, "Invalid date format: '{}'. Expected YYYY-MM-DD", s)
             }
+            ConfigError::InvalidDatetimeFormat(s) => {
+                write!(f, "Invalid datetime: '{}'. Expected YYYY-MM-DD HH:MM:SS", s)
+            }
             ConfigError::InvalidDateRange => {
                 write!(f, "Invalid date range: --from must be before or equal to --to")
             }
             ConfigError::MutuallyExclusiveFlags => {
-                write!(f, "Use --from/--to OR --last, not both")
+                write!(f, "Use one of --from/--to, --last or --since-datetime")
             }
             ConfigError::MissingFromDate => {
                 write!(f, "Use --from together with --to")
             }
+            ConfigError::UnknownColumn(name) => {
+                let known: Vec<&str> = Column::ALL.iter().map(|c| c.name()).collect();
+                write!(f, "Unknown column: '{}'. Expected one of: {}", name, known.join(", "))
+            }
+            ConfigError::InvalidDelimiter(value) => {
+                write!(f, "Invalid delimiter: '{}'. Expected a single character or `tab`", value)
+            }
+            ConfigError::UnknownField(entry) => {
+                write!(
+                    f,
+                    "Invalid field mapping: '{}'. Expected field=name with a field from: {}",
+                    entry,
+                    export::JSON_FIELDS.join(", ")
+                )
+            }
+            // same wording as the database layer, which does the checking
+            ConfigError::InvalidTableName(name) => {
+                write!(f, "{}", DbError::InvalidTableName(name.clone()))
+            }
+            ConfigError::SplitRequiresMarkdownOutput => {
+                write!(f, "--split needs --format markdown and an --output directory")
+            }
+            ConfigError::EpubNotesRequiresOutput => {
+                write!(f, "--format epub-notes needs an --output .sdr directory or file")
+            }
+            ConfigError::AppendRequiresOutput => write!(f, "--append needs an --output file"),
+            ConfigError::AppendNotSupported(format) => write!(
+                f,
+                "Can't --append {} exports; only --format csv and --json-shape lines can be",
+                format
+            ),
+            ConfigError::SinceIdRequiresFromDb => {
+                write!(f, "--since-id only works with --from-db")
+            }
+            ConfigError::FromDbRequiresFormat => {
+                write!(f, "--from-db needs a --format to export to")
+            }
+            ConfigError::InvalidDefaultLast(value) => {
+                write!(f, "Invalid HIGHLIGHTS_DEFAULT_LAST: '{}'. Expected a number of days", value)
+            }
+            ConfigError::InvalidLast(days) => {
+                write!(f, "Invalid --last: {} days goes back too far", days)
+            }
+            ConfigError::InvalidPageRange => {
+                write!(f, "Invalid page range: --page-from must not be after --page-to")
+            }
+            ConfigError::FromDbWithNoDb => {
+                write!(f, "--from-db reads the database, so it can't be used with --no-db")
+            }
+            ConfigError::WatchWithNoDb => {
+                write!(f, "--watch saves what it picks up, so it can't be used with --no-db")
+            }
+            ConfigError::DatabaseNotWritable(dir) => {
+                write!(f, "Cannot create the database: '{}' is missing or read-only", dir)
+            }
         }
     }
 }
@@ -73,38 +499,446 @@ impl Config {
         Self::from_args(cli, Local::now().date_naive())
     }
 
-    fn from_args(cli: CliArgs, today: NaiveDate) -> Result<Self, ConfigError> {
-        let (from_date, to_date) = resolve_dates(&cli, today)?;
+    pub(crate) fn from_args(cli: CliArgs, today: NaiveDate) -> Result<Self, ConfigError> {
+        let date_filter = resolve_dates(&cli, today)?;
+        let date_defaulted = cli.from.is_none()
+            && cli.to.is_none()
+            && cli.last.is_none()
+            && cli.since_datetime.is_none();
+        let (from_date, to_date) = match date_filter {
+            DateFilter::Range(from, to) => (from, to),
+            DateFilter::SinceDatetime(since) => (since.date(), today),
+        };
 
         let books_path = cli
             .books_path
             .or_else(|| std::env::var("BOOKS_PATH").ok())
-            .unwrap_or_else(|| DEFAULT_BOOKS_PATH.to_string());
+            .map(|path| expand_path(&path))
+            .unwrap_or_else(detect_default_books_path);
 
         let database_path = cli
             .database_path
             .or_else(|| std::env::var("DATABASE_PATH").ok())
-            .unwrap_or_else(|| DEFAULT_DATABASE_PATH.to_string());
+            .map(|path| expand_path(&path))
+            .unwrap_or_else(default_database_path);
+
+        let table = match &cli.table {
+            Some(name) => {
+                TableName::new(name).map_err(|_| ConfigError::InvalidTableName(name.clone()))?
+            }
+            None => TableName::default(),
+        };
+
+        let columns = match &cli.columns {
+            Some(list) => export::parse_columns(list).map_err(ConfigError::UnknownColumn)?,
+            None => Column::ALL.to_vec(),
+        };
+        let delimiter = match &cli.delimiter {
+            Some(value) => export::parse_delimiter(value).map_err(ConfigError::InvalidDelimiter)?,
+            None => ',',
+        };
+        let field_map = match &cli.field_map {
+            Some(list) => export::parse_field_map(list).map_err(ConfigError::UnknownField)?,
+            None => Vec::new(),
+        };
+
+        if cli.split && (cli.format != Some(Format::Markdown) || cli.output.is_none()) {
+            return Err(ConfigError::SplitRequiresMarkdownOutput);
+        }
+        if cli.format == Some(Format::EpubNotes) && cli.output.is_none() {
+            return Err(ConfigError::EpubNotesRequiresOutput);
+        }
+        if cli.append {
+            if cli.output.is_none() {
+                return Err(ConfigError::AppendRequiresOutput);
+            }
+            let shape = cli.json_shape.unwrap_or_default();
+            match cli.format {
+                Some(format) if !export::appendable(format, shape) => {
+                    return Err(ConfigError::AppendNotSupported(format.name().to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        if cli.from_db && cli.format.is_none() {
+            return Err(ConfigError::FromDbRequiresFormat);
+        }
+        if cli.since_id.is_some() && !cli.from_db {
+            return Err(ConfigError::SinceIdRequiresFromDb);
+        }
+
+        if let (Some(from), Some(to)) = (cli.page_from, cli.page_to) {
+            if from > to {
+                return Err(ConfigError::InvalidPageRange);
+            }
+        }
+
+        if cli.from_db && cli.no_db {
+            return Err(ConfigError::FromDbWithNoDb);
+        }
+
+        if cli.watch && cli.no_db {
+            return Err(ConfigError::WatchWithNoDb);
+        }
+
+        let prune_before = cli.prune_before.as_deref().map(parse_date).transpose()?;
 
         Ok(Config {
             books_path,
             database_path,
+            table,
+            book_match: cli.book_match.unwrap_or_default(),
+            on_duplicate: cli.on_duplicate.unwrap_or_default(),
             from_date,
             to_date,
+            date_filter,
+            date_defaulted,
+            book_filter: BookFilter {
+                books: cli.book,
+                authors: cli.author,
+                exclude_books: cli.exclude_book,
+                exclude_authors: cli.exclude_author,
+                doc_format: cli.doc_format,
+            },
+            page_range: PageRange {
+                from: cli.page_from,
+                to: cli.page_to,
+            },
+            include_bookmarks: cli.include_bookmarks,
+            clean_chapters: cli.clean_chapters,
+            merge_adjacent: cli.merge_adjacent,
+            infer_chapters: cli.infer_chapters,
+            dedup_by: cli.dedup_by.unwrap_or_default(),
+            author_format: cli.author_format.unwrap_or_default(),
+            normalize_newlines: !cli.no_normalize_newlines,
+            strict: cli.strict,
+            title_from_path: cli.title_from_path,
+            store_raw: cli.store_raw,
+            max_text_len: cli.max_text_len,
+            detect_language: cli.detect_language,
+            format: cli.format,
+            from_db: cli.from_db,
+            since_id: cli.since_id,
+            no_db: cli.no_db,
+            output: cli.output,
+            columns,
+            delimiter,
+            no_header: cli.no_header,
+            with_links: cli.with_links,
+            with_computed: cli.with_computed,
+            frontmatter: cli.frontmatter,
+            toc: cli.toc,
+            quote_style: cli.quote_style.unwrap_or_default(),
+            separator: cli.separator.unwrap_or_default(),
+            show_pages: cli.show_pages,
+            notes_as_footnotes: cli.notes_as_footnotes,
+            json_pretty: cli.json_pretty,
+            json_shape: cli.json_shape.unwrap_or_default(),
+            append: cli.append,
+            field_map,
+            split: cli.split,
+            sort: cli.sort,
+            book_sort: cli.book_sort,
+            reverse: cli.reverse,
+            limit: cli.limit,
+            prune_before,
+            prune_missing: cli.prune_missing,
+            reindex: cli.reindex,
+            backup: cli.backup.map(|path| expand_path(&path)),
+            vacuum: cli.vacuum,
+            stats: cli.stats,
+            by_day: cli.by_day,
+            fail_fast: cli.fail_fast,
+            audit_log: cli.audit_log.map(|path| expand_path(&path)),
+            color: cli.color.unwrap_or_default(),
+            quiet: cli.quiet,
+            no_notice: cli.no_notice,
+            preview_len: cli.preview_len.unwrap_or(DEFAULT_PREVIEW_LEN),
+            show_duplicates: cli.show_duplicates,
+            watch: cli.watch,
+            list_authors: cli.list_authors,
+            list_books: cli.list_books,
+            recent: cli.recent,
+            print_config: cli.print_config,
+            init_only: cli.init_only,
         })
     }
 }
 
-fn resolve_dates(cli: &CliArgs, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), ConfigError> {
+impl Config {
+    /// Whether the console log goes to stderr, leaving stdout to the export.
+    pub fn logs_to_stderr(&self) -> bool {
+        self.format.is_some() && self.output.is_none()
+    }
+
+    /// A plain import into `:memory:` is thrown away on exit; with an export
+    /// it's a handy scratch database, so that's left alone.
+    pub fn discards_import(&self) -> bool {
+        self.database_path == ":memory:" && self.format.is_none() && self.output.is_none()
+    }
+
+    /// The directory that will hold the database file.
+    fn database_dir(&self) -> &Path {
+        match Path::new(&self.database_path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    /// Creates the directory of the default database when it's missing. The
+    /// data directory is ours to create, unlike a path the user picked, which
+    /// `validate` reports instead.
+    pub fn create_database_dir(&self) -> std::io::Result<()> {
+        let dir = self.database_dir();
+        if self.database_path == default_database_path() && !dir.exists() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the environment before any work is done: the directory that
+    /// will hold the database has to exist and be writable. Catches a wrong
+    /// `DATABASE_PATH` before a long scan rather than after it.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.database_path == ":memory:" {
+            return Ok(());
+        }
+
+        let dir = self.database_dir();
+        if dir.is_dir() && can_create_file_in(dir) {
+            Ok(())
+        } else {
+            Err(ConfigError::DatabaseNotWritable(dir.display().to_string()))
+        }
+    }
+}
+
+/// Whether a file can be created in `dir`, found by creating and removing
+/// one. The permission bits alone miss directories owned by someone else
+/// and read-only mounts.
+fn can_create_file_in(dir: &Path) -> bool {
+    let probe = dir.join(format!(".koreader-highlights-{}.tmp", std::process::id()));
+    let created = std::fs::OpenOptions::new().write(true).create_new(true).open(&probe);
+    match created {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let on_off = |flag: bool| if flag { "on" } else { "off" };
+        let columns: Vec<&str> = self.columns.iter().map(|c| c.name()).collect();
+
+        writeln!(f, "Books path:        {}", self.books_path)?;
+        writeln!(f, "Database:          {}", self.database_path)?;
+        writeln!(f, "Table:             {}", self.table)?;
+        writeln!(f, "Period:            {}", self.date_filter)?;
+        writeln!(f, "Include bookmarks: {}", on_off(self.include_bookmarks))?;
+        writeln!(
+            f,
+            "Format:            {}",
+            self.format.map(|fmt| fmt.name()).unwrap_or("none")
+        )?;
+        writeln!(
+            f,
+            "Output:            {}",
+            self.output.as_deref().unwrap_or("stdout")
+        )?;
+        writeln!(f, "Columns:           {}", columns.join(","))
+    }
+}
+
+/// Where the database lives unless `--database-path` or `DATABASE_PATH` say
+/// otherwise: `koreader-highlights/highlights.db` in the user's data
+/// directory, so every working directory shares one database. Falls back to
+/// `DEFAULT_DATABASE_PATH` when no data directory can be worked out.
+pub fn default_database_path() -> String {
+    default_database_path_with(std::env::consts::OS, |key| std::env::var(key).ok())
+}
+
+/// `default_database_path` for the platform `os` (as in
+/// `std::env::consts::OS`), with the environment read through `lookup`.
+/// `XDG_DATA_HOME` wins everywhere; otherwise it's the platform's usual
+/// place under `APPDATA` or `HOME`.
+fn default_database_path_with(os: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let var = |key: &str| lookup(key).filter(|value| !value.is_empty()).map(PathBuf::from);
+
+    let data_dir = var("XDG_DATA_HOME").or_else(|| match os {
+        "windows" => var("APPDATA"),
+        "macos" => var("HOME").map(|home| home.join("Library/Application Support")),
+        _ => var("HOME").map(|home| home.join(".local/share")),
+    });
+
+    match data_dir {
+        Some(dir) => dir
+            .join("koreader-highlights")
+            .join("highlights.db")
+            .to_string_lossy()
+            .into_owned(),
+        None => DEFAULT_DATABASE_PATH.to_string(),
+    }
+}
+
+/// Looks for a mounted e-reader in the usual places for this platform, falling
+/// back to `DEFAULT_BOOKS_PATH` when nothing is mounted.
+pub fn detect_default_books_path() -> String {
+    detect_books_path_in(Path::new("/"), &books_path_candidates(std::env::consts::OS))
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|| DEFAULT_BOOKS_PATH.to_string())
+}
+
+/// Candidate locations relative to the filesystem root on the platform `os`,
+/// most specific first. A `*` segment matches any directory (usually the
+/// user name on Linux).
+fn books_path_candidates(os: &str) -> Vec<String> {
+    match os {
+        "windows" => ('D'..='Z')
+            .flat_map(|drive| {
+                [
+                    format!("{}:\\documents", drive),
+                    format!("{}:\\koreader", drive),
+                ]
+            })
+            .collect(),
+        "macos" => ["Volumes/Kindle/livros", "Volumes/Kindle/documents", "Volumes/KOBOeReader"]
+            .map(String::from)
+            .to_vec(),
+        _ => [
+            "media/*/Kindle/documents",
+            "media/*/KOBOeReader",
+            "media/*/koreader",
+            "run/media/*/Kindle/documents",
+            "run/media/*/KOBOeReader",
+            "run/media/*/koreader",
+            "mnt/us/documents",
+        ]
+        .map(String::from)
+        .to_vec(),
+    }
+}
+
+fn detect_books_path_in(root: &Path, candidates: &[String]) -> Option<PathBuf> {
+    candidates
+        .iter()
+        .find_map(|candidate| expand_candidate(root, candidate).into_iter().next())
+}
+
+fn expand_candidate(root: &Path, candidate: &str) -> Vec<PathBuf> {
+    let mut matches = vec![root.to_path_buf()];
+
+    for segment in candidate.split(['/', '\\']).filter(|s| !s.is_empty()) {
+        matches = matches
+            .into_iter()
+            .flat_map(|dir| {
+                if segment == "*" {
+                    let mut children: Vec<PathBuf> = std::fs::read_dir(&dir)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|e| e.ok())
+                        .map(|e| e.path())
+                        .filter(|p| p.is_dir())
+                        .collect();
+                    children.sort();
+                    children
+                } else {
+                    // a `C:` segment only makes sense as the start of a path
+                    let next = if segment.ends_with(':') {
+                        PathBuf::from(format!("{}\\", segment))
+                    } else {
+                        dir.join(segment)
+                    };
+                    if next.is_dir() {
+                        vec![next]
+                    } else {
+                        Vec::new()
+                    }
+                }
+            })
+            .collect();
+    }
+
+    matches
+}
+
+/// Expands a leading `~` to the home directory and `$VAR` / `${VAR}` to the
+/// variable's value, like a shell would. Unset variables are left as written.
+pub fn expand_path(path: &str) -> String {
+    expand_path_with(path, |name| std::env::var(name).ok())
+}
+
+fn expand_path_with(path: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut rest = path;
+
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        if let Some(home) = lookup("HOME").or_else(|| lookup("USERPROFILE")) {
+            out.push_str(&home);
+            rest = &rest[1..];
+        }
+    }
+
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+
+        let (name, written, remaining) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &rest[dollar..dollar + end + 3], &braced[end + 1..]),
+                None => ("", &rest[dollar..], ""),
+            }
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], &rest[dollar..dollar + end + 1], &after[end..])
+        };
+
+        match lookup(name).filter(|_| !name.is_empty()) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(written),
+        }
+        rest = remaining;
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn resolve_dates(cli: &CliArgs, today: NaiveDate) -> Result<DateFilter, ConfigError> {
+    resolve_dates_with(cli, today, |name| std::env::var(name).ok())
+}
+
+/// `resolve_dates` with the environment read through `lookup`. Without any
+/// date flag, `HIGHLIGHTS_DEFAULT_LAST=N` means `--last N`; otherwise it's
+/// the week so far.
+fn resolve_dates_with(
+    cli: &CliArgs,
+    today: NaiveDate,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<DateFilter, ConfigError> {
     let has_from_to = cli.from.is_some() || cli.to.is_some();
     let has_last = cli.last.is_some();
+    let has_since = cli.since_datetime.is_some();
 
-    if has_from_to && has_last {
+    if [has_from_to, has_last, has_since].iter().filter(|&&set| set).count() > 1 {
         return Err(ConfigError::MutuallyExclusiveFlags);
     }
 
+    if let Some(ref since) = cli.since_datetime {
+        let since = parser::parse_datetime(since)
+            .ok_or_else(|| ConfigError::InvalidDatetimeFormat(since.clone()))?;
+        return Ok(DateFilter::SinceDatetime(since));
+    }
+
     if let Some(days) = cli.last {
-        return Ok(compute_last_n_days(today, days));
+        let (from, to) =
+            compute_last_n_days(today, days).ok_or(ConfigError::InvalidLast(days))?;
+        return Ok(DateFilter::Range(from, to));
     }
 
     if cli.to.is_some() && cli.from.is_none() {
@@ -115,18 +949,30 @@ fn resolve_dates(cli: &CliArgs, today: NaiveDate) -> Result<(NaiveDate, NaiveDat
         let from = parse_date(from_str)?;
         let to = match &cli.to {
             Some(to_str) => parse_date(to_str)?,
-            None => today - Duration::days(1), // yesterday
+            None => yesterday(today),
         };
 
         if from > to {
             return Err(ConfigError::InvalidDateRange);
         }
 
-        return Ok((from, to));
+        return Ok(DateFilter::Range(from, to));
+    }
+
+    if let Some(value) = lookup("HIGHLIGHTS_DEFAULT_LAST") {
+        let (from, to) = value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&days| days > 0)
+            .and_then(|days| compute_last_n_days(today, days))
+            .ok_or_else(|| ConfigError::InvalidDefaultLast(value.clone()))?;
+        return Ok(DateFilter::Range(from, to));
     }
 
     // Default: last Sunday to yesterday
-    Ok(compute_week_range(today))
+    let (from, to) = compute_week_range(today);
+    Ok(DateFilter::Range(from, to))
 }
 
 fn parse_date(s: &str) -> Result<NaiveDate, ConfigError> {
@@ -134,8 +980,13 @@ fn parse_date(s: &str) -> Result<NaiveDate, ConfigError> {
         .map_err(|_| ConfigError::InvalidDateFormat(s.to_string()))
 }
 
+/// The calendar day before `today`, rolling back over month and year ends.
+fn yesterday(today: NaiveDate) -> NaiveDate {
+    today.pred_opt().unwrap_or(NaiveDate::MIN)
+}
+
 fn compute_week_range(today: NaiveDate) -> (NaiveDate, NaiveDate) {
-    let yesterday = today - Duration::days(1);
+    let yesterday = yesterday(today);
 
     let days_since_sunday = match today.weekday() {
         Weekday::Sun => 7,
@@ -147,10 +998,11 @@ fn compute_week_range(today: NaiveDate) -> (NaiveDate, NaiveDate) {
     (last_sunday, yesterday)
 }
 
-fn compute_last_n_days(today: NaiveDate, days: u32) -> (NaiveDate, NaiveDate) {
-    let yesterday = today - Duration::days(1);
-    let from = today - Duration::days(days as i64);
-    (from, yesterday)
+/// `None` when `days` reaches back past `NaiveDate::MIN`.
+fn compute_last_n_days(today: NaiveDate, days: u32) -> Option<(NaiveDate, NaiveDate)> {
+    let yesterday = yesterday(today);
+    let from = today.checked_sub_days(Days::new(days as u64))?;
+    Some((from, yesterday))
 }
 
 #[cfg(test)]
@@ -163,11 +1015,10 @@ mod tests {
         last: Option<u32>,
     ) -> CliArgs {
         CliArgs {
-            books_path: None,
-            database_path: None,
             from: from.map(String::from),
             to: to.map(String::from),
             last,
+            ..Default::default()
         }
     }
 
@@ -208,6 +1059,27 @@ mod tests {
         assert_eq!(config.to_date, date(2026, 1, 31));   // yesterday
     }
 
+    #[test]
+    fn test_yesterday_across_year_end() {
+        let today = date(2026, 1, 1); // Thursday
+
+        let open_from = Config::from_args(make_cli(Some("2025-12-01"), None, None), today).unwrap();
+        let week = Config::from_args(make_cli(None, None, None), today).unwrap();
+
+        assert_eq!(open_from.to_date, date(2025, 12, 31));
+        assert_eq!(week.from_date, date(2025, 12, 28)); // last Sunday
+        assert_eq!(week.to_date, date(2025, 12, 31));
+    }
+
+    #[test]
+    fn test_last_one_day_on_march_first() {
+        let non_leap = Config::from_args(make_cli(None, None, Some(1)), date(2026, 3, 1)).unwrap();
+        let leap = Config::from_args(make_cli(None, None, Some(1)), date(2028, 3, 1)).unwrap();
+
+        assert_eq!((non_leap.from_date, non_leap.to_date), (date(2026, 2, 28), date(2026, 2, 28)));
+        assert_eq!((leap.from_date, leap.to_date), (date(2028, 2, 29), date(2028, 2, 29)));
+    }
+
     #[test]
     fn test_explicit_from_to() {
         let cli = make_cli(Some("2026-01-10"), Some("2026-01-20"), None);
@@ -250,6 +1122,45 @@ mod tests {
         assert_eq!(result, Err(ConfigError::MutuallyExclusiveFlags));
     }
 
+    #[test]
+    fn test_since_datetime_parsed() {
+        let mut cli = make_cli(None, None, None);
+        cli.since_datetime = Some("2026-01-28 18:45:00".to_string());
+        let today = date(2026, 2, 1);
+
+        let config = Config::from_args(cli, today).unwrap();
+
+        let since = date(2026, 1, 28).and_hms_opt(18, 45, 0).unwrap();
+        assert_eq!(config.date_filter, DateFilter::SinceDatetime(since));
+        assert_eq!(config.from_date, date(2026, 1, 28));
+        assert_eq!(config.to_date, today);
+    }
+
+    #[test]
+    fn test_since_datetime_invalid() {
+        let mut cli = make_cli(None, None, None);
+        cli.since_datetime = Some("2026-01-28".to_string());
+
+        let result = Config::from_args(cli, date(2026, 2, 1));
+
+        assert_eq!(result, Err(ConfigError::InvalidDatetimeFormat("2026-01-28".to_string())));
+    }
+
+    #[test]
+    fn test_since_datetime_excludes_other_date_flags() {
+        for mut cli in [
+            make_cli(Some("2026-01-10"), None, None),
+            make_cli(None, Some("2026-01-20"), None),
+            make_cli(None, None, Some(7)),
+        ] {
+            cli.since_datetime = Some("2026-01-28 18:45:00".to_string());
+
+            let result = Config::from_args(cli, date(2026, 2, 1));
+
+            assert_eq!(result, Err(ConfigError::MutuallyExclusiveFlags));
+        }
+    }
+
     #[test]
     fn test_invalid_date_range() {
         let cli = make_cli(Some("2026-01-20"), Some("2026-01-10"), None);
@@ -262,12 +1173,414 @@ mod tests {
 
     #[test]
     fn test_default_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let macos = books_path_candidates("macos");
+        assert_eq!(detect_books_path_in(root.path(), &macos), None);
+        std::fs::create_dir_all(root.path().join("Volumes/Kindle/livros")).unwrap();
+        assert_eq!(
+            detect_books_path_in(root.path(), &macos),
+            Some(root.path().join("Volumes/Kindle/livros"))
+        );
+
+        let home = |key: &str| (key == "HOME").then(|| "/Users/reader".to_string());
+        assert_eq!(
+            default_database_path_with("macos", home),
+            "/Users/reader/Library/Application Support/koreader-highlights/highlights.db"
+        );
+    }
+
+    #[test]
+    fn test_default_database_path_in_data_dir() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| vars.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string())
+        };
+
+        let xdg = default_database_path_with(
+            "linux",
+            env(&[("XDG_DATA_HOME", "/data/reader"), ("HOME", "/home/reader")]),
+        );
+        assert_eq!(xdg, "/data/reader/koreader-highlights/highlights.db");
+
+        let home = default_database_path_with(
+            "linux",
+            env(&[("XDG_DATA_HOME", ""), ("HOME", "/home/reader")]),
+        );
+        assert_eq!(home, "/home/reader/.local/share/koreader-highlights/highlights.db");
+
+        let appdata = default_database_path_with("windows", env(&[("APPDATA", "/AppData")]));
+        assert_eq!(Path::new(&appdata), Path::new("/AppData/koreader-highlights/highlights.db"));
+
+        assert_eq!(default_database_path_with("linux", env(&[])), "./highlights.db");
+    }
+
+    #[test]
+    fn test_books_path_candidates_per_platform() {
+        let windows = books_path_candidates("windows");
+        assert_eq!(windows[..2], ["D:\\documents", "D:\\koreader"]);
+        assert_eq!(windows.len(), 2 * 23);
+        assert_eq!(books_path_candidates("macos")[0], "Volumes/Kindle/livros");
+        assert!(books_path_candidates("linux").contains(&"media/*/koreader".to_string()));
+    }
+
+    #[test]
+    fn test_columns_default_to_all() {
         let cli = make_cli(None, None, None);
         let today = date(2026, 2, 1);
 
         let config = Config::from_args(cli, today).unwrap();
 
-        assert_eq!(config.books_path, "/Volumes/Kindle/livros");
-        assert_eq!(config.database_path, "./highlights.db");
+        assert_eq!(config.columns, Column::ALL.to_vec());
+    }
+
+    #[test]
+    fn test_custom_columns() {
+        let mut cli = make_cli(None, None, None);
+        cli.columns = Some("title, page,text".to_string());
+        let today = date(2026, 2, 1);
+
+        let config = Config::from_args(cli, today).unwrap();
+
+        assert_eq!(config.columns, vec![Column::Title, Column::Page, Column::Text]);
+    }
+
+    #[test]
+    fn test_unknown_column_is_error() {
+        let mut cli = make_cli(None, None, None);
+        cli.columns = Some("title,colour".to_string());
+        let today = date(2026, 2, 1);
+
+        let result = Config::from_args(cli, today);
+
+        assert_eq!(result, Err(ConfigError::UnknownColumn("colour".to_string())));
+    }
+
+    #[test]
+    fn test_delimiter_must_be_one_character() {
+        let mut cli = make_cli(None, None, None);
+        cli.delimiter = Some("||".to_string());
+        let result = Config::from_args(cli, date(2026, 2, 1));
+        assert_eq!(result, Err(ConfigError::InvalidDelimiter("||".to_string())));
+
+        let mut cli = make_cli(None, None, None);
+        cli.delimiter = Some("tab".to_string());
+        assert_eq!(Config::from_args(cli, date(2026, 2, 1)).unwrap().delimiter, '\t');
+    }
+
+    #[test]
+    fn test_print_config_shows_resolved_dates() {
+        let cli = make_cli(None, None, Some(7));
+        let today = date(2026, 2, 1);
+
+        let printed = Config::from_args(cli, today).unwrap().to_string();
+
+        assert!(printed.contains("Period:            2026-01-25 to 2026-01-31"));
+        assert!(printed.contains(&format!("Database:          {}", default_database_path())));
+        assert!(printed.contains("Format:            none"));
+    }
+
+    #[test]
+    fn test_prune_before_parsed() {
+        let mut cli = make_cli(None, None, None);
+        cli.prune_before = Some("2026-01-01".to_string());
+        let today = date(2026, 2, 1);
+
+        let config = Config::from_args(cli, today).unwrap();
+
+        assert_eq!(config.prune_before, Some(date(2026, 1, 1)));
+    }
+
+    #[test]
+    fn test_prune_before_invalid_date() {
+        let mut cli = make_cli(None, None, None);
+        cli.prune_before = Some("01/01/2026".to_string());
+        let today = date(2026, 2, 1);
+
+        let result = Config::from_args(cli, today);
+
+        assert_eq!(
+            result,
+            Err(ConfigError::InvalidDateFormat("01/01/2026".to_string()))
+        );
+    }
+
+    fn candidates(list: &[&str]) -> Vec<String> {
+        list.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn test_detect_books_path_with_wildcard() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("media/alice/koreader")).unwrap();
+
+        let found = detect_books_path_in(
+            root.path(),
+            &candidates(&["media/*/Kindle/documents", "media/*/koreader"]),
+        );
+
+        assert_eq!(found, Some(root.path().join("media/alice/koreader")));
+    }
+
+    #[test]
+    fn test_detect_books_path_prefers_earlier_candidate() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("media/bob/Kindle/documents")).unwrap();
+        std::fs::create_dir_all(root.path().join("media/bob/koreader")).unwrap();
+
+        let found = detect_books_path_in(
+            root.path(),
+            &candidates(&["media/*/Kindle/documents", "media/*/koreader"]),
+        );
+
+        assert_eq!(found, Some(root.path().join("media/bob/Kindle/documents")));
+    }
+
+    #[test]
+    fn test_detect_books_path_nothing_mounted() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("media/alice/usb-stick")).unwrap();
+
+        let found = detect_books_path_in(root.path(), &candidates(&["media/*/koreader"]));
+
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn test_split_requires_markdown_and_output() {
+        let today = date(2026, 2, 1);
+
+        let mut cli = make_cli(None, None, None);
+        cli.split = true;
+        cli.format = Some(Format::Csv);
+        cli.output = Some("out".to_string());
+        assert_eq!(
+            Config::from_args(cli, today),
+            Err(ConfigError::SplitRequiresMarkdownOutput)
+        );
+
+        let mut cli = make_cli(None, None, None);
+        cli.split = true;
+        cli.format = Some(Format::Markdown);
+        assert_eq!(
+            Config::from_args(cli, today),
+            Err(ConfigError::SplitRequiresMarkdownOutput)
+        );
+
+        let mut cli = make_cli(None, None, None);
+        cli.split = true;
+        cli.format = Some(Format::Markdown);
+        cli.output = Some("out".to_string());
+        assert!(Config::from_args(cli, today).unwrap().split);
+    }
+
+    #[test]
+    fn test_epub_notes_requires_output() {
+        let mut cli = make_cli(None, None, None);
+        cli.format = Some(Format::EpubNotes);
+        assert_eq!(
+            Config::from_args(cli, date(2026, 2, 1)),
+            Err(ConfigError::EpubNotesRequiresOutput)
+        );
+    }
+
+    #[test]
+    fn test_append_only_for_appendable_exports() {
+        let today = date(2026, 2, 1);
+        let cli = |format, shape| CliArgs {
+            format: Some(format),
+            json_shape: shape,
+            output: Some("out".to_string()),
+            append: true,
+            ..make_cli(None, None, None)
+        };
+
+        assert_eq!(
+            Config::from_args(cli(Format::Json, None), today),
+            Err(ConfigError::AppendNotSupported("json".to_string()))
+        );
+        assert_eq!(
+            Config::from_args(cli(Format::Markdown, None), today),
+            Err(ConfigError::AppendNotSupported("markdown".to_string()))
+        );
+        let lines = Config::from_args(cli(Format::Json, Some(JsonShape::Lines)), today);
+        assert!(lines.unwrap().append);
+        assert!(Config::from_args(cli(Format::Csv, None), today).is_ok());
+
+        let no_output = CliArgs {
+            output: None,
+            ..cli(Format::Csv, None)
+        };
+        assert_eq!(Config::from_args(no_output, today), Err(ConfigError::AppendRequiresOutput));
+    }
+
+    #[test]
+    fn test_from_db_requires_format() {
+        let mut cli = make_cli(None, None, None);
+        cli.from_db = true;
+
+        let result = Config::from_args(cli, date(2026, 2, 1));
+
+        assert_eq!(result, Err(ConfigError::FromDbRequiresFormat));
+
+        let mut cli = make_cli(None, None, None);
+        cli.from_db = true;
+        cli.no_db = true;
+        cli.format = Some(Format::Json);
+        assert_eq!(Config::from_args(cli, date(2026, 2, 1)), Err(ConfigError::FromDbWithNoDb));
+    }
+
+    #[test]
+    fn test_normalize_newlines_on_by_default() {
+        let today = date(2026, 2, 1);
+        assert!(Config::from_args(make_cli(None, None, None), today).unwrap().normalize_newlines);
+
+        let mut cli = make_cli(None, None, None);
+        cli.no_normalize_newlines = true;
+        assert!(!Config::from_args(cli, today).unwrap().normalize_newlines);
+    }
+
+    #[test]
+    fn test_watch_with_no_db_is_error() {
+        let mut cli = make_cli(None, None, None);
+        cli.watch = true;
+        cli.no_db = true;
+        assert_eq!(Config::from_args(cli, date(2026, 2, 1)), Err(ConfigError::WatchWithNoDb));
+    }
+
+    #[test]
+    fn test_custom_table_name() {
+        let mut cli = make_cli(None, None, None);
+        cli.table = Some("fiction".to_string());
+        let today = date(2026, 2, 1);
+
+        let config = Config::from_args(cli, today).unwrap();
+
+        assert_eq!(config.table.as_str(), "fiction");
+    }
+
+    #[test]
+    fn test_invalid_table_name_is_error() {
+        let mut cli = make_cli(None, None, None);
+        cli.table = Some("foo;bar".to_string());
+        let today = date(2026, 2, 1);
+
+        let result = Config::from_args(cli, today);
+
+        assert_eq!(result, Err(ConfigError::InvalidTableName("foo;bar".to_string())));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            DbError::InvalidTableName("foo;bar".to_string()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_page_range_order_checked() {
+        let mut cli = make_cli(None, None, None);
+        cli.page_from = Some(50);
+        cli.page_to = Some(10);
+
+        assert_eq!(Config::from_args(cli, date(2026, 2, 1)), Err(ConfigError::InvalidPageRange));
+    }
+
+    #[test]
+    fn test_default_last_from_env() {
+        let today = date(2026, 2, 1);
+        let env = |name: &str| (name == "HIGHLIGHTS_DEFAULT_LAST").then(|| "3".to_string());
+
+        let filter = resolve_dates_with(&make_cli(None, None, None), today, env).unwrap();
+        assert_eq!(filter, DateFilter::Range(date(2026, 1, 29), date(2026, 1, 31)));
+
+        // any date flag beats the environment
+        let filter = resolve_dates_with(&make_cli(None, None, Some(10)), today, env).unwrap();
+        assert_eq!(filter, DateFilter::Range(date(2026, 1, 22), date(2026, 1, 31)));
+        let cli = make_cli(Some("2026-01-05"), Some("2026-01-06"), None);
+        let filter = resolve_dates_with(&cli, today, env).unwrap();
+        assert_eq!(filter, DateFilter::Range(date(2026, 1, 5), date(2026, 1, 6)));
+
+        let bad = |_: &str| Some("a week".to_string());
+        assert_eq!(
+            resolve_dates_with(&make_cli(None, None, None), today, bad),
+            Err(ConfigError::InvalidDefaultLast("a week".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_last_out_of_range_is_error() {
+        let today = date(2026, 2, 1);
+
+        for value in ["0", "4294967295"] {
+            let env = |_: &str| Some(value.to_string());
+            assert_eq!(
+                resolve_dates_with(&make_cli(None, None, None), today, env),
+                Err(ConfigError::InvalidDefaultLast(value.to_string()))
+            );
+        }
+        assert_eq!(
+            Config::from_args(make_cli(None, None, Some(u32::MAX)), today),
+            Err(ConfigError::InvalidLast(u32::MAX))
+        );
+    }
+
+    fn fake_env(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/reader".to_string()),
+            "BOOKS" => Some("/mnt/books".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_path_tilde() {
+        assert_eq!(expand_path_with("~/Books", fake_env), "/home/reader/Books");
+        assert_eq!(expand_path_with("~", fake_env), "/home/reader");
+        // only a leading ~ on its own means home
+        assert_eq!(expand_path_with("~other/Books", fake_env), "~other/Books");
+        assert_eq!(expand_path_with("/data/~/x", fake_env), "/data/~/x");
+    }
+
+    #[test]
+    fn test_expand_path_variables() {
+        assert_eq!(expand_path_with("$HOME/highlights.db", fake_env), "/home/reader/highlights.db");
+        assert_eq!(expand_path_with("${BOOKS}_old/x", fake_env), "/mnt/books_old/x");
+        assert_eq!(expand_path_with("$NOPE/x", fake_env), "$NOPE/x");
+        assert_eq!(expand_path_with("${NOPE}/x", fake_env), "${NOPE}/x");
+        assert_eq!(expand_path_with("costs $5", fake_env), "costs $5");
+        assert_eq!(expand_path_with("${unclosed", fake_env), "${unclosed");
+    }
+
+    fn config_with_database(path: &Path) -> Config {
+        let cli = CliArgs {
+            database_path: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        Config::from_args(cli, date(2026, 2, 1)).unwrap()
+    }
+
+    #[test]
+    fn test_validate_database_directory() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(config_with_database(&root.path().join("h.db")).validate(), Ok(()));
+
+        let missing = root.path().join("nope");
+        assert_eq!(
+            config_with_database(&missing.join("h.db")).validate(),
+            Err(ConfigError::DatabaseNotWritable(missing.display().to_string()))
+        );
+
+        let locked = root.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        let original = std::fs::metadata(&locked).unwrap().permissions();
+        let mut read_only = original.clone();
+        read_only.set_readonly(true);
+        std::fs::set_permissions(&locked, read_only).unwrap();
+        let result = config_with_database(&locked.join("h.db")).validate();
+        // root writes through the permission bits, so it has nothing to report
+        let writable = can_create_file_in(&locked);
+        std::fs::set_permissions(&locked, original).unwrap();
+        if !writable {
+            assert!(matches!(result, Err(ConfigError::DatabaseNotWritable(_))));
+        }
+        assert_eq!(std::fs::read_dir(&locked).unwrap().count(), 0);
+
+        assert_eq!(config_with_database(Path::new(":memory:")).validate(), Ok(()));
     }
 }
@@ -1,11 +1,16 @@
-use crate::models::Highlight;
-use rusqlite::{params, Connection};
+use crate::models::{BookData, Highlight, HighlightKind, StoredHighlight};
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::ValueEnum;
+use rusqlite::{params, Connection, DatabaseName, OpenFlags, OptionalExtension};
 use std::path::Path;
 
 #[derive(Debug)]
 pub enum DbError {
     ConnectionFailed(String),
     QueryFailed(String),
+    InvalidTableName(String),
+    /// A highlight that's already stored, with `DuplicatePolicy::Error`.
+    DuplicateHighlight(String),
 }
 
 impl std::fmt::Display for DbError {
@@ -13,6 +18,12 @@ impl std::fmt::Display for DbError {
         match self {
             DbError::ConnectionFailed(e) => write!(f, "Failed to connect to database: {}", e),
             DbError::QueryFailed(e) => write!(f, "Query failed: {}", e),
+            DbError::InvalidTableName(name) => write!(
+                f,
+                "Invalid table name: '{}'. Use letters, digits and underscores only",
+                name
+            ),
+            DbError::DuplicateHighlight(what) => write!(f, "Highlight already stored: {}", what),
         }
     }
 }
@@ -25,15 +36,213 @@ impl From<rusqlite::Error> for DbError {
     }
 }
 
-pub fn init_db(path: &Path) -> Result<Connection, DbError> {
+pub const DEFAULT_TABLE: &str = "highlights";
+
+/// Name of the table highlights are stored in. It ends up spliced into SQL,
+/// so only plain identifiers (`[A-Za-z_][A-Za-z0-9_]*`) are accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableName(String);
+
+impl TableName {
+    pub fn new(name: &str) -> Result<Self, DbError> {
+        let mut chars = name.chars();
+        let valid_start = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+        let valid_rest = chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        // sqlite_ is reserved for SQLite's own tables
+        let reserved = name.to_ascii_lowercase().starts_with("sqlite_");
+
+        if valid_start && valid_rest && !reserved {
+            Ok(TableName(name.to_string()))
+        } else {
+            Err(DbError::InvalidTableName(name.to_string()))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for TableName {
+    fn default() -> Self {
+        TableName(DEFAULT_TABLE.to_string())
+    }
+}
+
+impl std::fmt::Display for TableName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Columns every highlights table has besides `id` and `book_id`, in the order
+/// they're copied when migrating and exposed through the flat view.
+const HIGHLIGHT_COLUMNS: &str = "chapter, page, text, note, datetime, processed, created_at, \
+     kind, raw_datetime, pos0, pos1, word_count, updated_at, text_edited, source_path, raw_json, \
+     truncated, lang, text_hash";
+
+/// Name of the view that joins highlights back to their book, giving the
+/// one-row-per-highlight shape older versions stored directly.
+pub fn flat_view_name(table: &TableName) -> String {
+    format!("{}_flat", table)
+}
+
+/// Recorded in SQLite's `user_version` by `init_db`. Bump it whenever
+/// `init_db` starts creating or migrating something new.
+pub const SCHEMA_VERSION: i32 = 1;
+
+/// Opens an existing database for reading only, for exports. Nothing is
+/// created or migrated, and a concurrent import can't be disturbed.
+pub fn open_read_only(path: &Path) -> Result<Connection, DbError> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| DbError::ConnectionFailed(e.to_string()))
+}
+
+pub fn init_db(path: &Path, table: &TableName) -> Result<Connection, DbError> {
     let conn = Connection::open(path)
         .map_err(|e| DbError::ConnectionFailed(e.to_string()))?;
 
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS highlights (
+        "CREATE TABLE IF NOT EXISTS books (
             id INTEGER PRIMARY KEY,
-            book_title TEXT NOT NULL,
-            book_author TEXT NOT NULL,
+            title TEXT NOT NULL,
+            author TEXT NOT NULL,
+            series TEXT,
+            language TEXT,
+            description TEXT,
+            doc_format TEXT,
+            UNIQUE(title, author)
+        )",
+        [],
+    )?;
+    add_column_if_missing(&conn, "books", "description", "TEXT")?;
+    add_column_if_missing(&conn, "books", "doc_format", "TEXT")?;
+
+    create_highlights_table(&conn, table.as_str())?;
+
+    // Databases created by older versions won't have the newer columns
+    let name = table.as_str();
+    add_column_if_missing(&conn, name, "kind", "TEXT NOT NULL DEFAULT 'highlight'")?;
+    add_column_if_missing(&conn, name, "raw_datetime", "TEXT")?;
+    add_column_if_missing(&conn, name, "pos0", "TEXT")?;
+    add_column_if_missing(&conn, name, "pos1", "TEXT")?;
+    add_column_if_missing(&conn, name, "word_count", "INTEGER")?;
+    add_column_if_missing(&conn, name, "updated_at", "TEXT")?;
+    add_column_if_missing(&conn, name, "text_edited", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, name, "source_path", "TEXT")?;
+    add_column_if_missing(&conn, name, "raw_json", "TEXT")?;
+    add_column_if_missing(&conn, name, "truncated", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, name, "lang", "TEXT")?;
+    add_column_if_missing(&conn, name, "text_hash", "TEXT")?;
+    migrate_flat_table(&conn, name)?;
+    migrate_unique_key(&conn, name)?;
+    backfill_word_counts(&conn, name)?;
+    backfill_text_hashes(&conn, name)?;
+    create_unique_index(&conn, name)?;
+
+    let view_columns: Vec<String> = HIGHLIGHT_COLUMNS
+        .split(',')
+        .map(|c| format!("h.{}", c.trim()))
+        .collect();
+    conn.execute_batch(&format!(
+        "DROP VIEW IF EXISTS {view};
+         CREATE VIEW {view} AS
+         SELECT h.id, h.book_id, b.title AS book_title, b.author AS book_author,
+                b.doc_format AS book_doc_format, {columns}
+         FROM {table} h JOIN books b ON b.id = h.book_id;",
+        view = flat_view_name(table),
+        columns = view_columns.join(", "),
+        table = table,
+    ))?;
+
+    create_fts_table(&conn, table)?;
+    conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+
+    Ok(conn)
+}
+
+/// The schema version stored in the database, 0 if `init_db` never ran.
+pub fn schema_version(conn: &Connection) -> Result<i32, DbError> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// Name of the full-text index over a highlights table's text and notes.
+pub fn fts_table_name(table: &TableName) -> String {
+    format!("{}_fts", table)
+}
+
+/// Sets up the FTS5 index mirroring `table`, kept current by triggers. A new
+/// index is filled from the rows already stored.
+fn create_fts_table(conn: &Connection, table: &TableName) -> Result<(), DbError> {
+    let fts = fts_table_name(table);
+    let existed: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE name = ?1",
+        params![fts],
+        |row| row.get(0),
+    )?;
+
+    conn.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS {fts}
+             USING fts5(text, note, content='{table}', content_rowid='id');
+         CREATE TRIGGER IF NOT EXISTS {fts}_insert AFTER INSERT ON {table} BEGIN
+             INSERT INTO {fts}(rowid, text, note) VALUES (new.id, new.text, new.note);
+         END;
+         CREATE TRIGGER IF NOT EXISTS {fts}_delete AFTER DELETE ON {table} BEGIN
+             INSERT INTO {fts}({fts}, rowid, text, note)
+             VALUES ('delete', old.id, old.text, old.note);
+         END;
+         CREATE TRIGGER IF NOT EXISTS {fts}_update AFTER UPDATE OF text, note ON {table} BEGIN
+             INSERT INTO {fts}({fts}, rowid, text, note)
+             VALUES ('delete', old.id, old.text, old.note);
+             INSERT INTO {fts}(rowid, text, note) VALUES (new.id, new.text, new.note);
+         END;",
+        fts = fts,
+        table = table,
+    ))?;
+
+    if !existed {
+        reindex_fts(conn, table)?;
+    }
+    Ok(())
+}
+
+/// Rebuilds the full-text index from the highlights table, for when the two
+/// have drifted apart, e.g. after editing the table by hand.
+pub fn reindex_fts(conn: &Connection, table: &TableName) -> Result<(), DbError> {
+    let fts = fts_table_name(table);
+    conn.execute(&format!("INSERT INTO {fts}({fts}) VALUES ('rebuild')", fts = fts), [])?;
+    Ok(())
+}
+
+/// Finds the highlights whose text or note matches an FTS5 `query`, best
+/// matches first.
+pub fn search(
+    conn: &Connection,
+    table: &TableName,
+    query: &str,
+) -> Result<Vec<StoredHighlight>, DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {columns} FROM {view}
+         JOIN (SELECT rowid, rank FROM {fts} WHERE {fts} MATCH ?1) m ON m.rowid = {view}.id
+         ORDER BY m.rank",
+        columns = STORED_COLUMNS,
+        view = flat_view_name(table),
+        fts = fts_table_name(table),
+    ))?;
+    let rows = stmt
+        .query_map(params![query], stored_highlight_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+fn create_highlights_table(conn: &Connection, table: &str) -> Result<(), DbError> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+            id INTEGER PRIMARY KEY,
+            book_id INTEGER NOT NULL REFERENCES books(id),
             chapter TEXT,
             page INTEGER NOT NULL,
             text TEXT NOT NULL,
@@ -41,44 +250,695 @@ pub fn init_db(path: &Path) -> Result<Connection, DbError> {
             datetime TEXT NOT NULL,
             processed INTEGER DEFAULT 0,
             created_at TEXT DEFAULT CURRENT_TIMESTAMP,
-            UNIQUE(book_title, page, text)
+            kind TEXT NOT NULL DEFAULT 'highlight',
+            raw_datetime TEXT,
+            pos0 TEXT,
+            pos1 TEXT,
+            word_count INTEGER,
+            updated_at TEXT,
+            text_edited INTEGER NOT NULL DEFAULT 0,
+            source_path TEXT,
+            raw_json TEXT,
+            truncated INTEGER NOT NULL DEFAULT 0,
+            lang TEXT,
+            text_hash TEXT
         )",
-        [],
+        table = table,
+    ))?;
+
+    Ok(())
+}
+
+/// Keeps a highlight from being stored twice. It's keyed on the hash of the
+/// full text, so highlights cut short by `--max-text-len` still differ.
+/// Created once the table is in its current layout, since older layouts lack
+/// `book_id`; an index from before `text_hash` is replaced.
+fn create_unique_index(conn: &Connection, table: &str) -> Result<(), DbError> {
+    let index = format!("{}_unique", table);
+    let existing: Option<String> = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'index' AND name = ?1",
+            params![index],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if existing.is_some_and(|sql| !sql.contains("text_hash")) {
+        conn.execute_batch(&format!("DROP INDEX {}", index))?;
+    }
+
+    conn.execute_batch(&format!(
+        "CREATE UNIQUE INDEX IF NOT EXISTS {index} ON {table} (book_id, page, text_hash, {key})",
+        index = index,
+        table = table,
+        key = NOTE_KEY,
+    ))?;
+
+    Ok(())
+}
+
+/// The part of a highlight's identity beyond its book, page and text: the
+/// note, for entries without text, so two page notes on one page both get
+/// stored. Matches `Highlight::key`.
+const NOTE_KEY: &str = "(CASE WHEN text = '' THEN COALESCE(note, '') ELSE '' END)";
+
+/// Rebuilds a table made before the note was part of the unique key, whose
+/// `UNIQUE(book_id, page, text)` constraint can't be dropped in place.
+fn migrate_unique_key(conn: &Connection, table: &str) -> Result<(), DbError> {
+    let sql: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![table],
+        |row| row.get(0),
     )?;
+    if !sql.contains("UNIQUE(book_id, page, text)") {
+        return Ok(());
+    }
 
-    Ok(conn)
+    let old = format!("{}_before_note_key", table);
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(&format!("ALTER TABLE {} RENAME TO {}", table, old))?;
+    create_highlights_table(&tx, table)?;
+    tx.execute_batch(&format!(
+        "INSERT INTO {table} (id, book_id, {columns})
+         SELECT id, book_id, {columns} FROM {old};
+         DROP TABLE {old};",
+        table = table,
+        columns = HIGHLIGHT_COLUMNS,
+        old = old,
+    ))?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Moves a table from the old layout, with the book title and author on every
+/// row, to one referencing `books`. Ids are kept, so nothing pointing at a
+/// highlight breaks.
+fn migrate_flat_table(conn: &Connection, table: &str) -> Result<(), DbError> {
+    if !has_column(conn, table, "book_title")? {
+        return Ok(());
+    }
+
+    let old = format!("{}_before_books", table);
+    let copied: Vec<String> = HIGHLIGHT_COLUMNS
+        .split(',')
+        .map(|c| format!("o.{}", c.trim()))
+        .collect();
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(&format!(
+        "INSERT OR IGNORE INTO books (title, author)
+         SELECT DISTINCT book_title, book_author FROM {table};
+         ALTER TABLE {table} RENAME TO {old};",
+        table = table,
+        old = old,
+    ))?;
+    create_highlights_table(&tx, table)?;
+    tx.execute_batch(&format!(
+        "INSERT OR IGNORE INTO {table} (id, book_id, {columns})
+         SELECT o.id, b.id, {copied}
+         FROM {old} o JOIN books b ON b.title = o.book_title AND b.author = o.book_author;
+         DROP TABLE {old};",
+        table = table,
+        columns = HIGHLIGHT_COLUMNS,
+        copied = copied.join(", "),
+        old = old,
+    ))?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, DbError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+
+    Ok(exists)
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), DbError> {
+    if !has_column(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Fills in `text_hash` for rows stored before the column existed. Text that
+/// was already cut short can only be hashed as stored.
+fn backfill_text_hashes(conn: &Connection, table: &str) -> Result<(), DbError> {
+    let missing: Vec<(i64, String)> = conn
+        .prepare(&format!("SELECT id, text FROM {} WHERE text_hash IS NULL", table))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    for (id, text) in missing {
+        let hash = Highlight {
+            text,
+            ..Default::default()
+        }
+        .text_hash();
+        conn.execute(
+            &format!("UPDATE {} SET text_hash = ?1 WHERE id = ?2", table),
+            params![hash, id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Fills in `word_count` for rows stored before the column existed.
+fn backfill_word_counts(conn: &Connection, table: &str) -> Result<(), DbError> {
+    let missing: Vec<(i64, String)> = conn
+        .prepare(&format!("SELECT id, text FROM {} WHERE word_count IS NULL", table))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    for (id, text) in missing {
+        let words = Highlight {
+            text,
+            ..Default::default()
+        }
+        .word_count();
+        conn.execute(
+            &format!("UPDATE {} SET word_count = ?1 WHERE id = ?2", table),
+            params![words as i64, id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// How `upsert_book` decides a parsed book is one already stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum BookMatch {
+    /// Same title, whatever the author (survives fixed-up author metadata)
+    Title,
+    /// Same title and same author
+    #[default]
+    #[value(name = "title+author")]
+    TitleAuthor,
+}
+
+/// Stores the book's metadata, or refreshes it if `strategy` finds it already
+/// stored, returning the book's id either way. Missing series, language,
+/// description or document type never erase values stored earlier.
+pub fn upsert_book(
+    conn: &Connection,
+    book: &BookData,
+    strategy: BookMatch,
+) -> Result<i64, DbError> {
+    if strategy == BookMatch::Title {
+        let existing: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM books WHERE title = ?1 ORDER BY id LIMIT 1",
+                params![book.title],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        if let Some(id) = existing {
+            conn.execute(
+                "UPDATE books SET
+                     series = COALESCE(?2, series),
+                     language = COALESCE(?3, language),
+                     description = COALESCE(?4, description),
+                     doc_format = COALESCE(?5, doc_format)
+                 WHERE id = ?1",
+                params![
+                    id,
+                    book.series,
+                    book.language,
+                    book.description,
+                    book.doc_format
+                ],
+            )?;
+            return Ok(id);
+        }
+    }
+
+    let id = conn.query_row(
+        "INSERT INTO books (title, author, series, language, description, doc_format)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(title, author) DO UPDATE SET
+             series = COALESCE(excluded.series, series),
+             language = COALESCE(excluded.language, language),
+             description = COALESCE(excluded.description, description),
+             doc_format = COALESCE(excluded.doc_format, doc_format)
+         RETURNING id",
+        params![
+            book.title,
+            book.author,
+            book.series,
+            book.language,
+            book.description,
+            book.doc_format
+        ],
+        |row| row.get(0),
+    )?;
+
+    Ok(id)
+}
+
+/// What `insert_highlight_with` does with a highlight that's already stored
+/// (same book, page and text, and for entries without text the same note).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Keep the stored highlight as it is
+    #[default]
+    Ignore,
+    /// Refresh its note, chapter, datetimes and edit flag from the new one
+    Update,
+    /// Fail with `DbError::DuplicateHighlight`
+    Error,
 }
 
+/// Stores a highlight, returning whether it was new. `source_path` is the
+/// metadata file it was read from; a duplicate gets its path refreshed, so
+/// `prune_missing` keeps up with books that moved.
 pub fn insert_highlight(
     conn: &Connection,
+    table: &TableName,
+    book_id: i64,
     highlight: &Highlight,
-    book_title: &str,
-    book_author: &str,
+    source_path: Option<&Path>,
 ) -> Result<bool, DbError> {
+    insert_highlight_with(conn, table, book_id, highlight, source_path, DuplicatePolicy::Ignore)
+}
+
+/// Like `insert_highlight`, handling a duplicate as `policy` says.
+pub fn insert_highlight_with(
+    conn: &Connection,
+    table: &TableName,
+    book_id: i64,
+    highlight: &Highlight,
+    source_path: Option<&Path>,
+    policy: DuplicatePolicy,
+) -> Result<bool, DbError> {
+    insert_highlight_cut(conn, table, book_id, highlight, None, source_path, policy)
+}
+
+/// Like `insert_highlight_with`, storing the text cut down to `max_text_len`
+/// characters with `Highlight::truncate_text`. It's still recognised by its
+/// full text, so highlights sharing a beginning stay apart, and changing the
+/// length between runs doesn't store them again.
+pub fn insert_highlight_cut(
+    conn: &Connection,
+    table: &TableName,
+    book_id: i64,
+    highlight: &Highlight,
+    max_text_len: Option<usize>,
+    source_path: Option<&Path>,
+    policy: DuplicatePolicy,
+) -> Result<bool, DbError> {
+    let text_hash = highlight.text_hash();
+    let cut;
+    let highlight = match max_text_len {
+        Some(max_len) => {
+            let mut long = highlight.clone();
+            long.truncate_text(max_len);
+            cut = long;
+            &cut
+        }
+        None => highlight,
+    };
     let datetime_str = highlight.datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    let source = source_path.map(|p| p.to_string_lossy().into_owned());
+    let updated_str = highlight
+        .updated
+        .map(|u| u.format("%Y-%m-%d %H:%M:%S").to_string());
 
     let rows = conn.execute(
-        "INSERT OR IGNORE INTO highlights
-         (book_title, book_author, chapter, page, text, note, datetime)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        &format!(
+            "INSERT OR IGNORE INTO {}
+             (book_id, chapter, page, text, note, datetime, kind, raw_datetime,
+              pos0, pos1, word_count, updated_at, text_edited, source_path, raw_json,
+              truncated, lang, text_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16,
+                     ?17, ?18)",
+            table
+        ),
         params![
-            book_title,
-            book_author,
+            book_id,
             highlight.chapter,
             highlight.page,
             highlight.text,
             highlight.note,
             datetime_str,
+            highlight.kind.as_str(),
+            highlight.raw_datetime,
+            highlight.pos0,
+            highlight.pos1,
+            highlight.word_count() as i64,
+            updated_str,
+            highlight.text_edited,
+            source,
+            highlight.raw_json,
+            highlight.truncated,
+            highlight.lang,
+            text_hash,
         ],
     )?;
 
-    Ok(rows > 0)
+    if rows > 0 {
+        return Ok(true);
+    }
+
+    match policy {
+        DuplicatePolicy::Ignore if source.is_some() => {
+            conn.execute(
+                &format!(
+                    "UPDATE {} SET source_path = ?1
+                     WHERE book_id = ?2 AND page = ?3 AND text_hash = ?6
+                     AND {} = (CASE WHEN ?4 = '' THEN COALESCE(?5, '') ELSE '' END)",
+                    table, NOTE_KEY
+                ),
+                params![
+                    source,
+                    book_id,
+                    highlight.page,
+                    highlight.text,
+                    highlight.note,
+                    text_hash
+                ],
+            )?;
+        }
+        DuplicatePolicy::Ignore => {}
+        DuplicatePolicy::Update => {
+            conn.execute(
+                &format!(
+                    "UPDATE {} SET note = ?1, chapter = ?2, datetime = ?3,
+                     source_path = COALESCE(?4, source_path), raw_datetime = ?8,
+                     updated_at = ?9, text_edited = ?10
+                     WHERE book_id = ?5 AND page = ?6 AND text_hash = ?11
+                     AND {} = (CASE WHEN ?7 = '' THEN COALESCE(?1, '') ELSE '' END)",
+                    table, NOTE_KEY
+                ),
+                params![
+                    highlight.note,
+                    highlight.chapter,
+                    datetime_str,
+                    source,
+                    book_id,
+                    highlight.page,
+                    highlight.text,
+                    highlight.raw_datetime,
+                    updated_str,
+                    highlight.text_edited,
+                    text_hash
+                ],
+            )?;
+        }
+        DuplicatePolicy::Error => {
+            let start: String = highlight.text.chars().take(40).collect();
+            return Err(DbError::DuplicateHighlight(format!(
+                "p.{} \"{}\"",
+                highlight.page, start
+            )));
+        }
+    }
+
+    Ok(false)
+}
+
+const STORED_COLUMNS: &str = "id, book_title, book_author, chapter, page, text, note, datetime, \
+     kind, raw_datetime, pos0, pos1, updated_at, text_edited, raw_json, book_doc_format, \
+     truncated, lang";
+
+fn stored_highlight_from_row(row: &rusqlite::Row) -> rusqlite::Result<StoredHighlight> {
+    let datetime: String = row.get(7)?;
+    let parsed = NaiveDateTime::parse_from_str(&datetime, "%Y-%m-%d %H:%M:%S").map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(7, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    let kind: String = row.get(8)?;
+    let updated = row
+        .get::<_, Option<String>>(12)?
+        .and_then(|u| NaiveDateTime::parse_from_str(&u, "%Y-%m-%d %H:%M:%S").ok());
+
+    Ok(StoredHighlight {
+        id: row.get(0)?,
+        book_title: row.get(1)?,
+        book_author: row.get(2)?,
+        book_doc_format: row.get(15)?,
+        highlight: Highlight {
+            chapter: row.get(3)?,
+            page: row.get(4)?,
+            text: row.get(5)?,
+            note: row.get(6)?,
+            datetime: parsed,
+            raw_datetime: row.get::<_, Option<String>>(9)?.unwrap_or(datetime),
+            updated,
+            text_edited: row.get(13)?,
+            kind: HighlightKind::from_name(&kind),
+            pos0: row.get(10)?,
+            pos1: row.get(11)?,
+            raw_json: row.get(14)?,
+            truncated: row.get(16)?,
+            lang: row.get(17)?,
+        },
+    })
+}
+
+/// Returns every stored highlight of the book with exactly this title,
+/// ordered by page.
+pub fn highlights_for_book(
+    conn: &Connection,
+    table: &TableName,
+    title: &str,
+) -> Result<Vec<StoredHighlight>, DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} WHERE book_title = ?1 ORDER BY page, id",
+        STORED_COLUMNS, flat_view_name(table)
+    ))?;
+    let rows = stmt
+        .query_map(params![title], stored_highlight_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+/// Returns every stored highlight with its book, grouped by book and ordered
+/// by page within each.
+pub fn export_all(conn: &Connection, table: &TableName) -> Result<Vec<StoredHighlight>, DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} ORDER BY book_title, book_author, page, id",
+        STORED_COLUMNS, flat_view_name(table)
+    ))?;
+    let rows = stmt
+        .query_map([], stored_highlight_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+/// Like `export_all`, limited to highlights stored after the one with `id`,
+/// for callers that remember the last id they've seen.
+pub fn highlights_after_id(
+    conn: &Connection,
+    table: &TableName,
+    id: i64,
+) -> Result<Vec<StoredHighlight>, DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} WHERE id > ?1 ORDER BY book_title, book_author, page, id",
+        STORED_COLUMNS,
+        flat_view_name(table)
+    ))?;
+    let rows = stmt
+        .query_map(params![id], stored_highlight_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+/// The `n` highlights stored last, newest first. Rows stored in the same
+/// second are ordered by when they were made, then by id.
+pub fn recent(
+    conn: &Connection,
+    table: &TableName,
+    n: usize,
+) -> Result<Vec<StoredHighlight>, DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} ORDER BY created_at DESC, datetime DESC, id DESC LIMIT ?1",
+        STORED_COLUMNS,
+        flat_view_name(table)
+    ))?;
+    let rows = stmt
+        .query_map(params![n as i64], stored_highlight_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+/// Like `export_all`, limited to highlights made from `from` through `to`
+/// (whole days) so the date filter runs in SQLite. The stored
+/// `%Y-%m-%d %H:%M:%S` text sorts like the time it stands for.
+pub fn highlights_between(
+    conn: &Connection,
+    table: &TableName,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<StoredHighlight>, DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} WHERE datetime BETWEEN ?1 AND ?2
+         ORDER BY book_title, book_author, page, id",
+        STORED_COLUMNS,
+        flat_view_name(table)
+    ))?;
+    let start = format!("{} 00:00:00", from.format("%Y-%m-%d"));
+    let end = format!("{} 23:59:59", to.format("%Y-%m-%d"));
+    let rows = stmt
+        .query_map(params![start, end], stored_highlight_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+/// Every author with highlights in `table` once, sorted case-insensitively.
+pub fn distinct_authors(conn: &Connection, table: &TableName) -> Result<Vec<String>, DbError> {
+    distinct_book_values(conn, table, "author")
+}
+
+/// Every book title with highlights in `table` once, sorted case-insensitively.
+pub fn distinct_books(conn: &Connection, table: &TableName) -> Result<Vec<String>, DbError> {
+    distinct_book_values(conn, table, "title")
+}
+
+fn distinct_book_values(
+    conn: &Connection,
+    table: &TableName,
+    column: &str,
+) -> Result<Vec<String>, DbError> {
+    // books outlive their highlights (pruned, or kept in another table), so
+    // only list the ones this table still has something for
+    let mut stmt = conn.prepare(&format!(
+        "SELECT DISTINCT b.{column} FROM books b JOIN {table} h ON h.book_id = b.id \
+         ORDER BY b.{column} COLLATE NOCASE, b.{column}",
+        column = column,
+        table = table
+    ))?;
+    let values = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(values)
+}
+
+/// Totals over everything stored in a table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DbStats {
+    pub highlights: usize,
+    pub books: usize,
+    pub words: usize,
+}
+
+pub fn stats(conn: &Connection, table: &TableName) -> Result<DbStats, DbError> {
+    let stats = conn.query_row(
+        &format!(
+            "SELECT COUNT(*), COUNT(DISTINCT book_id), COALESCE(SUM(word_count), 0) FROM {}",
+            table
+        ),
+        [],
+        |row| {
+            Ok(DbStats {
+                highlights: row.get::<_, i64>(0)? as usize,
+                books: row.get::<_, i64>(1)? as usize,
+                words: row.get::<_, i64>(2)? as usize,
+            })
+        },
+    )?;
+
+    Ok(stats)
+}
+
+/// Deletes every stored highlight made before `before`, returning how many
+/// rows were removed.
+pub fn prune(conn: &Connection, table: &TableName, before: NaiveDate) -> Result<usize, DbError> {
+    let tx = conn.unchecked_transaction()?;
+    // datetime is stored as `%Y-%m-%d %H:%M:%S`, so comparing against the bare
+    // date string keeps everything from `before` onwards
+    let removed = tx.execute(
+        &format!("DELETE FROM {} WHERE datetime < ?1", table),
+        params![before.format("%Y-%m-%d").to_string()],
+    )?;
+    tx.commit()?;
+
+    Ok(removed)
+}
+
+/// Deletes the highlights whose metadata file is gone, e.g. because the book
+/// was removed from the device, and returns how many went. Rows stored before
+/// the source path was recorded are left alone.
+pub fn prune_missing(conn: &Connection, table: &TableName) -> Result<usize, DbError> {
+    let tx = conn.unchecked_transaction()?;
+    let paths: Vec<String> = {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT DISTINCT source_path FROM {} WHERE source_path IS NOT NULL",
+            table
+        ))?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let mut removed = 0;
+    for path in paths.iter().filter(|p| !Path::new(p).exists()) {
+        removed += tx.execute(
+            &format!("DELETE FROM {} WHERE source_path = ?1", table),
+            params![path],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(removed)
+}
+
+/// Copies the whole database to `dest` with SQLite's online backup, so the
+/// copy is consistent even while `conn` is in use. An existing `dest` is
+/// replaced.
+pub fn backup(conn: &Connection, dest: &Path) -> Result<(), DbError> {
+    conn.backup(DatabaseName::Main, dest, None)?;
+    Ok(())
+}
+
+/// Backs up the database at `path` as it is on disk, before `init_db` gets
+/// to create or migrate anything in it.
+pub fn backup_file(path: &Path, dest: &Path) -> Result<(), DbError> {
+    let conn = Connection::open(path).map_err(|e| DbError::ConnectionFailed(e.to_string()))?;
+    backup(&conn, dest)
+}
+
+/// Rebuilds the database file, reclaiming space left behind by deletions,
+/// and lets SQLite refresh its query planner statistics.
+pub fn vacuum(conn: &Connection) -> Result<(), DbError> {
+    conn.execute_batch("VACUUM; PRAGMA optimize;")?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::NaiveDateTime;
+
+    fn table() -> TableName {
+        TableName::default()
+    }
+
+    fn book_id(conn: &Connection, title: &str, author: &str) -> i64 {
+        let book = BookData {
+            title: title.to_string(),
+            author: author.to_string(),
+            ..Default::default()
+        };
+        upsert_book(conn, &book, BookMatch::default()).unwrap()
+    }
 
     fn make_highlight(text: &str, page: i32, note: Option<&str>) -> Highlight {
         Highlight {
@@ -88,12 +948,14 @@ mod tests {
             note: note.map(String::from),
             datetime: NaiveDateTime::parse_from_str("2026-01-25 10:30:00", "%Y-%m-%d %H:%M:%S")
                 .unwrap(),
+            raw_datetime: "2026-01-25 10:30:00".to_string(),
+            ..Default::default()
         }
     }
 
     #[test]
     fn test_init_db_creates_table() {
-        let conn = init_db(Path::new(":memory:")).unwrap();
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
 
         let count: i32 = conn
             .query_row(
@@ -108,10 +970,11 @@ mod tests {
 
     #[test]
     fn test_insert_highlight() {
-        let conn = init_db(Path::new(":memory:")).unwrap();
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
         let h = make_highlight("Test text", 42, None);
 
-        let inserted = insert_highlight(&conn, &h, "Test Book", "Test Author").unwrap();
+        let inserted = insert_highlight(&conn, &table(), book, &h, None).unwrap();
 
         assert!(inserted);
 
@@ -122,12 +985,72 @@ mod tests {
         assert_eq!(count, 1);
     }
 
+    #[test]
+    fn test_duplicate_policies() {
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
+        let first = make_highlight("Same text", 42, Some("first note"));
+        let mut second = make_highlight("Same text", 42, Some("second note"));
+        second.chapter = Some("Chapter 2".to_string());
+        insert_highlight(&conn, &table(), book, &first, None).unwrap();
+
+        let stored = |conn: &Connection| -> (i64, Option<String>, Option<String>) {
+            conn.query_row(
+                "SELECT COUNT(*), MAX(note), MAX(chapter) FROM highlights",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+        };
+
+        let ignored = DuplicatePolicy::Ignore;
+        assert!(!insert_highlight_with(&conn, &table(), book, &second, None, ignored).unwrap());
+        assert_eq!(
+            stored(&conn),
+            (1, Some("first note".to_string()), Some("Chapter 1".to_string()))
+        );
+
+        let error = DuplicatePolicy::Error;
+        let result = insert_highlight_with(&conn, &table(), book, &second, None, error);
+        assert!(matches!(result, Err(DbError::DuplicateHighlight(_))));
+        assert_eq!(stored(&conn).0, 1);
+
+        let update = DuplicatePolicy::Update;
+        second.raw_datetime = "2026-02-01 08:00:00".to_string();
+        second.datetime =
+            NaiveDateTime::parse_from_str(&second.raw_datetime, "%Y-%m-%d %H:%M:%S").unwrap();
+        second.updated = second.datetime.checked_add_signed(chrono::Duration::hours(1));
+        second.text_edited = true;
+        assert!(!insert_highlight_with(&conn, &table(), book, &second, None, update).unwrap());
+        assert_eq!(
+            stored(&conn),
+            (1, Some("second note".to_string()), Some("Chapter 2".to_string()))
+        );
+        let dates: (String, String, Option<String>, bool) = conn
+            .query_row(
+                "SELECT datetime, raw_datetime, updated_at, text_edited FROM highlights",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            dates,
+            (
+                "2026-02-01 08:00:00".to_string(),
+                "2026-02-01 08:00:00".to_string(),
+                Some("2026-02-01 09:00:00".to_string()),
+                true
+            )
+        );
+    }
+
     #[test]
     fn test_insert_highlight_with_note() {
-        let conn = init_db(Path::new(":memory:")).unwrap();
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
         let h = make_highlight("Test text", 42, Some("my note"));
 
-        insert_highlight(&conn, &h, "Test Book", "Test Author").unwrap();
+        insert_highlight(&conn, &table(), book, &h, None).unwrap();
 
         let note: Option<String> = conn
             .query_row("SELECT note FROM highlights WHERE id = 1", [], |row| {
@@ -140,11 +1063,12 @@ mod tests {
 
     #[test]
     fn test_insert_duplicate_ignored() {
-        let conn = init_db(Path::new(":memory:")).unwrap();
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
         let h = make_highlight("Same text", 42, None);
 
-        let first = insert_highlight(&conn, &h, "Test Book", "Test Author").unwrap();
-        let second = insert_highlight(&conn, &h, "Test Book", "Test Author").unwrap();
+        let first = insert_highlight(&conn, &table(), book, &h, None).unwrap();
+        let second = insert_highlight(&conn, &table(), book, &h, None).unwrap();
 
         assert!(first);
         assert!(!second); // duplicate ignored
@@ -158,12 +1082,13 @@ mod tests {
 
     #[test]
     fn test_same_text_different_page_not_duplicate() {
-        let conn = init_db(Path::new(":memory:")).unwrap();
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
         let h1 = make_highlight("Same text", 42, None);
         let h2 = make_highlight("Same text", 100, None);
 
-        insert_highlight(&conn, &h1, "Test Book", "Test Author").unwrap();
-        insert_highlight(&conn, &h2, "Test Book", "Test Author").unwrap();
+        insert_highlight(&conn, &table(), book, &h1, None).unwrap();
+        insert_highlight(&conn, &table(), book, &h2, None).unwrap();
 
         let count: i32 = conn
             .query_row("SELECT COUNT(*) FROM highlights", [], |row| row.get(0))
@@ -171,4 +1096,654 @@ mod tests {
 
         assert_eq!(count, 2);
     }
+
+    #[test]
+    fn test_cut_highlights_keyed_on_full_text() {
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
+        let first = make_highlight("The same long opening, then one ending", 42, None);
+        let second = make_highlight("The same long opening, then another", 42, None);
+        let insert = |h: &Highlight, max_len| {
+            let policy = DuplicatePolicy::Ignore;
+            insert_highlight_cut(&conn, &table(), book, h, max_len, None, policy).unwrap()
+        };
+
+        assert!(insert(&first, Some(10)));
+        assert!(insert(&second, Some(10)));
+        // another length, or none, is still the same highlight
+        assert!(!insert(&first, Some(20)));
+        assert!(!insert(&second, None));
+
+        let texts: Vec<String> = highlights_for_book(&conn, &table(), "Test Book")
+            .unwrap()
+            .into_iter()
+            .map(|s| s.highlight.text)
+            .collect();
+        assert_eq!(texts, vec!["The same l...", "The same l..."]);
+    }
+
+    #[test]
+    fn test_page_notes_on_same_page_both_stored() {
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
+        let first = make_highlight("", 42, Some("first note"));
+        let second = make_highlight("", 42, Some("second note"));
+
+        assert!(insert_highlight(&conn, &table(), book, &first, None).unwrap());
+        assert!(insert_highlight(&conn, &table(), book, &second, None).unwrap());
+        assert!(!insert_highlight(&conn, &table(), book, &first, None).unwrap());
+
+        let count: i32 = conn
+            .query_row("SELECT COUNT(*) FROM highlights", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_init_db_adds_note_to_old_unique_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.db");
+        {
+            let conn = init_db(&path, &table()).unwrap();
+            conn.execute_batch(
+                "DROP VIEW highlights_flat;
+                 DROP TABLE highlights_fts;
+                 DROP TABLE highlights;
+                 CREATE TABLE highlights (
+                    id INTEGER PRIMARY KEY,
+                    book_id INTEGER NOT NULL REFERENCES books(id),
+                    chapter TEXT,
+                    page INTEGER NOT NULL,
+                    text TEXT NOT NULL,
+                    note TEXT,
+                    datetime TEXT NOT NULL,
+                    processed INTEGER DEFAULT 0,
+                    created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+                    UNIQUE(book_id, page, text)
+                );
+                INSERT INTO books (id, title, author) VALUES (1, 'Book', 'Author');
+                INSERT INTO highlights (id, book_id, page, text, note, datetime)
+                VALUES (5, 1, 42, '', 'first note', '2026-01-01 10:00:00');",
+            )
+            .unwrap();
+        }
+
+        let conn = init_db(&path, &table()).unwrap();
+        let second = make_highlight("", 42, Some("second note"));
+        assert!(insert_highlight(&conn, &table(), 1, &second, None).unwrap());
+
+        let ids: Vec<i64> = highlights_for_book(&conn, &table(), "Book")
+            .unwrap()
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[0], 5);
+    }
+
+    #[test]
+    fn test_insert_bookmark_stores_kind() {
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
+        let mut h = make_highlight("", 80, Some("page note"));
+        h.kind = HighlightKind::Bookmark;
+
+        insert_highlight(&conn, &table(), book, &h, None).unwrap();
+        let h = make_highlight("Text", 42, None);
+        insert_highlight(&conn, &table(), book, &h, None).unwrap();
+
+        let kinds: Vec<String> = conn
+            .prepare("SELECT kind FROM highlights ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|k| k.unwrap())
+            .collect();
+
+        assert_eq!(kinds, vec!["bookmark", "highlight"]);
+    }
+
+    #[test]
+    fn test_init_db_adds_kind_to_old_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE highlights (
+                id INTEGER PRIMARY KEY,
+                book_title TEXT NOT NULL,
+                book_author TEXT NOT NULL,
+                chapter TEXT,
+                page INTEGER NOT NULL,
+                text TEXT NOT NULL,
+                note TEXT,
+                datetime TEXT NOT NULL,
+                UNIQUE(book_title, page, text)
+            )",
+            [],
+        )
+        .unwrap();
+
+        add_column_if_missing(&conn, "highlights", "kind", "TEXT NOT NULL DEFAULT 'highlight'")
+            .unwrap();
+        // running again is a no-op
+        add_column_if_missing(&conn, "highlights", "kind", "TEXT NOT NULL DEFAULT 'highlight'")
+            .unwrap();
+
+        let count: i32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('highlights') WHERE name = 'kind'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_prune_removes_older_highlights() {
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
+        let datetimes = [
+            "2026-01-10 08:00:00",
+            "2026-01-19 23:59:59",
+            "2026-01-20 00:00:00",
+            "2026-01-25 12:00:00",
+        ];
+        for (i, datetime) in datetimes.iter().enumerate() {
+            let mut h = make_highlight(&format!("Text {}", i), 42, None);
+            h.datetime = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S").unwrap();
+            insert_highlight(&conn, &table(), book, &h, None).unwrap();
+        }
+
+        let before = NaiveDate::from_ymd_opt(2026, 1, 20).unwrap();
+        let removed = prune(&conn, &table(), before).unwrap();
+        vacuum(&conn).unwrap();
+
+        assert_eq!(removed, 2);
+
+        let remaining: Vec<String> = conn
+            .prepare("SELECT datetime FROM highlights ORDER BY datetime")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|d| d.unwrap())
+            .collect();
+
+        assert_eq!(remaining, vec!["2026-01-20 00:00:00", "2026-01-25 12:00:00"]);
+    }
+
+    #[test]
+    fn test_reindex_fts_repairs_drifted_index() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let book = book_id(&conn, "Book", "Author");
+        insert_highlight(&conn, &table(), book, &make_highlight("The quick fox", 1, None), None)
+            .unwrap();
+        insert_highlight(&conn, &table(), book, &make_highlight("Lazy dog", 2, Some("fox")), None)
+            .unwrap();
+        assert_eq!(search(&conn, &table(), "fox").unwrap().len(), 2);
+
+        conn.execute("INSERT INTO highlights_fts(highlights_fts) VALUES ('delete-all')", [])
+            .unwrap();
+        assert!(search(&conn, &table(), "fox").unwrap().is_empty());
+
+        reindex_fts(&conn, &table()).unwrap();
+
+        let pages: Vec<i32> = search(&conn, &table(), "fox")
+            .unwrap()
+            .iter()
+            .map(|s| s.highlight.page)
+            .collect();
+        assert_eq!(pages.len(), 2);
+        assert!(pages.contains(&1) && pages.contains(&2));
+    }
+
+    #[test]
+    fn test_highlights_between_days() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let book = book_id(&conn, "Book", "Author");
+        let datetimes = [
+            "2026-01-04 23:59:59",
+            "2026-01-05 00:00:00",
+            "2026-01-06 12:00:00",
+            "2026-01-07 23:59:59",
+            "2026-01-08 00:00:00",
+        ];
+        for (i, datetime) in datetimes.iter().enumerate() {
+            let mut h = make_highlight(&format!("Text {}", i), i as i32, None);
+            h.datetime = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S").unwrap();
+            insert_highlight(&conn, &table(), book, &h, None).unwrap();
+        }
+
+        let from = NaiveDate::from_ymd_opt(2026, 1, 5).unwrap();
+        let to = NaiveDate::from_ymd_opt(2026, 1, 7).unwrap();
+        let texts: Vec<String> = highlights_between(&conn, &table(), from, to)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.highlight.text)
+            .collect();
+
+        assert_eq!(texts, vec!["Text 1", "Text 2", "Text 3"]);
+    }
+
+    #[test]
+    fn test_recent_returns_newest_first() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let book = book_id(&conn, "Book", "Author");
+        for (i, datetime) in ["2026-01-05 10:00:00", "2026-01-07 10:00:00", "2026-01-06 10:00:00"]
+            .iter()
+            .enumerate()
+        {
+            let mut h = make_highlight(&format!("Text {}", i), i as i32, None);
+            h.datetime = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S").unwrap();
+            insert_highlight(&conn, &table(), book, &h, None).unwrap();
+        }
+        // imported later than the others, despite being made first
+        conn.execute(
+            "UPDATE highlights SET created_at = '2099-01-01 00:00:00' WHERE text = 'Text 0'",
+            [],
+        )
+        .unwrap();
+
+        let texts: Vec<String> = recent(&conn, &table(), 2)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.highlight.text)
+            .collect();
+
+        assert_eq!(texts, vec!["Text 0", "Text 1"]);
+    }
+
+    #[test]
+    fn test_backup_copies_every_row() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let book = book_id(&conn, "Book", "Author");
+        for page in 1..=3 {
+            let h = make_highlight(&format!("Text {}", page), page, None);
+            insert_highlight(&conn, &table(), book, &h, None).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("backup.db");
+
+        backup(&conn, &dest).unwrap();
+
+        let copy = open_read_only(&dest).unwrap();
+        let count = |c: &Connection| -> i64 {
+            c.query_row("SELECT COUNT(*) FROM highlights", [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(count(&copy), 3);
+        assert_eq!(count(&copy), count(&conn));
+        assert_eq!(schema_version(&copy).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_vacuum_file_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = init_db(&dir.path().join("h.db"), &table()).unwrap();
+        let book = book_id(&conn, "Book", "Author");
+        for page in 0..50 {
+            insert_highlight(&conn, &table(), book, &make_highlight("Text", page, None), None)
+                .unwrap();
+        }
+        conn.execute("DELETE FROM highlights WHERE page < 40", []).unwrap();
+
+        vacuum(&conn).unwrap();
+
+        let count: i64 =
+            conn.query_row("SELECT COUNT(*) FROM highlights", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 10);
+    }
+
+    #[test]
+    fn test_open_read_only_reads_but_refuses_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("h.db");
+        {
+            let conn = init_db(&path, &table()).unwrap();
+            let book = book_id(&conn, "Book", "Author");
+            insert_highlight(&conn, &table(), book, &make_highlight("Text", 1, None), None)
+                .unwrap();
+        }
+
+        let conn = open_read_only(&path).unwrap();
+
+        assert_eq!(export_all(&conn, &table()).unwrap().len(), 1);
+        assert!(conn.execute("DELETE FROM highlights", []).is_err());
+        assert!(open_read_only(&dir.path().join("missing.db")).is_err());
+    }
+
+    #[test]
+    fn test_prune_missing_only_removes_deleted_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("kept.lua");
+        let removed = dir.path().join("removed.lua");
+        std::fs::write(&kept, "").unwrap();
+        std::fs::write(&removed, "").unwrap();
+
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let h = make_highlight("Text", 42, None);
+        let kept_book = book_id(&conn, "Kept", "Author");
+        let removed_book = book_id(&conn, "Removed", "Author");
+        let legacy_book = book_id(&conn, "Legacy", "Author");
+        insert_highlight(&conn, &table(), kept_book, &h, Some(&kept)).unwrap();
+        insert_highlight(&conn, &table(), removed_book, &h, Some(&removed)).unwrap();
+        insert_highlight(&conn, &table(), legacy_book, &h, None).unwrap();
+        std::fs::remove_file(&removed).unwrap();
+
+        assert_eq!(prune_missing(&conn, &table()).unwrap(), 1);
+
+        let books: Vec<String> = export_all(&conn, &table())
+            .unwrap()
+            .into_iter()
+            .map(|s| s.book_title)
+            .collect();
+        assert_eq!(books, vec!["Kept", "Legacy"]);
+    }
+
+    #[test]
+    fn test_raw_datetime_stored_verbatim() {
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let book = book_id(&conn, "Test Book", "Test Author");
+        let mut h = make_highlight("Test text", 42, None);
+        h.raw_datetime = "2026-01-25T10:30:00".to_string();
+
+        insert_highlight(&conn, &table(), book, &h, None).unwrap();
+
+        let (datetime, raw): (String, String) = conn
+            .query_row("SELECT datetime, raw_datetime FROM highlights", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+
+        assert_eq!(datetime, "2026-01-25 10:30:00");
+        assert_eq!(raw, "2026-01-25T10:30:00");
+    }
+
+    #[test]
+    fn test_updated_at_round_trip() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let mut h = make_highlight("Edited later", 42, Some("second thoughts"));
+        h.updated =
+            NaiveDateTime::parse_from_str("2026-01-28 09:15:00", "%Y-%m-%d %H:%M:%S").ok();
+        h.text_edited = true;
+        insert_highlight(&conn, &table(), book_id(&conn, "Book", "Author"), &h, None).unwrap();
+
+        let rows = highlights_for_book(&conn, &table(), "Book").unwrap();
+
+        assert_eq!(rows[0].highlight.updated, h.updated);
+        assert!(rows[0].highlight.text_edited);
+    }
+
+    #[test]
+    fn test_highlights_for_book_filters_and_orders() {
+        let conn = init_db(Path::new(":memory:"), &TableName::default()).unwrap();
+        let rows = [
+            (make_highlight("Late", 200, None), "Book A"),
+            (make_highlight("Other", 5, None), "Book B"),
+            (make_highlight("Early", 10, Some("n")), "Book A"),
+        ];
+        for (h, title) in &rows {
+            insert_highlight(&conn, &table(), book_id(&conn, title, "Author"), h, None).unwrap();
+        }
+
+        let rows = highlights_for_book(&conn, &table(), "Book A").unwrap();
+
+        let texts: Vec<&str> = rows.iter().map(|r| r.highlight.text.as_str()).collect();
+        assert_eq!(texts, vec!["Early", "Late"]);
+        assert!(rows.iter().all(|r| r.book_title == "Book A"));
+        assert_eq!(rows[0].highlight, make_highlight("Early", 10, Some("n")));
+    }
+
+    #[test]
+    fn test_export_all_groups_by_book() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let rows = [
+            (make_highlight("B late", 9, None), "Book B"),
+            (make_highlight("A", 3, None), "Book A"),
+            (make_highlight("B early", 1, None), "Book B"),
+        ];
+        for (h, title) in &rows {
+            insert_highlight(&conn, &table(), book_id(&conn, title, "Author"), h, None).unwrap();
+        }
+
+        let all = export_all(&conn, &table()).unwrap();
+
+        let texts: Vec<&str> = all.iter().map(|r| r.highlight.text.as_str()).collect();
+        assert_eq!(texts, vec!["A", "B early", "B late"]);
+    }
+
+    #[test]
+    fn test_distinct_authors_and_books() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        for (title, author) in [
+            ("dune", "Frank Herbert"),
+            ("Dune Messiah", "Frank Herbert"),
+            ("Anathem", "neal Stephenson"),
+            ("dune", "Someone Else"),
+        ] {
+            let book = book_id(&conn, title, author);
+            insert_highlight(&conn, &table(), book, &make_highlight(title, 1, None), None).unwrap();
+        }
+
+        assert_eq!(
+            distinct_authors(&conn, &table()).unwrap(),
+            vec!["Frank Herbert", "neal Stephenson", "Someone Else"]
+        );
+        assert_eq!(
+            distinct_books(&conn, &table()).unwrap(),
+            vec!["Anathem", "dune", "Dune Messiah"]
+        );
+    }
+
+    #[test]
+    fn test_distinct_skips_books_without_highlights_in_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("h.db");
+        let other = TableName::new("reading_2025").unwrap();
+        drop(init_db(&path, &other).unwrap());
+        let conn = init_db(&path, &table()).unwrap();
+
+        let kept = book_id(&conn, "Dune", "Frank Herbert");
+        insert_highlight(&conn, &table(), kept, &make_highlight("Fear", 1, None), None).unwrap();
+        // only has highlights in another table
+        let elsewhere = book_id(&conn, "Anathem", "Neal Stephenson");
+        insert_highlight(&conn, &other, elsewhere, &make_highlight("Cloister", 1, None), None)
+            .unwrap();
+        // had highlights, all pruned since
+        let pruned = book_id(&conn, "Emma", "Jane Austen");
+        let mut old = make_highlight("Handsome", 1, None);
+        old.datetime = NaiveDateTime::parse_from_str("2020-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+            .unwrap();
+        insert_highlight(&conn, &table(), pruned, &old, None).unwrap();
+        prune(&conn, &table(), NaiveDate::from_ymd_opt(2025, 1, 1).unwrap()).unwrap();
+
+        assert_eq!(distinct_authors(&conn, &table()).unwrap(), vec!["Frank Herbert"]);
+        assert_eq!(distinct_books(&conn, &table()).unwrap(), vec!["Dune"]);
+        assert_eq!(distinct_books(&conn, &other).unwrap(), vec!["Anathem"]);
+    }
+
+    #[test]
+    fn test_custom_table_round_trip() {
+        let custom = TableName::new("reading_2026").unwrap();
+        let conn = init_db(Path::new(":memory:"), &custom).unwrap();
+
+        let id = book_id(&conn, "Book", "Author");
+        insert_highlight(&conn, &custom, id, &make_highlight("Text", 42, None), None).unwrap();
+
+        let rows = highlights_for_book(&conn, &custom, "Book").unwrap();
+        assert_eq!(rows.len(), 1);
+
+        // nothing went into the default table
+        let default_tables: i32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='highlights'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(default_tables, 0);
+    }
+
+    #[test]
+    fn test_invalid_table_names_rejected() {
+        for name in ["foo;bar", "", "1abc", "drop table", "sqlite_master", "a-b"] {
+            assert!(
+                matches!(TableName::new(name), Err(DbError::InvalidTableName(_))),
+                "{:?} should be rejected",
+                name
+            );
+        }
+        assert!(TableName::new("_Valid_1").is_ok());
+    }
+
+    #[test]
+    fn test_stats_totals() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let rows = [
+            (make_highlight("three little words", 1, None), "Book A"),
+            (make_highlight("two  words", 2, None), "Book A"),
+            (make_highlight("one", 3, None), "Book B"),
+        ];
+        for (h, title) in &rows {
+            insert_highlight(&conn, &table(), book_id(&conn, title, "Author"), h, None).unwrap();
+        }
+
+        let totals = stats(&conn, &table()).unwrap();
+
+        assert_eq!(
+            totals,
+            DbStats {
+                highlights: 3,
+                books: 2,
+                words: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn test_word_count_backfilled_for_old_rows() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let id = book_id(&conn, "Book", "Author");
+        insert_highlight(&conn, &table(), id, &make_highlight("a b c", 1, None), None).unwrap();
+        conn.execute("UPDATE highlights SET word_count = NULL", []).unwrap();
+
+        backfill_word_counts(&conn, "highlights").unwrap();
+
+        assert_eq!(stats(&conn, &table()).unwrap().words, 3);
+    }
+
+    #[test]
+    fn test_upsert_book_returns_same_id() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let mut book = BookData {
+            title: "Dune".to_string(),
+            author: "Frank Herbert".to_string(),
+            series: Some("Dune #1".to_string()),
+            ..Default::default()
+        };
+
+        let first = upsert_book(&conn, &book, BookMatch::TitleAuthor).unwrap();
+        book.series = None;
+        book.language = Some("en".to_string());
+        let second = upsert_book(&conn, &book, BookMatch::TitleAuthor).unwrap();
+
+        assert_eq!(first, second);
+        let (series, language): (Option<String>, Option<String>) = conn
+            .query_row("SELECT series, language FROM books", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(series.as_deref(), Some("Dune #1"));
+        assert_eq!(language.as_deref(), Some("en"));
+
+        let h = make_highlight("The spice must flow", 12, None);
+        assert!(insert_highlight(&conn, &table(), second, &h, None).unwrap());
+        let rows = highlights_for_book(&conn, &table(), "Dune").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].book_author, "Frank Herbert");
+    }
+
+    #[test]
+    fn test_book_match_strategies() {
+        let conn = init_db(Path::new(":memory:"), &table()).unwrap();
+        let book = |author: &str| BookData {
+            title: "Dune".to_string(),
+            author: author.to_string(),
+            ..Default::default()
+        };
+
+        let original = upsert_book(&conn, &book("Frank Herbert"), BookMatch::TitleAuthor).unwrap();
+        let by_title = upsert_book(&conn, &book("Herbert, Frank"), BookMatch::Title).unwrap();
+        let by_both = upsert_book(&conn, &book("Herbert, Frank"), BookMatch::TitleAuthor).unwrap();
+        let new_title = BookData {
+            title: "Dune Messiah".to_string(),
+            ..book("Frank Herbert")
+        };
+        let other = upsert_book(&conn, &new_title, BookMatch::Title).unwrap();
+
+        assert_eq!(by_title, original);
+        assert_ne!(by_both, original);
+        assert_ne!(other, original);
+        // the stored author is left alone when matching by title
+        let author: String = conn
+            .query_row("SELECT author FROM books WHERE id = ?1", [original], |row| row.get(0))
+            .unwrap();
+        assert_eq!(author, "Frank Herbert");
+    }
+
+    #[test]
+    fn test_init_db_migrates_flat_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE highlights (
+                    id INTEGER PRIMARY KEY,
+                    book_title TEXT NOT NULL,
+                    book_author TEXT NOT NULL,
+                    chapter TEXT,
+                    page INTEGER NOT NULL,
+                    text TEXT NOT NULL,
+                    note TEXT,
+                    datetime TEXT NOT NULL,
+                    processed INTEGER DEFAULT 0,
+                    created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+                    UNIQUE(book_title, page, text)
+                );
+                INSERT INTO highlights (id, book_title, book_author, page, text, datetime) VALUES
+                    (7, 'Book A', 'Author', 1, 'one', '2026-01-01 10:00:00'),
+                    (9, 'Book A', 'Author', 2, 'two words', '2026-01-02 10:00:00'),
+                    (12, 'Book B', 'Author', 1, 'three', '2026-01-03 10:00:00');",
+            )
+            .unwrap();
+        }
+
+        let conn = init_db(&path, &table()).unwrap();
+
+        let ids: Vec<i64> = highlights_for_book(&conn, &table(), "Book A")
+            .unwrap()
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(ids, vec![7, 9]);
+        assert_eq!(
+            stats(&conn, &table()).unwrap(),
+            DbStats {
+                highlights: 3,
+                books: 2,
+                words: 4,
+            }
+        );
+
+        // the old layout is still readable through the view
+        let flat: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM highlights_flat WHERE book_title = 'Book B'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(flat, 1);
+    }
 }
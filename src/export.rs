@@ -0,0 +1,1532 @@
+use crate::config::Config;
+use crate::models::{BookData, Highlight};
+use crate::sdr;
+use chrono::{Local, NaiveDateTime};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Csv,
+    Json,
+    Markdown,
+    Toml,
+    Xml,
+    Kindle,
+    /// Back into a KOReader `metadata.epub.lua`, see `sdr::write_book`
+    EpubNotes,
+}
+
+impl Format {
+    /// The name used on the command line, e.g. `csv`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+            Format::Markdown => "markdown",
+            Format::Toml => "toml",
+            Format::Xml => "xml",
+            Format::Kindle => "kindle",
+            Format::EpubNotes => "epub-notes",
+        }
+    }
+}
+
+/// Whether an export in `format` (and `shape`, for JSON) can be added to the
+/// end of an earlier one and still be valid, for `--append`.
+pub fn appendable(format: Format, shape: JsonShape) -> bool {
+    match format {
+        Format::Csv => true,
+        Format::Json => shape == JsonShape::Lines,
+        _ => false,
+    }
+}
+
+/// Opens the `--output` file, replacing it or, with `append`, adding to it.
+fn open_output(path: &str, append: bool) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+}
+
+/// Renders `books` in `format`, honouring the export options in `config`.
+pub fn render(format: Format, books: &[BookData], config: &Config) -> String {
+    match format {
+        Format::Csv => to_csv(books, &CsvOptions::from_config(config)),
+        Format::Json => to_json(books, &JsonOptions::from_config(config)),
+        Format::Markdown => to_markdown(books, &MarkdownOptions::from_config(config)),
+        Format::Toml => to_toml(books),
+        Format::Xml => to_xml(books),
+        Format::Kindle => to_kindle_clippings(books),
+        Format::EpubNotes => books
+            .first()
+            .map(sdr::serialize_metadata)
+            .unwrap_or_default(),
+    }
+}
+
+/// Writes the export where `config` asks for it: one file per book into the
+/// `--output` directory for `--split`, the `--output` file, or stdout.
+pub fn write(format: Format, books: &[BookData], config: &Config) -> io::Result<()> {
+    if config.split {
+        // config validation guarantees markdown and an output directory
+        let dir = Path::new(config.output.as_deref().unwrap_or("."));
+        return write_markdown_split(dir, books, &MarkdownOptions::from_config(config)).map(|_| ());
+    }
+
+    if format == Format::EpubNotes {
+        // config validation guarantees an output path
+        let target = Path::new(config.output.as_deref().unwrap_or("."));
+        return match books {
+            [book] => sdr::write_book(target, book),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("epub-notes writes one book at a time, got {}", books.len()),
+            )),
+        };
+    }
+
+    if let (Format::Csv, Some(path)) = (format, &config.output) {
+        let file = open_output(path, config.append)?;
+        let mut options = CsvOptions::from_config(config);
+        // a file being appended to already starts with the header
+        options.header &= file.metadata()?.len() == 0;
+        let mut file = io::BufWriter::new(file);
+        write_csv(&mut file, books, &options)?;
+        return file.flush();
+    }
+
+    let rendered = render(format, books, config);
+    match &config.output {
+        Some(path) => open_output(path, config.append)?.write_all(rendered.as_bytes()),
+        None => io::stdout().write_all(rendered.as_bytes()),
+    }
+}
+
+/// A field that tabular exports know how to emit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Title,
+    Author,
+    Chapter,
+    Page,
+    Text,
+    Note,
+    Datetime,
+    Kind,
+}
+
+impl Column {
+    pub const ALL: [Column; 8] = [
+        Column::Title,
+        Column::Author,
+        Column::Chapter,
+        Column::Page,
+        Column::Text,
+        Column::Note,
+        Column::Datetime,
+        Column::Kind,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Title => "title",
+            Column::Author => "author",
+            Column::Chapter => "chapter",
+            Column::Page => "page",
+            Column::Text => "text",
+            Column::Note => "note",
+            Column::Datetime => "datetime",
+            Column::Kind => "kind",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Column> {
+        Column::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    fn value(&self, row: &Row) -> String {
+        let h = row.highlight;
+        match self {
+            Column::Title => row.book.title.clone(),
+            Column::Author => row.book.author.clone(),
+            Column::Chapter => h.chapter.clone().unwrap_or_default(),
+            Column::Page => h.page.to_string(),
+            Column::Text => h.text.clone(),
+            Column::Note => h.note.clone().unwrap_or_default(),
+            Column::Datetime => h.datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
+            Column::Kind => h.kind.as_str().to_string(),
+        }
+    }
+}
+
+/// Parses a comma-separated column list like `title,page,text`, returning the
+/// first unknown name as the error.
+pub fn parse_columns(list: &str) -> Result<Vec<Column>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| Column::from_name(name).ok_or_else(|| name.to_string()))
+        .collect()
+}
+
+/// Parses the `--delimiter` value: a single character, or `\t` / `tab` for
+/// tabs since those are awkward to type. Quotes and line breaks can't be
+/// used, as CSV quoting relies on them.
+pub fn parse_delimiter(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    let delimiter = match (value, chars.next(), chars.next()) {
+        ("\\t" | "tab", _, _) => '\t',
+        (_, Some(c), None) => c,
+        _ => return Err(value.to_string()),
+    };
+
+    if matches!(delimiter, '"' | '\n' | '\r') {
+        return Err(value.to_string());
+    }
+    Ok(delimiter)
+}
+
+/// One highlight together with the book it belongs to, i.e. one output line.
+pub struct Row<'a> {
+    pub book: &'a BookData,
+    pub highlight: &'a Highlight,
+}
+
+pub fn rows(books: &[BookData]) -> impl Iterator<Item = Row<'_>> {
+    books.iter().flat_map(|book| {
+        book.highlights
+            .iter()
+            .map(move |highlight| Row { book, highlight })
+    })
+}
+
+pub fn select_columns(row: &Row, columns: &[Column]) -> Vec<String> {
+    columns.iter().map(|c| c.value(row)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvOptions {
+    pub columns: Vec<Column>,
+    pub delimiter: char,
+    /// Start with a line of column names; off for appending to a file.
+    pub header: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            columns: Column::ALL.to_vec(),
+            delimiter: ',',
+            header: true,
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn from_config(config: &Config) -> Self {
+        CsvOptions {
+            columns: config.columns.clone(),
+            delimiter: config.delimiter,
+            header: !config.no_header,
+        }
+    }
+}
+
+pub fn to_csv(books: &[BookData], options: &CsvOptions) -> String {
+    let mut out = Vec::new();
+    // writing into memory can't fail
+    let _ = write_csv(&mut out, books, options);
+    String::from_utf8(out).unwrap_or_default()
+}
+
+/// Like `to_csv`, writing each line to `w` as it goes instead of building
+/// the whole export in memory.
+pub fn write_csv<W: Write>(w: &mut W, books: &[BookData], options: &CsvOptions) -> io::Result<()> {
+    let delimiter = options.delimiter;
+
+    if options.header {
+        let header: Vec<String> = options
+            .columns
+            .iter()
+            .map(|c| c.name().to_string())
+            .collect();
+        write_csv_line(w, &header, delimiter)?;
+    }
+
+    for row in rows(books) {
+        write_csv_line(w, &select_columns(&row, &options.columns), delimiter)?;
+    }
+
+    Ok(())
+}
+
+fn write_csv_line<W: Write>(w: &mut W, fields: &[String], delimiter: char) -> io::Result<()> {
+    let line: Vec<String> = fields.iter().map(|f| csv_field(f, delimiter)).collect();
+    writeln!(w, "{}", line.join(&delimiter.to_string()))
+}
+
+fn csv_field(value: &str, delimiter: char) -> String {
+    if value.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Builds a link that points back at the highlight in KOReader, using the
+/// selection's start xpointer when known and the page otherwise.
+pub fn koreader_link(row: &Row) -> String {
+    let title = percent_encode(&row.book.title);
+    match &row.highlight.pos0 {
+        Some(pos0) => format!("koreader://book/{}/pos/{}", title, percent_encode(pos0)),
+        None => format!("koreader://book/{}/page/{}", title, row.highlight.page),
+    }
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut out = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JsonOptions {
+    pub with_links: bool,
+    /// Indent the output for reading instead of one compact line.
+    pub pretty: bool,
+    pub shape: JsonShape,
+    /// Output keys to rename, see `parse_field_map`.
+    pub field_map: Vec<(String, String)>,
+    /// Add `word_count`, `content_hash` and (when the page count is known)
+    /// `progress` to each highlight.
+    pub with_computed: bool,
+}
+
+impl JsonOptions {
+    pub fn from_config(config: &Config) -> Self {
+        JsonOptions {
+            with_links: config.with_links,
+            pretty: config.json_pretty,
+            shape: config.json_shape,
+            field_map: config.field_map.clone(),
+            with_computed: config.with_computed,
+        }
+    }
+}
+
+/// Every key `to_json` can write.
+pub const JSON_FIELDS: [&str; 13] = [
+    "title",
+    "author",
+    "chapter",
+    "page",
+    "text",
+    "note",
+    "datetime",
+    "kind",
+    "koreader_link",
+    "word_count",
+    "content_hash",
+    "progress",
+    "highlights",
+];
+
+/// Parses a `--field-map` list like `text=content,note=annotation`. The
+/// error is the first entry that isn't `known=new` with a key from
+/// `JSON_FIELDS`.
+pub fn parse_field_map(list: &str) -> Result<Vec<(String, String)>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((from, to)) if JSON_FIELDS.contains(&from.trim()) && !to.trim().is_empty() => {
+                Ok((from.trim().to_string(), to.trim().to_string()))
+            }
+            _ => Err(entry.to_string()),
+        })
+        .collect()
+}
+
+/// Renames the keys of `item` as `field_map` says, keeping their order.
+fn rename_fields(item: Map<String, Value>, field_map: &[(String, String)]) -> Map<String, Value> {
+    if field_map.is_empty() {
+        return item;
+    }
+    item.into_iter()
+        .map(|(key, value)| {
+            let renamed = field_map.iter().find(|(from, _)| *from == key);
+            (renamed.map_or(key, |(_, to)| to.clone()), value)
+        })
+        .collect()
+}
+
+/// How `to_json` lays out the highlights.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum JsonShape {
+    /// One object per highlight, with the book fields repeated on each
+    #[default]
+    Flat,
+    /// One object per book, holding its highlights
+    Nested,
+    /// One flat object per line (JSON Lines), so exports can be appended
+    Lines,
+}
+
+/// Renders the highlights as JSON, laid out as `options.shape` asks. The
+/// output is a single line unless `options.pretty` (or one line per highlight
+/// for `JsonShape::Lines`, which is never indented).
+pub fn to_json(books: &[BookData], options: &JsonOptions) -> String {
+    let items: Vec<Value> = match options.shape {
+        JsonShape::Lines => {
+            return rows(books)
+                .map(|row| Value::Object(highlight_object(&row, options, true)).to_string() + "\n")
+                .collect();
+        }
+        JsonShape::Flat => rows(books)
+            .map(|row| Value::Object(highlight_object(&row, options, true)))
+            .collect(),
+        JsonShape::Nested => books
+            .iter()
+            .map(|book| {
+                let highlights: Vec<Value> = book
+                    .highlights
+                    .iter()
+                    .map(|highlight| {
+                        let row = Row { book, highlight };
+                        Value::Object(highlight_object(&row, options, false))
+                    })
+                    .collect();
+                let mut item = Map::new();
+                item.insert("title".into(), book.title.clone().into());
+                item.insert("author".into(), book.author.clone().into());
+                item.insert("highlights".into(), highlights.into());
+                Value::Object(rename_fields(item, &options.field_map))
+            })
+            .collect(),
+    };
+
+    let items = Value::Array(items);
+    let mut out = if options.pretty {
+        serde_json::to_string_pretty(&items)
+    } else {
+        serde_json::to_string(&items)
+    }
+    .unwrap_or_default();
+    out.push('\n');
+    out
+}
+
+fn highlight_object(row: &Row, options: &JsonOptions, with_book: bool) -> Map<String, Value> {
+    let h = row.highlight;
+    let mut item = Map::new();
+    if with_book {
+        item.insert("title".into(), row.book.title.clone().into());
+        item.insert("author".into(), row.book.author.clone().into());
+    }
+    item.insert("chapter".into(), h.chapter.clone().into());
+    item.insert("page".into(), h.page.into());
+    item.insert("text".into(), h.text.clone().into());
+    item.insert("note".into(), h.note.clone().into());
+    item.insert(
+        "datetime".into(),
+        h.datetime.format("%Y-%m-%d %H:%M:%S").to_string().into(),
+    );
+    item.insert("kind".into(), h.kind.as_str().into());
+    if options.with_links {
+        item.insert("koreader_link".into(), koreader_link(row).into());
+    }
+    if options.with_computed {
+        item.insert("word_count".into(), h.word_count().into());
+        item.insert("content_hash".into(), h.content_hash().into());
+        if let Some(progress) = h.progress(row.book.doc_pages) {
+            item.insert("progress".into(), progress.into());
+        }
+    }
+    rename_fields(item, &options.field_map)
+}
+
+/// Renders highlights as a TOML array of tables, one `[[highlight]]` each.
+/// TOML has no null, so a missing note is simply left out.
+pub fn to_toml(books: &[BookData]) -> String {
+    let mut out = String::new();
+
+    for row in rows(books) {
+        let h = row.highlight;
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str("[[highlight]]\n");
+        out.push_str(&format!("title = {}\n", toml_string(&row.book.title)));
+        out.push_str(&format!("author = {}\n", toml_string(&row.book.author)));
+        out.push_str(&format!("page = {}\n", h.page));
+        out.push_str(&format!("text = {}\n", toml_string(&h.text)));
+        if let Some(note) = &h.note {
+            out.push_str(&format!("note = {}\n", toml_string(note)));
+        }
+        out.push_str(&format!(
+            "datetime = {}\n",
+            toml_string(&h.datetime.format("%Y-%m-%d %H:%M:%S").to_string())
+        ));
+    }
+
+    out
+}
+
+fn toml_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Renders the highlights as a `<library>` of `<book>` elements, each holding
+/// its `<highlight>`s. Page, time and kind are attributes; chapter, text and
+/// note are child elements, left out when missing.
+pub fn to_xml(books: &[BookData]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<library>\n");
+
+    for book in books {
+        out.push_str(&format!(
+            "  <book title=\"{}\" author=\"{}\">\n",
+            xml_escape(&book.title),
+            xml_escape(&book.author)
+        ));
+        for h in &book.highlights {
+            out.push_str(&format!(
+                "    <highlight page=\"{}\" datetime=\"{}\" kind=\"{}\">\n",
+                h.page,
+                h.datetime.format("%Y-%m-%d %H:%M:%S"),
+                h.kind.as_str()
+            ));
+            if let Some(chapter) = &h.chapter {
+                out.push_str(&format!(
+                    "      <chapter>{}</chapter>\n",
+                    xml_escape(chapter)
+                ));
+            }
+            out.push_str(&format!("      <text>{}</text>\n", xml_escape(&h.text)));
+            if let Some(note) = &h.note {
+                out.push_str(&format!("      <note>{}</note>\n", xml_escape(note)));
+            }
+            out.push_str("    </highlight>\n");
+        }
+        out.push_str("  </book>\n");
+    }
+
+    out.push_str("</library>\n");
+    out
+}
+
+/// Escapes text for use in both XML content and double-quoted attributes.
+/// Control characters other than tab and newline aren't allowed in XML 1.0
+/// at all, so they're dropped.
+fn xml_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Renders the highlights the way a Kindle writes `My Clippings.txt`, so
+/// they can be loaded by tools made for it. Each highlight is one entry, and
+/// a note becomes a second entry right after it, as on the Kindle. Lines end
+/// in `\r\n` like the original file.
+pub fn to_kindle_clippings(books: &[BookData]) -> String {
+    let mut out = String::new();
+
+    for row in rows(books) {
+        let h = row.highlight;
+        let heading = format!("{} ({})", row.book.title, row.book.author);
+        let added = h.datetime.format("%A, %B %-d, %Y %-I:%M:%S %p");
+
+        let mut entry = |kind: &str, body: &str| {
+            out.push_str(&format!(
+                "{}\r\n- Your {} on page {} | Added on {}\r\n\r\n{}\r\n==========\r\n",
+                heading,
+                kind,
+                h.page,
+                added,
+                body.replace('\n', "\r\n")
+            ));
+        };
+
+        if !h.text.is_empty() {
+            entry("Highlight", &h.text);
+        }
+        if let Some(note) = &h.note {
+            entry("Note", note);
+        }
+    }
+
+    out
+}
+
+/// How Markdown exports set off the highlighted text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum QuoteStyle {
+    /// `> text`
+    #[default]
+    Blockquote,
+    /// An Obsidian `> [!quote]` callout
+    Callout,
+    /// The text as a plain paragraph
+    Plain,
+}
+
+/// What Markdown exports put between two highlights under one heading.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Separator {
+    /// An empty line
+    #[default]
+    Blank,
+    /// A `---` horizontal rule
+    Rule,
+    /// Nothing, so the quotes run together
+    None,
+}
+
+/// The quoted text of one highlight in `style`, with `suffix` (page number,
+/// footnote marker) at the end of the last line.
+fn render_quote(text: &str, style: QuoteStyle, suffix: &str) -> String {
+    let prefix = match style {
+        QuoteStyle::Blockquote | QuoteStyle::Callout => "> ",
+        QuoteStyle::Plain => "",
+    };
+    let mut out = match style {
+        QuoteStyle::Callout => String::from("> [!quote]\n"),
+        _ => String::new(),
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        let end = if i + 1 == lines.len() { suffix } else { "" };
+        out.push_str(&format!("{}{}{}\n", prefix, line, end));
+    }
+    out
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MarkdownOptions {
+    pub with_links: bool,
+    /// Put notes in footnotes after the book instead of under each quote.
+    pub notes_as_footnotes: bool,
+    /// Start the combined file with a YAML block for static-site generators.
+    pub frontmatter: bool,
+    /// The `generated` value of the frontmatter.
+    pub generated: NaiveDateTime,
+    /// List the books and chapters, linked to their headings, at the top.
+    pub toc: bool,
+    pub quote_style: QuoteStyle,
+    pub separator: Separator,
+    /// Follow each quote with its page, e.g. ` — p.42`, when it's known.
+    pub show_pages: bool,
+}
+
+impl MarkdownOptions {
+    pub fn from_config(config: &Config) -> Self {
+        MarkdownOptions {
+            with_links: config.with_links,
+            notes_as_footnotes: config.notes_as_footnotes,
+            frontmatter: config.frontmatter,
+            generated: Local::now().naive_local(),
+            toc: config.toc,
+            quote_style: config.quote_style,
+            separator: config.separator,
+            show_pages: config.show_pages,
+        }
+    }
+}
+
+pub fn to_markdown(books: &[BookData], options: &MarkdownOptions) -> String {
+    let body = books
+        .iter()
+        .map(|book| book_to_markdown(book, options))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut parts = Vec::new();
+    if options.frontmatter {
+        parts.push(frontmatter(books, options.generated));
+    }
+    if options.toc {
+        parts.push(toc(books));
+    }
+    parts.push(body);
+    parts.join("\n")
+}
+
+/// A nested list linking every book and chapter heading `book_to_markdown`
+/// writes, with anchors worked out the way GitHub and most renderers do.
+fn toc(books: &[BookData]) -> String {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut out = String::new();
+
+    for book in books {
+        let anchor = unique_slug(&book.title, &mut seen);
+        out.push_str(&format!("- [{}](#{})\n", link_text(&book.title), anchor));
+
+        // the same chapter changes that start a `##` heading
+        let mut current_chapter: Option<&str> = None;
+        for h in &book.highlights {
+            let chapter = h.chapter.as_deref();
+            if let Some(name) = chapter.filter(|_| chapter != current_chapter) {
+                let anchor = unique_slug(name, &mut seen);
+                out.push_str(&format!("  - [{}](#{})\n", link_text(name), anchor));
+            }
+            current_chapter = chapter;
+        }
+    }
+
+    out
+}
+
+fn link_text(text: &str) -> String {
+    text.replace('[', "\\[").replace(']', "\\]")
+}
+
+/// The anchor a renderer gives a heading: lowercased, punctuation dropped,
+/// spaces turned into dashes.
+pub fn slugify(heading: &str) -> String {
+    heading
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `slugify`, with a `-1`, `-2`... suffix for headings seen before in the
+/// same document.
+fn unique_slug(heading: &str, seen: &mut HashMap<String, usize>) -> String {
+    let slug = slugify(heading);
+    let count = seen.entry(slug.clone()).or_insert(0);
+    *count += 1;
+    match *count {
+        1 => slug,
+        n => format!("{}-{}", slug, n - 1),
+    }
+}
+
+fn frontmatter(books: &[BookData], generated: NaiveDateTime) -> String {
+    let highlights: usize = books.iter().map(|b| b.highlights.len()).sum();
+    // a JSON string is also a valid double-quoted YAML scalar
+    let generated = Value::from(generated.format("%Y-%m-%d %H:%M:%S").to_string());
+
+    format!(
+        "---\ngenerated: {}\nbooks: {}\nhighlights: {}\n---\n",
+        generated,
+        books.len(),
+        highlights
+    )
+}
+
+fn book_to_markdown(book: &BookData, options: &MarkdownOptions) -> String {
+    let mut out = format!("# {}\n\n*{}*\n", book.title, book.author);
+    let mut current_chapter: Option<&str> = None;
+    let mut footnotes: Vec<&str> = Vec::new();
+
+    for (i, h) in book.highlights.iter().enumerate() {
+        let chapter = h.chapter.as_deref();
+        let new_chapter = chapter.is_some() && chapter != current_chapter;
+        if new_chapter {
+            out.push_str(&format!("\n## {}\n", chapter.unwrap_or_default()));
+        }
+        current_chapter = chapter;
+
+        let follows_highlight = i > 0 && !new_chapter;
+        match options.separator {
+            Separator::Rule if follows_highlight => out.push_str("\n---\n\n"),
+            Separator::None if follows_highlight => {}
+            _ => out.push('\n'),
+        }
+        let footnote = match &h.note {
+            Some(note) if options.notes_as_footnotes && !h.text.is_empty() => {
+                footnotes.push(note.as_str());
+                Some(footnotes.len())
+            }
+            _ => None,
+        };
+        if !h.text.is_empty() {
+            let mut suffix = String::new();
+            // page 0 means KOReader didn't know the page
+            if options.show_pages && h.page > 0 {
+                suffix.push_str(&format!(" — p.{}", h.page));
+            }
+            if let Some(n) = footnote {
+                suffix.push_str(&format!(" [^{}]", n));
+            }
+            out.push_str(&render_quote(&h.text, options.quote_style, &suffix));
+        }
+        if let (Some(note), None) = (&h.note, footnote) {
+            if !h.text.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("**Note:** {}\n", note));
+        }
+        if options.with_links {
+            let row = Row { book, highlight: h };
+            out.push_str(&format!("\n[Open in KOReader]({})\n", koreader_link(&row)));
+        }
+    }
+
+    // numbered per book, so each book's section stands on its own
+    if !footnotes.is_empty() {
+        out.push('\n');
+        for (i, note) in footnotes.iter().enumerate() {
+            // continuation lines of a footnote are indented
+            out.push_str(&format!("[^{}]: {}\n", i + 1, note.replace('\n', "\n    ")));
+        }
+    }
+
+    out
+}
+
+/// Writes one Markdown file per book into `dir`, named after the sanitized
+/// title. Titles that end up with the same name get a ` (2)`, ` (3)`...
+/// suffix. Returns the written paths in book order.
+pub fn write_markdown_split(
+    dir: &Path,
+    books: &[BookData],
+    options: &MarkdownOptions,
+) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+
+    let mut used: HashSet<String> = HashSet::new();
+    let mut written = Vec::new();
+
+    for book in books {
+        let base = sanitize_filename(&book.title);
+        let mut name = base.clone();
+        let mut counter = 1;
+        // compare case-insensitively, since macOS and Windows filesystems do
+        while !used.insert(name.to_lowercase()) {
+            counter += 1;
+            name = format!("{} ({})", base, counter);
+        }
+
+        let path = dir.join(format!("{}.md", name));
+        fs::write(&path, book_to_markdown(book, options))?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/// Makes a book title safe to use as a file name, replacing the characters
+/// Obsidian refuses in note names (a superset of what Windows rejects).
+pub fn sanitize_filename(title: &str) -> String {
+    const FORBIDDEN: &[char] = &[
+        '*', '"', '\\', '/', '<', '>', ':', '|', '?', '#', '^', '[', ']',
+    ];
+
+    let cleaned: String = title
+        .chars()
+        .map(|c| {
+            if FORBIDDEN.contains(&c) || c.is_control() {
+                '-'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // leading dots hide the file, trailing dots/spaces upset Windows
+    let cleaned = cleaned
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' ']);
+
+    if cleaned.is_empty() {
+        "Untitled".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    fn make_book() -> BookData {
+        BookData {
+            title: "Test Book".to_string(),
+            author: "Test Author".to_string(),
+            series: None,
+            language: None,
+            description: None,
+            doc_format: None,
+            doc_pages: Some(200),
+            highlights: vec![
+                Highlight {
+                    chapter: Some("Chapter 1".to_string()),
+                    page: 42,
+                    text: "Plain text".to_string(),
+                    note: None,
+                    datetime: NaiveDateTime::parse_from_str(
+                        "2026-01-25 10:30:00",
+                        "%Y-%m-%d %H:%M:%S",
+                    )
+                    .unwrap(),
+                    ..Default::default()
+                },
+                Highlight {
+                    chapter: None,
+                    page: 100,
+                    text: "Text with \"quotes\", and a comma".to_string(),
+                    note: Some("a note".to_string()),
+                    datetime: NaiveDateTime::parse_from_str(
+                        "2026-01-26 14:00:00",
+                        "%Y-%m-%d %H:%M:%S",
+                    )
+                    .unwrap(),
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_to_csv_all_columns() {
+        let csv = to_csv(&[make_book()], &CsvOptions::default());
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "title,author,chapter,page,text,note,datetime,kind"
+        );
+        assert_eq!(
+            lines[1],
+            "Test Book,Test Author,Chapter 1,42,Plain text,,2026-01-25 10:30:00,highlight"
+        );
+        assert_eq!(
+            lines[2],
+            "Test Book,Test Author,,100,\"Text with \"\"quotes\"\", and a comma\",a note,2026-01-26 14:00:00,highlight"
+        );
+    }
+
+    #[test]
+    fn test_to_csv_custom_column_subset() {
+        let columns = parse_columns("page,title,text").unwrap();
+        let options = CsvOptions {
+            columns,
+            ..Default::default()
+        };
+        let csv = to_csv(&[make_book()], &options);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines[0], "page,title,text");
+        assert_eq!(lines[1], "42,Test Book,Plain text");
+    }
+
+    #[test]
+    fn test_to_csv_other_delimiters_quote_relative_to_them() {
+        let mut book = make_book();
+        book.title = "Title; with a semicolon\tand a tab".to_string();
+        let mut options = CsvOptions {
+            columns: parse_columns("title,text").unwrap(),
+            delimiter: ';',
+            ..Default::default()
+        };
+
+        let semicolon = to_csv(&[book.clone()], &options);
+        let lines: Vec<&str> = semicolon.lines().collect();
+        assert_eq!(lines[0], "title;text");
+        assert_eq!(
+            lines[1],
+            "\"Title; with a semicolon\tand a tab\";Plain text"
+        );
+        // commas don't need quoting any more, the embedded quotes still do
+        assert_eq!(
+            lines[2],
+            "\"Title; with a semicolon\tand a tab\";\"Text with \"\"quotes\"\", and a comma\""
+        );
+
+        options.delimiter = '\t';
+        let tab = to_csv(&[book], &options);
+        let lines: Vec<&str> = tab.lines().collect();
+        assert_eq!(lines[0], "title\ttext");
+        assert_eq!(
+            lines[1],
+            "\"Title; with a semicolon\tand a tab\"\tPlain text"
+        );
+    }
+
+    #[test]
+    fn test_write_csv_matches_to_csv() {
+        let options = CsvOptions {
+            delimiter: ';',
+            ..Default::default()
+        };
+        let mut buffer = Vec::new();
+
+        write_csv(&mut buffer, &[make_book(), make_book()], &options).unwrap();
+
+        assert_eq!(
+            buffer,
+            to_csv(&[make_book(), make_book()], &options).into_bytes()
+        );
+        assert!(buffer.starts_with(b"title;author;"));
+    }
+
+    #[test]
+    fn test_to_csv_without_header_starts_with_data() {
+        let options = CsvOptions {
+            header: false,
+            ..Default::default()
+        };
+
+        let csv = to_csv(&[make_book()], &options);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("Test Book,Test Author,Chapter 1,42,Plain text,"));
+        assert!(lines[1].contains("\"Text with \"\"quotes\"\", and a comma\""));
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(parse_delimiter(";"), Ok(';'));
+        assert_eq!(parse_delimiter("\\t"), Ok('\t'));
+        assert_eq!(parse_delimiter("tab"), Ok('\t'));
+        assert_eq!(parse_delimiter("\t"), Ok('\t'));
+        assert_eq!(parse_delimiter(";;"), Err(";;".to_string()));
+        assert_eq!(parse_delimiter(""), Err(String::new()));
+        assert_eq!(parse_delimiter("\""), Err("\"".to_string()));
+    }
+
+    #[test]
+    fn test_select_columns_orders_values() {
+        let book = make_book();
+        let row = Row {
+            book: &book,
+            highlight: &book.highlights[0],
+        };
+
+        let values = select_columns(&row, &[Column::Text, Column::Page]);
+
+        assert_eq!(values, vec!["Plain text", "42"]);
+    }
+
+    #[test]
+    fn test_parse_columns_unknown_name() {
+        let result = parse_columns("title,pages,text");
+
+        assert_eq!(result, Err("pages".to_string()));
+    }
+
+    #[test]
+    fn test_to_markdown_groups_by_chapter() {
+        let md = to_markdown(&[make_book()], &MarkdownOptions::default());
+
+        assert!(md.starts_with("# Test Book\n\n*Test Author*\n"));
+        assert!(md.contains("\n## Chapter 1\n\n> Plain text\n"));
+        assert!(md.contains("> Text with \"quotes\", and a comma\n\n**Note:** a note\n"));
+    }
+
+    #[test]
+    fn test_to_markdown_show_pages() {
+        let mut book = make_book();
+        book.highlights[1].page = 0;
+        let options = MarkdownOptions {
+            show_pages: true,
+            ..Default::default()
+        };
+
+        let md = to_markdown(&[book], &options);
+
+        assert!(md.contains("> Plain text — p.42\n"));
+        assert!(md.contains("> Text with \"quotes\", and a comma\n"));
+        assert!(!md.contains("p.0"));
+        assert!(!to_markdown(&[make_book()], &MarkdownOptions::default()).contains("— p."));
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("Plain Title"), "Plain Title");
+        assert_eq!(sanitize_filename("What? Why: A/B"), "What- Why- A-B");
+        assert_eq!(sanitize_filename("..."), "Untitled");
+    }
+
+    #[test]
+    fn test_write_markdown_split_one_file_per_book() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = make_book();
+        let mut illegal = make_book();
+        illegal.title = "Why? A <Guide>".to_string();
+
+        let written =
+            write_markdown_split(dir.path(), &[plain, illegal], &MarkdownOptions::default())
+                .unwrap();
+
+        assert_eq!(
+            written,
+            vec![
+                dir.path().join("Test Book.md"),
+                dir.path().join("Why- A -Guide-.md"),
+            ]
+        );
+        let content = fs::read_to_string(&written[1]).unwrap();
+        assert!(content.starts_with("# Why? A <Guide>\n"));
+    }
+
+    #[test]
+    fn test_write_markdown_split_title_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = make_book();
+        first.title = "A/B".to_string();
+        let mut second = make_book();
+        second.title = "A:B".to_string();
+
+        let written =
+            write_markdown_split(dir.path(), &[first, second], &MarkdownOptions::default())
+                .unwrap();
+
+        assert_eq!(written[0], dir.path().join("A-B.md"));
+        assert_eq!(written[1], dir.path().join("A-B (2).md"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_koreader_link_with_and_without_positions() {
+        let mut book = make_book();
+        book.title = "A Book/Title".to_string();
+        book.highlights[1].pos0 = Some("/body/DocFragment[3]/body/p[2]/text().15".to_string());
+
+        let by_page = Row {
+            book: &book,
+            highlight: &book.highlights[0],
+        };
+        let by_pos = Row {
+            book: &book,
+            highlight: &book.highlights[1],
+        };
+
+        assert_eq!(
+            koreader_link(&by_page),
+            "koreader://book/A%20Book%2FTitle/page/42"
+        );
+        assert_eq!(
+            koreader_link(&by_pos),
+            "koreader://book/A%20Book%2FTitle/pos/%2Fbody%2FDocFragment%5B3%5D%2Fbody%2Fp%5B2%5D%2Ftext%28%29.15"
+        );
+    }
+
+    #[test]
+    fn test_to_json_flat_objects() {
+        let json = to_json(&[make_book()], &JsonOptions::default());
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+
+        let items = parsed.as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["title"], "Test Book");
+        assert_eq!(items[0]["page"], 42);
+        assert_eq!(items[0]["note"], Value::Null);
+        assert_eq!(items[1]["text"], "Text with \"quotes\", and a comma");
+        assert!(items[0].get("koreader_link").is_none());
+    }
+
+    #[test]
+    fn test_to_json_nested_groups_by_book() {
+        let mut other = make_book();
+        other.title = "Other Book".to_string();
+        other.highlights.truncate(1);
+        let options = JsonOptions {
+            shape: JsonShape::Nested,
+            ..Default::default()
+        };
+
+        let json = to_json(&[make_book(), other], &options);
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+
+        let books = parsed.as_array().unwrap();
+        assert_eq!(books.len(), 2);
+        assert_eq!(books[0]["title"], "Test Book");
+        assert_eq!(books[0]["author"], "Test Author");
+        assert_eq!(books[0]["highlights"].as_array().unwrap().len(), 2);
+        assert_eq!(books[0]["highlights"][1]["page"], 100);
+        assert!(books[0]["highlights"][0].get("title").is_none());
+        assert_eq!(books[1]["title"], "Other Book");
+        assert_eq!(books[1]["highlights"].as_array().unwrap().len(), 1);
+
+        let flat = to_json(&[make_book()], &JsonOptions::default());
+        let flat: Value = serde_json::from_str(&flat).unwrap();
+        assert!(flat
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|item| item["title"] == "Test Book" && item["author"] == "Test Author"));
+    }
+
+    #[test]
+    fn test_to_json_field_map_renames_keys() {
+        let options = JsonOptions {
+            field_map: parse_field_map("text=content, note=annotation").unwrap(),
+            ..Default::default()
+        };
+
+        let json = to_json(&[make_book()], &options);
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+
+        let items = parsed.as_array().unwrap();
+        assert_eq!(items[1]["content"], "Text with \"quotes\", and a comma");
+        assert_eq!(items[1]["annotation"], "a note");
+        assert_eq!(items[1]["page"], 100);
+        assert!(items[1].get("text").is_none());
+        assert!(items[1].get("note").is_none());
+        assert_eq!(
+            parse_field_map("txt=content"),
+            Err("txt=content".to_string())
+        );
+    }
+
+    #[test]
+    fn test_to_json_with_computed_fields() {
+        let mut book = make_book();
+        book.highlights[1].page = 0;
+        let options = JsonOptions {
+            with_computed: true,
+            ..Default::default()
+        };
+
+        let json = to_json(&[book.clone()], &options);
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+
+        let items = parsed.as_array().unwrap();
+        assert_eq!(items[0]["word_count"], 2);
+        assert_eq!(
+            items[0]["content_hash"],
+            book.highlights[0].content_hash().as_str()
+        );
+        assert_eq!(items[0]["content_hash"].as_str().unwrap().len(), 16);
+        assert_ne!(items[0]["content_hash"], items[1]["content_hash"]);
+        assert_eq!(items[0]["progress"], 0.21);
+        // page 0 means the page isn't known
+        assert!(items[1].get("progress").is_none());
+
+        let plain = to_json(&[make_book()], &JsonOptions::default());
+        assert!(!plain.contains("word_count"));
+    }
+
+    #[test]
+    fn test_append_accumulates_json_lines() {
+        use crate::config::CliArgs;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("highlights.jsonl");
+        let cli = CliArgs {
+            from: Some("2026-01-01".to_string()),
+            to: Some("2026-01-31".to_string()),
+            format: Some(Format::Json),
+            json_shape: Some(JsonShape::Lines),
+            output: Some(path.to_string_lossy().into_owned()),
+            append: true,
+            ..Default::default()
+        };
+        let config = Config::from_args(cli, NaiveDate::from_ymd_opt(2026, 2, 1).unwrap()).unwrap();
+
+        write(Format::Json, &[make_book()], &config).unwrap();
+        write(Format::Json, &[make_book()], &config).unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2]["text"], "Plain text");
+        assert_eq!(lines[3]["page"], 100);
+    }
+
+    #[test]
+    fn test_to_json_compact_and_pretty() {
+        let compact = to_json(&[make_book()], &JsonOptions::default());
+        let pretty = to_json(
+            &[make_book()],
+            &JsonOptions {
+                pretty: true,
+                ..Default::default()
+            },
+        );
+
+        assert!(!compact.trim_end().contains('\n'));
+        assert!(pretty.contains("\n  {\n    \"title\": \"Test Book\""));
+        let compact: Value = serde_json::from_str(&compact).unwrap();
+        let pretty: Value = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(compact, pretty);
+    }
+
+    #[test]
+    fn test_links_included_when_requested() {
+        let options = JsonOptions {
+            with_links: true,
+            ..Default::default()
+        };
+        let json = to_json(&[make_book()], &options);
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            parsed[0]["koreader_link"],
+            "koreader://book/Test%20Book/page/42"
+        );
+
+        let options = MarkdownOptions {
+            with_links: true,
+            ..Default::default()
+        };
+        let md = to_markdown(&[make_book()], &options);
+        assert!(md.contains("[Open in KOReader](koreader://book/Test%20Book/page/42)"));
+    }
+
+    #[test]
+    fn test_markdown_notes_as_footnotes() {
+        let mut book = make_book();
+        book.highlights[0].note = Some("first note".to_string());
+        book.highlights[1].text = "Two\nlines".to_string();
+        let options = MarkdownOptions {
+            notes_as_footnotes: true,
+            ..Default::default()
+        };
+
+        let md = to_markdown(&[book], &options);
+
+        assert!(md.contains("> Plain text [^1]\n"));
+        assert!(md.contains("> Two\n> lines [^2]\n"));
+        assert!(md.ends_with("\n[^1]: first note\n[^2]: a note\n"));
+        assert!(!md.contains("**Note:**"));
+    }
+
+    #[test]
+    fn test_markdown_frontmatter() {
+        let options = MarkdownOptions {
+            frontmatter: true,
+            generated: NaiveDateTime::parse_from_str("2026-02-01 08:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+            ..Default::default()
+        };
+
+        let md = to_markdown(&[make_book()], &options);
+
+        let block = md
+            .strip_prefix("---\n")
+            .unwrap()
+            .split("\n---\n")
+            .next()
+            .unwrap();
+        let yaml: serde_yaml::Value = serde_yaml::from_str(block).unwrap();
+        assert_eq!(yaml["generated"].as_str(), Some("2026-02-01 08:00:00"));
+        assert_eq!(yaml["books"].as_u64(), Some(1));
+        assert_eq!(yaml["highlights"].as_u64(), Some(2));
+        assert!(md.contains("---\n\n# Test Book\n"));
+
+        assert!(!to_markdown(&[make_book()], &MarkdownOptions::default()).starts_with("---"));
+    }
+
+    #[test]
+    fn test_quote_styles() {
+        let text = "First line\nsecond line";
+
+        assert_eq!(
+            render_quote(text, QuoteStyle::Blockquote, ""),
+            "> First line\n> second line\n"
+        );
+        assert_eq!(
+            render_quote(text, QuoteStyle::Callout, " [^2]"),
+            "> [!quote]\n> First line\n> second line [^2]\n"
+        );
+        assert_eq!(
+            render_quote(text, QuoteStyle::Plain, ""),
+            "First line\nsecond line\n"
+        );
+
+        let options = MarkdownOptions {
+            quote_style: QuoteStyle::Callout,
+            ..Default::default()
+        };
+        assert!(to_markdown(&[make_book()], &options).contains("\n> [!quote]\n> Plain text\n"));
+    }
+
+    #[test]
+    fn test_markdown_separators() {
+        let with = |separator| {
+            let options = MarkdownOptions {
+                separator,
+                ..Default::default()
+            };
+            to_markdown(&[make_book()], &options)
+        };
+
+        assert!(with(Separator::Rule).contains("> Plain text\n\n---\n\n> Text with"));
+        assert!(with(Separator::Blank).contains("> Plain text\n\n> Text with"));
+        assert!(with(Separator::None).contains("> Plain text\n> Text with"));
+        // nothing goes before the first highlight
+        assert!(with(Separator::Rule).contains("## Chapter 1\n\n> Plain text"));
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Test Book"), "test-book");
+        assert_eq!(
+            slugify("Gödel, Escher, Bach: An Eternal Golden Braid"),
+            "gödel-escher-bach-an-eternal-golden-braid"
+        );
+        assert_eq!(slugify("Chapter 1 - The End?"), "chapter-1---the-end");
+    }
+
+    #[test]
+    fn test_markdown_toc_links_match_headings() {
+        let mut other = make_book();
+        other.title = "Another Book!".to_string();
+        let options = MarkdownOptions {
+            toc: true,
+            ..Default::default()
+        };
+
+        let md = to_markdown(&[make_book(), other], &options);
+
+        let (toc, body) = md.split_once("\n\n").unwrap();
+        let links: Vec<&str> = toc
+            .lines()
+            .map(|line| line.split("](#").nth(1).unwrap().trim_end_matches(')'))
+            .collect();
+        // both books have a "Chapter 1", so the second one gets a suffix
+        assert_eq!(
+            links,
+            vec!["test-book", "chapter-1", "another-book", "chapter-1-1"]
+        );
+
+        let mut seen = HashMap::new();
+        let anchors: Vec<String> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("# ").or_else(|| line.strip_prefix("## ")))
+            .map(|heading| unique_slug(heading, &mut seen))
+            .collect();
+        assert_eq!(anchors, links);
+    }
+
+    #[test]
+    fn test_to_toml_round_trips() {
+        let mut book = make_book();
+        book.highlights[0].text = "She said \"no\"\nand left.\\".to_string();
+
+        let rendered = to_toml(&[book.clone()]);
+        let parsed: toml::Table = rendered.parse().unwrap();
+
+        let items = parsed["highlight"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0]["text"].as_str(),
+            Some(book.highlights[0].text.as_str())
+        );
+        assert_eq!(items[0]["title"].as_str(), Some("Test Book"));
+        assert_eq!(items[0]["page"].as_integer(), Some(42));
+        assert_eq!(items[0]["datetime"].as_str(), Some("2026-01-25 10:30:00"));
+        assert!(items[0].get("note").is_none());
+        assert_eq!(items[1]["note"].as_str(), Some("a note"));
+    }
+
+    /// Checks that every tag is closed in order and that `&` only starts an
+    /// entity; enough to catch broken escaping without an XML parser.
+    fn assert_well_formed(xml: &str) {
+        let body = xml
+            .strip_prefix("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n")
+            .unwrap();
+        let mut open: Vec<&str> = Vec::new();
+        let mut rest = body;
+        while let Some(start) = rest.find('<') {
+            let end = start + rest[start..].find('>').unwrap();
+            let tag = &rest[start + 1..end];
+            assert!(!rest[..start].contains(['<', '>']));
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop(), Some(name));
+            } else {
+                open.push(tag.split(' ').next().unwrap());
+            }
+            rest = &rest[end + 1..];
+        }
+        assert!(open.is_empty());
+
+        for (i, _) in body.match_indices('&') {
+            let entity = &body[i..i + body[i..].find(';').unwrap() + 1];
+            assert!(["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"].contains(&entity));
+        }
+    }
+
+    #[test]
+    fn test_to_xml_escapes_text() {
+        let mut book = make_book();
+        book.title = "Salt & \"Pepper\"".to_string();
+        book.highlights[0].text = "Rock & roll <loud>".to_string();
+        book.highlights[1].note = Some("it's a > b".to_string());
+
+        let xml = to_xml(&[book]);
+
+        assert_well_formed(&xml);
+        assert!(
+            xml.contains("<book title=\"Salt &amp; &quot;Pepper&quot;\" author=\"Test Author\">")
+        );
+        assert!(xml.contains(
+            "<highlight page=\"42\" datetime=\"2026-01-25 10:30:00\" kind=\"highlight\">"
+        ));
+        assert!(xml.contains("<text>Rock &amp; roll &lt;loud&gt;</text>"));
+        assert!(xml.contains("<note>it&apos;s a &gt; b</note>"));
+        assert_eq!(xml.matches("<highlight ").count(), 2);
+    }
+
+    #[test]
+    fn test_to_kindle_clippings() {
+        let clippings = to_kindle_clippings(&[make_book()]);
+        let entries: Vec<&str> = clippings.split("==========\r\n").collect();
+
+        // two highlights, one of them with a note, and nothing after the last
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3], "");
+        assert_eq!(
+            entries[0],
+            "Test Book (Test Author)\r\n\
+             - Your Highlight on page 42 | Added on Sunday, January 25, 2026 10:30:00 AM\r\n\
+             \r\n\
+             Plain text\r\n"
+        );
+        assert!(entries[2]
+            .contains("- Your Note on page 100 | Added on Monday, January 26, 2026 2:00:00 PM"));
+        assert!(entries[2].ends_with("\r\n\r\na note\r\n"));
+    }
+}
@@ -0,0 +1,186 @@
+//! A small language guesser for `--detect-language`: the script for the
+//! languages that have their own, and common short words for the Latin
+//! ones. It only has to tell apart a quote in another language from the rest
+//! of the book, so anything unsure is left to the book's declared language.
+
+/// Common words of each Latin-script language, none shared between two
+/// lists so a hit counts for one language only.
+const STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "is", "that", "it", "with", "was", "for", "this", "are",
+            "you",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "les", "et", "des", "est", "une", "dans", "du", "pas", "qui", "je", "elle", "au",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "ein", "ich", "zu", "mit", "sie", "auf",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "por", "con", "una", "su", "para", "como", "pero", "yo",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "os", "as", "não", "uma", "com", "mas", "ele", "ela", "eu", "em", "dos", "foi",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "di", "che", "non", "gli", "della", "sono", "per", "ma", "lo", "io", "mi", "è",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "het", "een", "van", "en", "niet", "dat", "ik", "zijn", "op", "voor", "wat", "maar",
+        ],
+    ),
+];
+
+/// Stopword hits a language needs before the guess is trusted.
+const MIN_HITS: usize = 2;
+
+/// Guesses the language of `text` as an ISO 639-1 code, or `None` when it's
+/// too short or too mixed to tell.
+pub fn detect(text: &str) -> Option<&'static str> {
+    detect_script(text).or_else(|| detect_latin(text))
+}
+
+/// The language of a script only one language here is written in. Kana is
+/// checked before Han, since Japanese mixes the two.
+fn detect_script(text: &str) -> Option<&'static str> {
+    let has = |range: std::ops::RangeInclusive<char>| text.chars().any(|c| range.contains(&c));
+
+    if has('\u{3040}'..='\u{30FF}') {
+        Some("ja")
+    } else if has('\u{AC00}'..='\u{D7AF}') {
+        Some("ko")
+    } else if has('\u{4E00}'..='\u{9FFF}') {
+        Some("zh")
+    } else if has('\u{0400}'..='\u{04FF}') {
+        Some("ru")
+    } else if has('\u{0370}'..='\u{03FF}') {
+        Some("el")
+    } else if has('\u{0600}'..='\u{06FF}') {
+        Some("ar")
+    } else if has('\u{0590}'..='\u{05FF}') {
+        Some("he")
+    } else if has('\u{0E00}'..='\u{0E7F}') {
+        Some("th")
+    } else if has('\u{0900}'..='\u{097F}') {
+        Some("hi")
+    } else {
+        None
+    }
+}
+
+/// The Latin-script language with the most stopwords in `text`, if it has
+/// enough of them and no other language has as many.
+fn detect_latin(text: &str) -> Option<&'static str> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    let mut scores: Vec<(&str, usize)> = STOPWORDS
+        .iter()
+        .map(|(lang, stopwords)| {
+            let hits = words
+                .iter()
+                .filter(|w| stopwords.contains(&w.as_str()))
+                .count();
+            (*lang, hits)
+        })
+        .collect();
+    scores.sort_by_key(|&(_, hits)| std::cmp::Reverse(hits));
+
+    match scores.as_slice() {
+        [(lang, best), (_, second), ..] if *best >= MIN_HITS && best > second => Some(lang),
+        _ => None,
+    }
+}
+
+/// The language to tag a highlight with: the detected one, unless the book
+/// declares the same language (kept, with its region, as in `pt-BR`) or
+/// nothing could be detected.
+pub fn tag(text: &str, book_language: Option<&str>) -> Option<String> {
+    let primary = |tag: &str| tag.split(['-', '_']).next().unwrap_or(tag).to_lowercase();
+
+    match (detect(text), book_language) {
+        (Some(found), Some(book)) if primary(book) == found => Some(book.to_string()),
+        (Some(found), _) => Some(found.to_string()),
+        (None, book) => book.map(String::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_by_script() {
+        assert_eq!(detect("Война и мир"), Some("ru"));
+        assert_eq!(detect("吾輩は猫である"), Some("ja"));
+        assert_eq!(detect("道可道，非常道"), Some("zh"));
+        assert_eq!(detect("Μῆνιν ἄειδε θεὰ"), Some("el"));
+    }
+
+    #[test]
+    fn test_detect_by_stopwords() {
+        assert_eq!(
+            detect("It was the best of times, it was the worst of times"),
+            Some("en")
+        );
+        assert_eq!(
+            detect("Longtemps, je me suis couché de bonne heure et je dormais"),
+            Some("fr")
+        );
+        assert_eq!(
+            detect("Und ich weiß nicht, was soll es bedeuten, das ist so"),
+            Some("de")
+        );
+        let quijote = "En un lugar de la Mancha, de cuyo nombre no quiero acordarme y por";
+        assert_eq!(detect(quijote), Some("es"));
+        // too little to go on
+        assert_eq!(detect("A good highlight"), None);
+        assert_eq!(detect(""), None);
+    }
+
+    #[test]
+    fn test_tag_falls_back_to_book_language() {
+        assert_eq!(
+            tag("A good highlight", Some("pt-BR")),
+            Some("pt-BR".to_string())
+        );
+        assert_eq!(tag("A good highlight", None), None);
+        assert_eq!(
+            tag(
+                "It was the best of times, it was the worst of times",
+                Some("pt-BR")
+            ),
+            Some("en".to_string())
+        );
+        assert_eq!(
+            tag(
+                "It was the best of times, it was the worst of times",
+                Some("en_GB")
+            ),
+            Some("en_GB".to_string())
+        );
+    }
+}
@@ -1,4 +1,11 @@
+pub mod audit;
+pub mod color;
 pub mod config;
 pub mod db;
+pub mod export;
+pub mod lang;
 pub mod models;
 pub mod parser;
+pub mod runner;
+pub mod sdr;
+pub mod watch;
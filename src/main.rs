@@ -1,6 +1,11 @@
+use koreader_highlights::audit;
 use koreader_highlights::config::Config;
 use koreader_highlights::db;
+use koreader_highlights::export;
 use koreader_highlights::parser;
+use koreader_highlights::runner;
+use koreader_highlights::watch;
+use std::io::Write;
 use std::path::Path;
 
 fn main() {
@@ -12,70 +17,107 @@ fn main() {
         }
     };
 
-    println!("Books path: {}", config.books_path);
-    println!("Database: {}", config.database_path);
-    println!("Period: {} to {}", config.from_date, config.to_date);
-    println!();
+    if config.print_config {
+        print!("{}", config);
+        return;
+    }
 
-    let conn = match db::init_db(Path::new(&config.database_path)) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Database error: {}", e);
+    if config.init_only {
+        if let Err(e) = runner::init_only(&config, &mut std::io::stdout()) {
+            eprintln!("{}", e);
             std::process::exit(1);
         }
-    };
-
-    let files = parser::find_metadata_files(Path::new(&config.books_path));
-    println!("Found {} metadata files", files.len());
+        return;
+    }
 
-    let mut total_found = 0;
-    let mut total_inserted = 0;
+    if let Some(n) = config.recent {
+        if let Err(e) = runner::print_recent(&config, n, &mut std::io::stdout()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    for file in files {
-        let content = match std::fs::read_to_string(&file) {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Failed to read {}: {}", file.display(), e);
-                continue;
+    if config.list_authors || config.list_books {
+        // a read, so nothing is created or migrated
+        let listed = db::open_read_only(Path::new(&config.database_path)).and_then(|conn| {
+            let mut lists = Vec::new();
+            if config.list_authors {
+                lists.push(("Authors", db::distinct_authors(&conn, &config.table)?));
+            }
+            if config.list_books {
+                lists.push(("Books", db::distinct_books(&conn, &config.table)?));
+            }
+            Ok(lists)
+        });
+        match listed {
+            Ok(lists) => {
+                let both = lists.len() > 1;
+                for (i, (heading, values)) in lists.iter().enumerate() {
+                    if both {
+                        println!("{}{}:", if i > 0 { "\n" } else { "" }, heading);
+                    }
+                    values.iter().for_each(|v| println!("{}", v));
+                }
             }
-        };
-
-        let book = match parser::parse_metadata(&content, &file.to_string_lossy()) {
-            Ok(b) => b,
             Err(e) => {
-                eprintln!("Failed to parse {}: {}", file.display(), e);
-                continue;
+                eprintln!("Database error: {}", e);
+                std::process::exit(1);
             }
-        };
-
-        let filtered = parser::filter_by_date(book.highlights, config.from_date, config.to_date);
-
-        if filtered.is_empty() {
-            continue;
         }
+        return;
+    }
 
-        println!("\n{} by {}", book.title, book.author);
-
-        for h in &filtered {
-            total_found += 1;
+    // Keep stdout clean when it's carrying the export
+    let mut log: Box<dyn Write> = if config.logs_to_stderr() {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
 
-            match db::insert_highlight(&conn, h, &book.title, &book.author) {
-                Ok(true) => {
-                    total_inserted += 1;
-                    let preview: String = h.text.chars().take(60).collect();
-                    println!("  + p.{}: {}...", h.page, preview);
-                }
-                Ok(false) => {
-                    // duplicate, skip silently
-                }
-                Err(e) => {
-                    eprintln!("  Failed to insert: {}", e);
+    let mut max_id = None;
+    let result = if config.from_db {
+        runner::books_from_db(&config).map(|(books, id)| {
+            max_id = id;
+            books
+        })
+    } else {
+        runner::run(&config, &mut log).map(|stats| {
+            if let Some(path) = &config.audit_log {
+                if let Err(e) = audit::append(Path::new(path), &stats) {
+                    eprintln!("Failed to write audit log: {}", e);
                 }
             }
+            stats.books
+        })
+    };
+    let mut books = match result {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    };
+    if let Some(order) = config.book_sort {
+        parser::sort_books(&mut books, order);
     }
 
-    println!("\n---");
-    println!("Highlights found: {}", total_found);
-    println!("New highlights saved: {}", total_inserted);
+    if let Some(format) = config.format {
+        if let Err(e) = export::write(format, &books, &config) {
+            eprintln!("Failed to write export: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // for --since-id, so the caller knows where to pick up next time
+    if let Some(id) = max_id {
+        let _ = writeln!(log, "Max id: {}", id);
+    }
+
+    if config.watch {
+        if let Err(e) = watch::watch(&config, &mut log) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -1,17 +1,262 @@
 use chrono::NaiveDateTime;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct BookData {
     pub title: String,
     pub author: String,
+    pub series: Option<String>,
+    pub language: Option<String>,
+    /// The blurb from the book's metadata.
+    pub description: Option<String>,
+    /// The document type from the metadata file name, e.g. `pdf`.
+    pub doc_format: Option<String>,
+    /// The page count KOReader recorded (`doc_pages`), if it did.
+    pub doc_pages: Option<i32>,
     pub highlights: Vec<Highlight>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Highlight {
     pub chapter: Option<String>,
     pub page: i32,
     pub text: String,
     pub note: Option<String>,
     pub datetime: NaiveDateTime,
+    /// The datetime exactly as KOReader wrote it, before parsing.
+    pub raw_datetime: String,
+    /// When the highlight or its note was last edited, if it ever was.
+    pub updated: Option<NaiveDateTime>,
+    /// The reader corrected the highlighted text in KOReader.
+    pub text_edited: bool,
+    pub kind: HighlightKind,
+    /// Start and end xpointers of the selection (EPUBs only).
+    pub pos0: Option<String>,
+    pub pos1: Option<String>,
+    /// Every field of the annotation as JSON, kept with `--store-raw`.
+    pub raw_json: Option<String>,
+    /// The text was cut short by `--max-text-len` before it was stored.
+    pub truncated: bool,
+    /// Language of the text, tagged with `--detect-language`.
+    pub lang: Option<String>,
+}
+
+impl Highlight {
+    /// Number of words in the highlighted text. Words are split on Unicode
+    /// whitespace; stray punctuation such as a lone dash doesn't count.
+    pub fn word_count(&self) -> usize {
+        self.text
+            .split_whitespace()
+            .filter(|w| w.chars().any(char::is_alphanumeric))
+            .count()
+    }
+
+    /// Cuts the text down to `max_len` characters followed by an ellipsis,
+    /// marking it `truncated`. Text that already fits is left alone.
+    pub fn truncate_text(&mut self, max_len: usize) {
+        if let Some((end, _)) = self.text.char_indices().nth(max_len) {
+            self.text.truncate(end);
+            self.text.push_str("...");
+            self.truncated = true;
+        }
+    }
+
+    /// A note left on a page without highlighting any text.
+    pub fn is_note_only(&self) -> bool {
+        self.text.trim().is_empty() && self.note.as_deref().is_some_and(|n| !n.trim().is_empty())
+    }
+
+    /// The kind the entry's content suggests: text makes a highlight, a note
+    /// on its own a note, and neither a bare bookmark.
+    pub fn classify(&self) -> HighlightKind {
+        if !self.text.trim().is_empty() {
+            HighlightKind::Highlight
+        } else if self.is_note_only() {
+            HighlightKind::NoteOnly
+        } else {
+            HighlightKind::Bookmark
+        }
+    }
+
+    /// A stable fingerprint of the text and note, as 16 hex digits (64-bit
+    /// FNV-1a). Whitespace is collapsed first, like `key`.
+    pub fn content_hash(&self) -> String {
+        let text = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let note = self.note.as_deref().unwrap_or_default();
+        fnv1a(text.bytes().chain([0]).chain(note.bytes()))
+    }
+
+    /// A fingerprint of the text exactly as written, what the database
+    /// recognises a stored highlight by even after `truncate_text`.
+    pub fn text_hash(&self) -> String {
+        fnv1a(self.text.bytes())
+    }
+
+    /// How far into a `doc_pages`-page book the highlight is, from 0 to 1.
+    /// Unknown when either the page or the page count is.
+    pub fn progress(&self, doc_pages: Option<i32>) -> Option<f64> {
+        doc_pages
+            .filter(|&pages| pages > 0 && self.page > 0)
+            .map(|pages| f64::from(self.page) / f64::from(pages))
+    }
+
+    /// What makes two highlights the same passage. KOReader's start and end
+    /// positions are the most reliable, so they're used when both are known.
+    /// Otherwise it's the page and the text with whitespace collapsed, plus
+    /// the note for entries without text so different page notes differ.
+    pub fn key(&self) -> HighlightKey {
+        if let (Some(pos0), Some(pos1)) = (&self.pos0, &self.pos1) {
+            return HighlightKey::Position {
+                pos0: pos0.clone(),
+                pos1: pos1.clone(),
+            };
+        }
+
+        let text = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let note = if text.is_empty() { self.note.clone() } else { None };
+        HighlightKey::Text {
+            page: self.page,
+            text,
+            note,
+        }
+    }
+}
+
+/// 64-bit FNV-1a of `bytes`, as 16 hex digits.
+fn fnv1a(bytes: impl Iterator<Item = u8>) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Identity of a highlight, see `Highlight::key`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum HighlightKey {
+    Position { pos0: String, pos1: String },
+    Text { page: i32, text: String, note: Option<String> },
+}
+
+/// A highlight as read back from the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredHighlight {
+    pub id: i64,
+    pub book_title: String,
+    pub book_author: String,
+    pub book_doc_format: Option<String>,
+    pub highlight: Highlight,
+}
+
+/// What an entry is: highlighted text, a note on a page without any text,
+/// or a page bookmark.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HighlightKind {
+    #[default]
+    Highlight,
+    NoteOnly,
+    Bookmark,
+}
+
+impl HighlightKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HighlightKind::Highlight => "highlight",
+            HighlightKind::NoteOnly => "note",
+            HighlightKind::Bookmark => "bookmark",
+        }
+    }
+
+    /// Inverse of `as_str`; unknown names are treated as plain highlights.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "note" => HighlightKind::NoteOnly,
+            "bookmark" => HighlightKind::Bookmark,
+            _ => HighlightKind::Highlight,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_text(text: &str) -> Highlight {
+        Highlight {
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let note = |text: &str, note: Option<&str>| Highlight {
+            note: note.map(String::from),
+            ..with_text(text)
+        };
+
+        assert_eq!(note("Some text", None).classify(), HighlightKind::Highlight);
+        assert_eq!(note("Some text", Some("and a note")).classify(), HighlightKind::Highlight);
+        assert_eq!(note("", Some("just a note")).classify(), HighlightKind::NoteOnly);
+        assert!(note("  ", Some("just a note")).is_note_only());
+        assert_eq!(note("", None).classify(), HighlightKind::Bookmark);
+        assert_eq!(note("", Some(" ")).classify(), HighlightKind::Bookmark);
+    }
+
+    #[test]
+    fn test_truncate_text_on_char_boundary() {
+        let mut long = with_text("Ça va très bien");
+        long.truncate_text(9);
+        assert_eq!(long.text, "Ça va trè...");
+        assert!(long.truncated);
+
+        let mut short = with_text("Ça va");
+        short.truncate_text(5);
+        assert_eq!(short.text, "Ça va");
+        assert!(!short.truncated);
+    }
+
+    #[test]
+    fn test_word_count_multiple_spaces() {
+        assert_eq!(with_text("  one   two\tthree\n four ").word_count(), 4);
+    }
+
+    #[test]
+    fn test_word_count_punctuation() {
+        assert_eq!(with_text("Hello, world! — it's \"quoted\".").word_count(), 4);
+        assert_eq!(with_text("...").word_count(), 0);
+        assert_eq!(with_text("").word_count(), 0);
+    }
+
+    #[test]
+    fn test_key_ignores_whitespace_only() {
+        let a = Highlight {
+            page: 7,
+            ..with_text("A line  broken\nin two")
+        };
+        let b = Highlight {
+            page: 7,
+            ..with_text(" A line broken in two ")
+        };
+        let other_page = Highlight {
+            page: 8,
+            ..with_text("A line broken in two")
+        };
+        assert_eq!(a.key(), b.key());
+        assert_ne!(a.key(), other_page.key());
+        assert_ne!(a.key(), with_text("Another line").key());
+    }
+
+    #[test]
+    fn test_key_prefers_positions() {
+        let at = |pos0: &str, text: &str| Highlight {
+            pos0: Some(pos0.to_string()),
+            pos1: Some("/body/p[2]/text().9".to_string()),
+            ..with_text(text)
+        };
+        // an edited text is still the same selection
+        let start = "/body/p[2]/text().0";
+        assert_eq!(at(start, "before").key(), at(start, "after").key());
+        assert_ne!(at(start, "same").key(), at("/body/p[1]/text().0", "same").key());
+    }
 }
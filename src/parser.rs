@@ -1,7 +1,13 @@
-use crate::models::{BookData, Highlight};
-use chrono::{NaiveDate, NaiveDateTime};
-use full_moon::ast::{Expression, Field, LastStmt};
-use full_moon::tokenizer::TokenType;
+use crate::lang;
+use crate::models::{BookData, Highlight, HighlightKind};
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use clap::ValueEnum;
+use full_moon::ast::{AstError, Expression, Field, LastStmt};
+use full_moon::tokenizer::{Symbol, TokenType, TokenizerErrorType};
+use flate2::read::GzDecoder;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
@@ -9,6 +15,12 @@ use walkdir::WalkDir;
 pub enum ParseError {
     InvalidLua(String),
     MissingTitle(String),
+    /// The file doesn't return a table the way KOReader writes them, so it's
+    /// likely not KOReader's at all (binary, encrypted, or another program's).
+    NotKoreaderMetadata(String),
+    /// The file stops partway, typically because KOReader was interrupted
+    /// while saving it.
+    TruncatedFile(String),
 }
 
 impl std::fmt::Display for ParseError {
@@ -20,43 +32,190 @@ impl std::fmt::Display for ParseError {
             ParseError::MissingTitle(file) => {
                 write!(f, "Book has no title in doc_props: {}", file)
             }
+            ParseError::NotKoreaderMetadata(file) => {
+                write!(f, "Not a KOReader metadata file (expected `return {{ ... }}`): {}", file)
+            }
+            ParseError::TruncatedFile(file) => write!(
+                f,
+                "Metadata file ends partway through, likely an interrupted save \
+                 (opening the book in KOReader again usually rewrites it): {}",
+                file
+            ),
         }
     }
 }
 
 impl std::error::Error for ParseError {}
 
+/// Why `parse_metadata_file` couldn't turn a file into a book.
+#[derive(Debug, PartialEq)]
+pub enum ProcessError {
+    Read(PathBuf, String),
+    Parse(PathBuf, ParseError),
+}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessError::Read(path, e) => write!(f, "Failed to read {}: {}", path.display(), e),
+            ProcessError::Parse(path, e) => {
+                write!(f, "Failed to parse {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+/// An annotation `parse_metadata_checked` had to leave out, with its index in
+/// the `["annotations"]` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedAnnotation {
+    pub index: usize,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    MissingDatetime,
+    InvalidDatetime(String),
+    InvalidPageno,
+    MissingText,
+}
+
+impl std::fmt::Display for SkippedAnnotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "annotation [{}]: ", self.index)?;
+        match &self.reason {
+            SkipReason::MissingDatetime => write!(f, "no datetime"),
+            SkipReason::InvalidDatetime(raw) => write!(f, "unreadable datetime '{}'", raw),
+            SkipReason::InvalidPageno => write!(f, "pageno is not a number"),
+            SkipReason::MissingText => write!(f, "neither text nor note"),
+        }
+    }
+}
+
+/// Knobs that change what `parse_metadata_with` extracts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParseOptions {
+    /// Also import `["bookmarks"]` entries that carry a note but no text.
+    pub include_bookmarks: bool,
+    /// Tidy chapter names with `normalize_chapter`.
+    pub clean_chapters: bool,
+    /// Join selections KOReader split in two, with `merge_adjacent`.
+    pub merge_adjacent: bool,
+    /// Fill in missing chapters from the position, with `chapter_from_pos`.
+    pub infer_chapters: bool,
+    /// How repeated annotations are recognised.
+    pub dedup_by: DedupStrategy,
+    /// Treat a `pageno` that isn't a number as a reason to skip the
+    /// annotation, instead of reading it as page 0.
+    pub strict: bool,
+    /// Name untitled books after their `.sdr` directory, see `title_from_path`.
+    pub title_from_path: bool,
+    /// Keep each annotation's fields as JSON, see `extract_raw_annotation`.
+    pub store_raw: bool,
+    /// How the author name is stored, see `format_author`.
+    pub author_format: AuthorFormat,
+    /// Turn Windows and old Mac line breaks in text and notes into `\n`,
+    /// see `normalize_newlines`. Off in `Default`, so `parse_metadata` keeps
+    /// the text as written; the CLI turns it on unless
+    /// `--no-normalize-newlines` is given.
+    pub normalize_newlines: bool,
+    /// Tag each highlight with the language `lang::tag` finds in its text,
+    /// or the book's declared `language` when it can't tell.
+    pub detect_language: bool,
+}
+
+/// How author names are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AuthorFormat {
+    /// Exactly as in the book's metadata
+    #[default]
+    AsIs,
+    /// "Surname, Given", e.g. "Le Guin, Ursula K."
+    LastFirst,
+}
+
+/// What makes two annotations of one book the same highlight.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DedupStrategy {
+    /// Same position, or page and text, see `Highlight::key`
+    #[default]
+    Default,
+    /// Same time and start of text, for copies made by KOReader's sync
+    Datetime,
+}
+
 pub fn parse_metadata(content: &str, source_file: &str) -> Result<BookData, ParseError> {
-    let ast = full_moon::parse(content)
-        .map_err(|e| ParseError::InvalidLua(format!("{}: {}", source_file, e)))?;
+    parse_metadata_with(content, source_file, &ParseOptions::default())
+}
+
+pub fn parse_metadata_with(
+    content: &str,
+    source_file: &str,
+    options: &ParseOptions,
+) -> Result<BookData, ParseError> {
+    parse_metadata_checked(content, source_file, options).map(|(book, _)| book)
+}
+
+/// Like `parse_metadata_with`, also listing the annotations that couldn't be
+/// used, for `--strict`.
+pub fn parse_metadata_checked(
+    content: &str,
+    source_file: &str,
+    options: &ParseOptions,
+) -> Result<(BookData, Vec<SkippedAnnotation>), ParseError> {
+    // some editors save the file with a byte order mark, which isn't valid Lua
+    let content = content.strip_prefix('\u{FEFF}').unwrap_or(content);
+    let ast = full_moon::parse(content).map_err(|e| {
+        if !starts_with_return(content) {
+            ParseError::NotKoreaderMetadata(source_file.to_string())
+        } else if is_truncated(&e, content) {
+            ParseError::TruncatedFile(source_file.to_string())
+        } else {
+            ParseError::InvalidLua(format!("{}: {}", source_file, e))
+        }
+    })?;
+    if !returns_table(&ast) {
+        return Err(ParseError::NotKoreaderMetadata(source_file.to_string()));
+    }
 
-    let mut title: Option<String> = None;
-    let mut author: Option<String> = None;
+    let mut props = DocProps::default();
     let mut highlights: Vec<Highlight> = Vec::new();
+    let mut bookmarks: Vec<Highlight> = Vec::new();
+    let mut skipped: Vec<SkippedAnnotation> = Vec::new();
+    let mut found_annotations = false;
+    let mut doc_settings = None;
+    let mut doc_pages = None;
 
     // Find the return statement (it's a LastStmt, not a regular Stmt)
-    if let Some(last_stmt) = ast.nodes().last_stmt() {
-        if let LastStmt::Return(return_stmt) = last_stmt {
-            for expr in return_stmt.returns().iter() {
-                if let Expression::TableConstructor(table) = expr {
-                    // Parse the main table
-                    for field in table.fields() {
-                        if let Field::ExpressionKey { key, value, .. } = field {
-                            let key_name = extract_string_from_expr(key);
-
-                            match key_name.as_deref() {
-                                Some("doc_props") => {
-                                    if let Expression::TableConstructor(props) = value {
-                                        (title, author) = extract_doc_props(props);
-                                    }
-                                }
-                                Some("annotations") => {
-                                    if let Expression::TableConstructor(annots) = value {
-                                        highlights = extract_annotations(annots);
-                                    }
-                                }
-                                _ => {}
+    if let Some(LastStmt::Return(return_stmt)) = ast.nodes().last_stmt() {
+        for expr in return_stmt.returns().iter() {
+            if let Expression::TableConstructor(table) = expr {
+                // Parse the main table
+                for field in table.fields() {
+                    if let Some((key_name, value)) = field_key_value(field) {
+                        match (key_name.as_str(), value) {
+                            ("doc_props", Expression::TableConstructor(table)) => {
+                                props = extract_doc_props(table);
+                            }
+                            ("annotations", Expression::TableConstructor(annots)) => {
+                                highlights = extract_annotations(annots, options, &mut skipped);
+                                found_annotations = true;
+                            }
+                            ("bookmarks", Expression::TableConstructor(marks))
+                                if options.include_bookmarks =>
+                            {
+                                bookmarks = extract_bookmarks(marks, options.store_raw);
+                            }
+                            ("doc_pages", value) => {
+                                doc_pages = extract_number_from_expr(value);
                             }
+                            ("doc_settings", Expression::TableConstructor(settings)) => {
+                                doc_settings = Some(settings);
+                            }
+                            _ => {}
                         }
                     }
                 }
@@ -64,88 +223,686 @@ pub fn parse_metadata(content: &str, source_file: &str) -> Result<BookData, Pars
         }
     }
 
-    let title = title.ok_or_else(|| ParseError::MissingTitle(source_file.to_string()))?;
+    // Some versions keep everything one level down, under ["doc_settings"].
+    // The top level wins when both are present.
+    if let Some(settings) = doc_settings {
+        for field in settings.fields() {
+            if let Some((key_name, value)) = field_key_value(field) {
+                match (key_name.as_str(), value) {
+                    ("doc_props", Expression::TableConstructor(table)) if props.title.is_none() => {
+                        props = extract_doc_props(table);
+                    }
+                    ("annotations", Expression::TableConstructor(annots)) if !found_annotations => {
+                        highlights = extract_annotations(annots, options, &mut skipped);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let title = props
+        .title
+        .or_else(|| options.title_from_path.then(|| title_from_path(source_file)).flatten())
+        .ok_or_else(|| ParseError::MissingTitle(source_file.to_string()))?;
+    highlights.extend(bookmarks);
+
+    if options.infer_chapters {
+        for h in highlights.iter_mut().filter(|h| h.chapter.is_none()) {
+            h.chapter = h.pos0.as_deref().and_then(chapter_from_pos);
+        }
+    }
+
+    if options.clean_chapters {
+        for h in &mut highlights {
+            h.chapter = h.chapter.as_deref().map(normalize_chapter);
+        }
+    }
+
+    if options.normalize_newlines {
+        for h in &mut highlights {
+            h.text = normalize_newlines(&h.text);
+            h.note = h.note.as_deref().map(normalize_newlines);
+        }
+    }
+
+    if options.merge_adjacent {
+        highlights = merge_adjacent(highlights);
+    }
+
+    if options.detect_language {
+        for h in &mut highlights {
+            // a page note has no text, so its language is the note's
+            let text = match h.text.as_str() {
+                "" => h.note.as_deref().unwrap_or_default(),
+                text => text,
+            };
+            h.lang = lang::tag(text, props.language.as_deref());
+        }
+    }
 
-    Ok(BookData {
+    let book = BookData {
         title,
-        author: author.unwrap_or_else(|| "Unknown".to_string()),
+        author: props
+            .author
+            .map(|author| format_author(&author, options.author_format))
+            .unwrap_or_else(|| "Unknown".to_string()),
+        series: props.series,
+        language: props.language,
+        description: props.description,
+        doc_format: doc_format(Path::new(source_file)),
+        doc_pages,
         highlights,
-    })
+    };
+    Ok((book, skipped))
+}
+
+/// Whether the first token, after whitespace and comments, is `return`.
+/// KOReader's files always start like that, which tells a broken metadata file
+/// apart from something else that happens to share the name. Only looked at
+/// when the file doesn't parse, so it can't reject a valid one.
+fn starts_with_return(content: &str) -> bool {
+    let mut rest = content.trim_start();
+    while let Some(comment) = rest.strip_prefix("--") {
+        rest = match long_bracket_level(comment) {
+            // --[[ ... ]] or --[==[ ... ]==]
+            Some(level) => {
+                let close = format!("]{}]", "=".repeat(level));
+                match comment.find(&close) {
+                    Some(end) => &comment[end + close.len()..],
+                    None => return false,
+                }
+            }
+            None => comment.split_once('\n').map_or("", |(_, next)| next),
+        }
+        .trim_start();
+    }
+
+    rest.strip_prefix("return")
+        .is_some_and(|after| !after.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+}
+
+/// The number of `=` in a Lua long bracket opening `s`, like `[[` or `[==[`.
+fn long_bracket_level(s: &str) -> Option<usize> {
+    let inner = s.strip_prefix('[')?;
+    let level = inner.len() - inner.trim_start_matches('=').len();
+    inner[level..].starts_with('[').then_some(level)
+}
+
+/// Whether full_moon gave up only because `content` stops: the parser ran
+/// out of tokens (an unclosed table), or a quoted string runs to the end of
+/// the file.
+fn is_truncated(error: &full_moon::Error, content: &str) -> bool {
+    match error {
+        full_moon::Error::AstError(AstError::UnexpectedToken { token, .. }) => {
+            matches!(token.token_type(), TokenType::Eof)
+        }
+        full_moon::Error::TokenizerError(e) => {
+            let rest = content.get(e.position().bytes()..).unwrap_or_default();
+            matches!(e.error(), TokenizerErrorType::UnclosedString)
+                && rest.starts_with(['"', '\''])
+                && !rest.contains('\n')
+        }
+        _ => false,
+    }
+}
+
+fn returns_table(ast: &full_moon::ast::Ast) -> bool {
+    match ast.nodes().last_stmt() {
+        Some(LastStmt::Return(ret)) => {
+            ret.returns().iter().any(|e| matches!(e, Expression::TableConstructor(_)))
+        }
+        _ => false,
+    }
+}
+
+/// The book name KOReader encodes in the sidecar directory, so
+/// `Books/My Book.sdr/metadata.epub.lua` gives `"My Book"`.
+pub fn title_from_path(source_file: &str) -> Option<String> {
+    let dir = Path::new(source_file).parent()?.file_name()?.to_str()?;
+    let title = dir.strip_suffix(".sdr")?.trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+#[derive(Default)]
+struct DocProps {
+    title: Option<String>,
+    author: Option<String>,
+    series: Option<String>,
+    language: Option<String>,
+    description: Option<String>,
 }
 
-fn extract_doc_props(table: &full_moon::ast::TableConstructor) -> (Option<String>, Option<String>) {
-    let mut title = None;
-    let mut author = None;
+/// Reads the book-level fields. Anything that isn't a plain string (some
+/// files nest tables in here) is skipped rather than treated as an error.
+/// Hand-edited files sometimes say `author`; KOReader's `authors` wins.
+fn extract_doc_props(table: &full_moon::ast::TableConstructor) -> DocProps {
+    let mut props = DocProps::default();
+    let mut singular_author = None;
 
     for field in table.fields() {
-        if let Field::ExpressionKey { key, value, .. } = field {
-            let key_name = extract_string_from_expr(key);
+        if let Some((key_name, value)) = field_key_value(field) {
             let val = extract_string_from_expr(value);
 
-            match key_name.as_deref() {
-                Some("title") => title = val,
-                Some("authors") => author = val,
+            match key_name.as_str() {
+                "title" => props.title = val,
+                "authors" => props.author = val,
+                "author" => singular_author = val,
+                "series" => props.series = val,
+                "language" => props.language = val,
+                "description" => props.description = val,
                 _ => {}
             }
         }
     }
 
-    (title, author)
+    props.author = props.author.or(singular_author);
+    props
 }
 
-fn extract_annotations(table: &full_moon::ast::TableConstructor) -> Vec<Highlight> {
-    let mut highlights = Vec::new();
+pub(crate) fn extract_annotations(
+    table: &full_moon::ast::TableConstructor,
+    options: &ParseOptions,
+    skipped: &mut Vec<SkippedAnnotation>,
+) -> Vec<Highlight> {
+    let mut indexed = Vec::new();
+
+    for (position, field) in table.fields().iter().enumerate() {
+        // Each annotation is [N] = { ... }, or just { ... } in hand-edited files
+        let (key, annot) = match field {
+            Field::ExpressionKey {
+                key,
+                value: Expression::TableConstructor(annot),
+                ..
+            } => (Some(key), annot),
+            Field::NoKey(Expression::TableConstructor(annot)) => (None, annot),
+            _ => continue,
+        };
+        let index = key
+            .and_then(extract_number_from_expr)
+            .and_then(|n| usize::try_from(n).ok())
+            .unwrap_or(position + 1);
 
-    for field in table.fields() {
-        // Each annotation is [N] = { ... }
-        if let Field::ExpressionKey { value, .. } = field {
-            if let Expression::TableConstructor(annot) = value {
-                if let Some(h) = extract_single_annotation(annot) {
-                    highlights.push(h);
+        match extract_single_annotation(annot, options.strict) {
+            Ok(mut h) => {
+                if options.store_raw {
+                    h.raw_json = Some(extract_raw_annotation(annot).to_string());
                 }
+                indexed.push((index, h));
             }
+            Err(reason) => skipped.push(SkippedAnnotation { index, reason }),
         }
     }
 
+    // KOReader's order is the [N] index, which may have gaps after deletions
+    // and needn't match the order in the file
+    indexed.sort_by_key(|(index, _)| *index);
+    let highlights = indexed.into_iter().map(|(_, h)| h).collect();
+
+    match options.dedup_by {
+        DedupStrategy::Default => dedupe_highlights(highlights),
+        DedupStrategy::Datetime => dedupe_by_datetime(highlights),
+    }
+}
+
+/// Drops repeated highlights of the same passage (same `Highlight::key`),
+/// keeping the first occurrence. Entries without text are told apart by their
+/// note, so different page notes on one page all survive.
+pub fn dedupe_highlights(highlights: Vec<Highlight>) -> Vec<Highlight> {
+    let mut seen = HashSet::new();
+
+    highlights.into_iter().filter(|h| seen.insert(h.key())).collect()
+}
+
+/// Drops annotations made at the same second whose text starts the same,
+/// keeping the first. KOReader's sync can leave such copies behind with
+/// slightly different whitespace or positions, which `dedupe_highlights`
+/// would keep apart. Only the first 40 characters of the text (with runs of
+/// whitespace collapsed) are compared, and of the note when there is no text,
+/// so two bookmarks made in the same second stay apart. Called per book, so
+/// the title is part of the key implicitly.
+pub fn dedupe_by_datetime(highlights: Vec<Highlight>) -> Vec<Highlight> {
+    let start = |s: &str| -> String {
+        s.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .chars()
+            .take(40)
+            .collect()
+    };
+    let mut seen = HashSet::new();
+
     highlights
+        .into_iter()
+        .filter(|h| {
+            let text = start(&h.text);
+            let note = if text.is_empty() {
+                start(h.note.as_deref().unwrap_or(""))
+            } else {
+                String::new()
+            };
+            seen.insert((h.datetime, text, note))
+        })
+        .collect()
 }
 
-fn extract_single_annotation(table: &full_moon::ast::TableConstructor) -> Option<Highlight> {
+/// Sorts highlights by position and joins neighbours where one selection ends
+/// exactly where the next starts, on the same page and chapter. KOReader
+/// sometimes stores a single selection as two annotations like that.
+pub fn merge_adjacent(mut highlights: Vec<Highlight>) -> Vec<Highlight> {
+    highlights.sort_by(|a, b| {
+        a.page
+            .cmp(&b.page)
+            .then_with(|| position_key(a.pos0.as_deref()).cmp(&position_key(b.pos0.as_deref())))
+    });
+
+    let mut merged: Vec<Highlight> = Vec::with_capacity(highlights.len());
+    for h in highlights {
+        match merged.last_mut() {
+            Some(prev) if is_contiguous(prev, &h) => {
+                prev.text = format!("{} {}", prev.text.trim_end(), h.text.trim_start());
+                prev.note = match (prev.note.take(), h.note) {
+                    (Some(a), Some(b)) => Some(format!("{}\n{}", a, b)),
+                    (a, b) => a.or(b),
+                };
+                if h.datetime < prev.datetime {
+                    prev.datetime = h.datetime;
+                    prev.raw_datetime = h.raw_datetime;
+                }
+                prev.pos1 = h.pos1;
+            }
+            _ => merged.push(h),
+        }
+    }
+
+    merged
+}
+
+fn is_contiguous(prev: &Highlight, next: &Highlight) -> bool {
+    prev.kind == HighlightKind::Highlight
+        && next.kind == HighlightKind::Highlight
+        && prev.page == next.page
+        && prev.chapter == next.chapter
+        && prev.pos1.is_some()
+        && prev.pos1 == next.pos0
+}
+
+/// Guesses a chapter label from an EPUB xpointer such as
+/// `/body/DocFragment[12]/body/p[3]/text().5`. EPUBs are usually split into
+/// one file per chapter, so the fragment index is a fair stand-in, giving
+/// `"Section 12"`. Returns `None` for positions without a fragment, like
+/// the plain page numbers of PDFs.
+pub fn chapter_from_pos(pos0: &str) -> Option<String> {
+    let start = pos0.find("DocFragment[")? + "DocFragment[".len();
+    let len = pos0[start..].find(']')?;
+    let index: u32 = pos0[start..start + len].parse().ok()?;
+    Some(format!("Section {}", index))
+}
+
+/// Splits an xpointer into text and number runs so `p[10]` sorts after
+/// `p[9]`. Highlights without a position sort last.
+fn position_key(pos: Option<&str>) -> (bool, Vec<(String, u64)>) {
+    let Some(pos) = pos else {
+        return (true, Vec::new());
+    };
+
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut digits = String::new();
+    for c in pos.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+        } else {
+            if !digits.is_empty() {
+                parts.push((std::mem::take(&mut text), digits.parse().unwrap_or(u64::MAX)));
+                digits.clear();
+            }
+            text.push(c);
+        }
+    }
+    parts.push((text, digits.parse().unwrap_or(0)));
+
+    (false, parts)
+}
+
+fn extract_bookmarks(table: &full_moon::ast::TableConstructor, store_raw: bool) -> Vec<Highlight> {
+    let mut bookmarks = Vec::new();
+
+    for field in table.fields() {
+        if let Field::ExpressionKey {
+            value: Expression::TableConstructor(mark),
+            ..
+        } = field
+        {
+            let Ok(fields) = extract_annotation_fields(mark) else {
+                continue;
+            };
+
+            // Only page notes: plain bookmarks carry nothing worth storing,
+            // and ones with text are already covered by annotations.
+            let has_note = fields.note.as_deref().is_some_and(|n| !n.trim().is_empty());
+            let has_text = fields.text.as_deref().is_some_and(|t| !t.trim().is_empty());
+            if !has_note || has_text {
+                continue;
+            }
+
+            bookmarks.push(Highlight {
+                chapter: fields.chapter,
+                page: fields.page.unwrap_or(0),
+                text: String::new(),
+                note: fields.note,
+                datetime: fields.datetime,
+                raw_datetime: fields.raw_datetime,
+                updated: fields.updated,
+                text_edited: fields.text_edited,
+                kind: HighlightKind::Bookmark,
+                pos0: fields.pos0,
+                pos1: fields.pos1,
+                raw_json: store_raw.then(|| extract_raw_annotation(mark).to_string()),
+                truncated: false,
+                lang: None,
+            });
+        }
+    }
+
+    bookmarks
+}
+
+struct AnnotationFields {
+    chapter: Option<String>,
+    page: Option<i32>,
+    /// A `pageno` was there but wasn't a number.
+    page_invalid: bool,
+    text: Option<String>,
+    note: Option<String>,
+    datetime: NaiveDateTime,
+    raw_datetime: String,
+    updated: Option<NaiveDateTime>,
+    text_edited: bool,
+    pos0: Option<String>,
+    pos1: Option<String>,
+}
+
+/// Pulls the fields shared by annotations and bookmarks. Entries without a
+/// parseable datetime are dropped, as they can't be date-filtered.
+fn extract_annotation_fields(
+    table: &full_moon::ast::TableConstructor,
+) -> Result<AnnotationFields, SkipReason> {
     let mut chapter: Option<String> = None;
     let mut page: Option<i32> = None;
+    let mut page_invalid = false;
+    let mut page_fallback: Option<i32> = None;
     let mut text: Option<String> = None;
     let mut note: Option<String> = None;
     let mut datetime: Option<String> = None;
+    let mut epoch: Option<NaiveDateTime> = None;
+    let mut updated: Option<String> = None;
+    let mut text_edited = false;
+    let mut pos0: Option<String> = None;
+    let mut pos1: Option<String> = None;
 
     for field in table.fields() {
-        if let Field::ExpressionKey { key, value, .. } = field {
-            let key_name = extract_string_from_expr(key);
-
-            match key_name.as_deref() {
-                Some("chapter") => chapter = extract_string_from_expr(value),
-                Some("pageno") => page = extract_number_from_expr(value),
-                Some("text") => text = extract_string_from_expr(value),
-                Some("note") => note = extract_string_from_expr(value),
-                Some("datetime") => datetime = extract_string_from_expr(value),
+        if let Some((key_name, value)) = field_key_value(field) {
+            match key_name.as_str() {
+                "chapter" => chapter = extract_string_from_expr(value),
+                "pageno" => {
+                    page = extract_number_from_expr(value);
+                    page_invalid = page.is_none();
+                }
+                // older files; newer ones put the xpointer here, which isn't a number
+                "page" => page_fallback = extract_number_from_expr(value),
+                "text" => text = extract_string_from_expr(value),
+                "note" => note = extract_string_from_expr(value),
+                "datetime" => match extract_epoch_from_expr(value) {
+                    Some((seconds, parsed)) => {
+                        datetime = Some(seconds);
+                        epoch = Some(parsed);
+                    }
+                    None => datetime = extract_string_from_expr(value),
+                },
+                "datetime_updated" => updated = extract_string_from_expr(value),
+                "text_edited" => text_edited = extract_bool_from_expr(value).unwrap_or(false),
+                // PDFs store positions as tables; only EPUB xpointers are kept
+                "pos0" => pos0 = extract_string_from_expr(value),
+                "pos1" => pos1 = extract_string_from_expr(value),
                 _ => {}
             }
         }
     }
 
-    // text is required
-    let text = text?;
-    let page = page.unwrap_or(0);
-    let datetime = datetime.and_then(|s| parse_datetime(&s))?;
+    let raw_datetime = datetime.ok_or(SkipReason::MissingDatetime)?;
+    let datetime = epoch
+        .or_else(|| parse_datetime(&raw_datetime))
+        .ok_or_else(|| SkipReason::InvalidDatetime(raw_datetime.clone()))?;
 
-    Some(Highlight {
+    Ok(AnnotationFields {
         chapter,
-        page,
+        page: page.or(page_fallback),
+        page_invalid,
         text,
         note,
         datetime,
+        raw_datetime,
+        // unlike the creation time, a bad edit time isn't worth dropping the entry
+        updated: updated.as_deref().and_then(parse_datetime),
+        text_edited,
+        pos0,
+        pos1,
+    })
+}
+
+fn extract_single_annotation(
+    table: &full_moon::ast::TableConstructor,
+    strict: bool,
+) -> Result<Highlight, SkipReason> {
+    let fields = extract_annotation_fields(table)?;
+    if strict && fields.page_invalid {
+        return Err(SkipReason::InvalidPageno);
+    }
+
+    let text = fields.text.unwrap_or_default();
+    let page = fields.page.unwrap_or(0);
+
+    // Blank or missing text is only worth keeping when a note gives it
+    // meaning, in which case it's really a page note rather than a highlight
+    let (text, kind) = if text.trim().is_empty() {
+        fields
+            .note
+            .as_deref()
+            .filter(|n| !n.trim().is_empty())
+            .ok_or(SkipReason::MissingText)?;
+        (String::new(), HighlightKind::NoteOnly)
+    } else {
+        (text, HighlightKind::Highlight)
+    };
+
+    Ok(Highlight {
+        chapter: fields.chapter,
+        page,
+        text,
+        note: fields.note,
+        datetime: fields.datetime,
+        raw_datetime: fields.raw_datetime,
+        updated: fields.updated,
+        text_edited: fields.text_edited,
+        kind,
+        pos0: fields.pos0,
+        pos1: fields.pos1,
+        raw_json: None,
+        truncated: false,
+        lang: None,
     })
 }
 
+/// Rewrites `\r\n` and lone `\r` line breaks as `\n`. String literals are
+/// kept as written, so this covers both the escaped form KOReader usually
+/// writes and raw line breaks inside the file; other escapes are untouched.
+pub fn normalize_newlines(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('r') => {
+                    if chars.as_str().starts_with("\\n") {
+                        chars.nth(1);
+                    }
+                    out.push_str("\\n");
+                }
+                Some(next) => {
+                    out.push('\\');
+                    out.push(next);
+                }
+                None => out.push('\\'),
+            },
+            '\r' => {
+                if chars.as_str().starts_with('\n') {
+                    chars.next();
+                }
+                out.push('\n');
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Trims a chapter name and strips the list markers and dotted section
+/// numbers KOReader copies from some tables of contents, so
+/// `"  1.2.3 Actual Title"` becomes `"Actual Title"`. A bare number is part of
+/// the title (`"1984 Revisited"`, `"2001: A Space Odyssey"`) and is kept.
+pub fn normalize_chapter(chapter: &str) -> String {
+    let trimmed = chapter
+        .trim()
+        .trim_start_matches(['•', '·', '-', '–', '—', '*'])
+        .trim_start();
+
+    // a run of digits and dots, optionally closed by `)`
+    let prefix_len = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let prefix = &trimmed[..prefix_len];
+    let closed = trimmed[prefix_len..].starts_with(')');
+    let rest = if closed {
+        &trimmed[prefix_len + 1..]
+    } else {
+        &trimmed[prefix_len..]
+    };
+
+    // `1.2.3`, `3.` and `12)` are section numbers; a bare `1984` is not
+    let is_section_number =
+        prefix.starts_with(|c: char| c.is_ascii_digit()) && (prefix.contains('.') || closed);
+    let followed_by_space = rest.starts_with(char::is_whitespace);
+
+    if is_section_number && followed_by_space && !rest.trim().is_empty() {
+        rest.trim().to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Name particles that belong to the surname, as in "Ludwig van Beethoven",
+/// "Oscar De La Hoya" or "Ursula K. Le Guin".
+const SURNAME_PARTICLES: [&str; 13] =
+    ["da", "de", "del", "della", "der", "di", "do", "dos", "du", "la", "le", "van", "von"];
+
+/// Rewrites "Given Surname" as "Surname, Given" for `AuthorFormat::LastFirst`.
+/// Single names, names that already have a comma and lists of several
+/// authors (`&`, `;`, ` and ` or one per line) are left alone.
+pub fn format_author(author: &str, format: AuthorFormat) -> String {
+    let author = author.trim();
+    // the parser keeps escapes as written, so KOReader's one-author-per-line
+    // list usually arrives with `\n` rather than a line break
+    let is_list = author.contains([',', '&', ';', '\n'])
+        || author.contains("\\n")
+        || author.contains(" and ");
+    if format == AuthorFormat::AsIs || is_list {
+        return author.to_string();
+    }
+
+    let words: Vec<&str> = author.split_whitespace().collect();
+    let Some((&last, given)) = words.split_last().filter(|(_, given)| !given.is_empty()) else {
+        return author.to_string();
+    };
+
+    // pull the particles in front of the last word into the surname
+    let start = given
+        .iter()
+        .rposition(|w| !SURNAME_PARTICLES.contains(&w.to_lowercase().as_str()))
+        .map_or(0, |i| i + 1);
+    if start == 0 {
+        return author.to_string();
+    }
+    let surname: Vec<&str> = given[start..].iter().copied().chain([last]).collect();
+    format!("{}, {}", surname.join(" "), given[..start].join(" "))
+}
+
+/// Splits a table field into its key name and value, accepting both the
+/// `["key"] = value` form KOReader writes and the bare `key = value` form.
+pub(crate) fn field_key_value(field: &Field) -> Option<(String, &Expression)> {
+    match field {
+        Field::ExpressionKey { key, value, .. } => Some((extract_string_from_expr(key)?, value)),
+        Field::NameKey { key, value, .. } => match key.token().token_type() {
+            TokenType::Identifier { identifier } => Some((identifier.to_string(), value)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Converts an annotation table to JSON as is, so fields this tool doesn't
+/// know about yet aren't lost. Keys become strings (`[3]` gives `"3"`, an
+/// entry without a key its position), nested tables become objects and
+/// anything that isn't a plain value becomes `null`.
+pub fn extract_raw_annotation(table: &full_moon::ast::TableConstructor) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+
+    for (position, field) in table.fields().iter().enumerate() {
+        let (key, value) = match field {
+            Field::ExpressionKey { key, value, .. } => {
+                let key = extract_string_from_expr(key)
+                    .or_else(|| number_text(key))
+                    .unwrap_or_else(|| (position + 1).to_string());
+                (key, value)
+            }
+            Field::NameKey { key, value, .. } => (key.token().to_string(), value),
+            Field::NoKey(value) => ((position + 1).to_string(), value),
+            _ => continue,
+        };
+        object.insert(key, raw_value(value));
+    }
+
+    serde_json::Value::Object(object)
+}
+
+fn raw_value(expr: &Expression) -> serde_json::Value {
+    use serde_json::Value;
+
+    if let Expression::TableConstructor(table) = expr {
+        return extract_raw_annotation(table);
+    }
+    if let Some(s) = extract_string_from_expr(expr) {
+        return Value::String(s);
+    }
+    if let Some(b) = extract_bool_from_expr(expr) {
+        return Value::Bool(b);
+    }
+    number_text(expr)
+        .and_then(|text| serde_json::from_str::<serde_json::Number>(&text).ok())
+        .map_or(Value::Null, Value::Number)
+}
+
+fn number_text(expr: &Expression) -> Option<String> {
+    match expr {
+        Expression::Number(token) => match token.token().token_type() {
+            TokenType::Number { text } => Some(text.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 fn extract_string_from_expr(expr: &Expression) -> Option<String> {
     if let Expression::String(token) = expr {
         let token_type = token.token().token_type();
@@ -156,7 +913,23 @@ fn extract_string_from_expr(expr: &Expression) -> Option<String> {
     None
 }
 
-fn extract_number_from_expr(expr: &Expression) -> Option<i32> {
+/// `true`/`false` are symbols to full_moon, not literals.
+fn extract_bool_from_expr(expr: &Expression) -> Option<bool> {
+    if let Expression::Symbol(token) = expr {
+        match token.token().token_type() {
+            TokenType::Symbol {
+                symbol: Symbol::True,
+            } => return Some(true),
+            TokenType::Symbol {
+                symbol: Symbol::False,
+            } => return Some(false),
+            _ => {}
+        }
+    }
+    None
+}
+
+pub(crate) fn extract_number_from_expr(expr: &Expression) -> Option<i32> {
     if let Expression::Number(token) = expr {
         let token_type = token.token().token_type();
         if let TokenType::Number { text } = token_type {
@@ -166,8 +939,26 @@ fn extract_number_from_expr(expr: &Expression) -> Option<i32> {
     None
 }
 
-fn parse_datetime(s: &str) -> Option<NaiveDateTime> {
-    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").ok()
+/// A number where a datetime belongs is Unix seconds, as some plugins write
+/// them. Returns the number as written along with the local time it stands
+/// for, since KOReader's own datetime strings are local time.
+fn extract_epoch_from_expr(expr: &Expression) -> Option<(String, NaiveDateTime)> {
+    if let Expression::Number(token) = expr {
+        if let TokenType::Number { text } = token.token().token_type() {
+            let seconds: i64 = text.parse().ok()?;
+            let datetime = Local.timestamp_opt(seconds, 0).single()?.naive_local();
+            return Some((text.to_string(), datetime));
+        }
+    }
+    None
+}
+
+pub fn parse_datetime(s: &str) -> Option<NaiveDateTime> {
+    // KOReader writes `%Y-%m-%d %H:%M:%S`; the ISO `T` form turns up in
+    // files that went through other tools
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s.trim(), format).ok())
 }
 
 pub fn filter_by_date(
@@ -184,16 +975,200 @@ pub fn filter_by_date(
         .collect()
 }
 
+/// Keeps the highlights made at or after `since`, to the second.
+pub fn filter_by_datetime(highlights: Vec<Highlight>, since: NaiveDateTime) -> Vec<Highlight> {
+    highlights.into_iter().filter(|h| h.datetime >= since).collect()
+}
+
+/// How many highlights were made on each calendar day, oldest day first.
+/// Days without highlights are left out.
+pub fn count_by_day(highlights: &[Highlight]) -> BTreeMap<NaiveDate, usize> {
+    let mut counts = BTreeMap::new();
+    for h in highlights {
+        *counts.entry(h.datetime.date()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// The import period: whole days, or everything after an exact moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateFilter {
+    /// From the first day to the last, both included
+    Range(NaiveDate, NaiveDate),
+    SinceDatetime(NaiveDateTime),
+}
+
+impl DateFilter {
+    pub fn apply(&self, highlights: Vec<Highlight>) -> Vec<Highlight> {
+        match *self {
+            DateFilter::Range(from, to) => filter_by_date(highlights, from, to),
+            DateFilter::SinceDatetime(since) => filter_by_datetime(highlights, since),
+        }
+    }
+
+    /// The same period with no last day, for imports that keep running
+    /// (`--watch`), where highlights made after startup must get through.
+    pub fn open_ended(self) -> DateFilter {
+        match self {
+            DateFilter::Range(from, _) => DateFilter::SinceDatetime(from.and_time(NaiveTime::MIN)),
+            since => since,
+        }
+    }
+}
+
+impl std::fmt::Display for DateFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DateFilter::Range(from, to) => write!(f, "{} to {}", from, to),
+            DateFilter::SinceDatetime(since) => write!(f, "since {}", since),
+        }
+    }
+}
+
+/// Which books to import, by case-insensitive substring of title or author.
+/// A book must match the inclusions (when any are given) and none of the
+/// exclusions, so an exclusion wins when both apply.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookFilter {
+    pub books: Vec<String>,
+    pub authors: Vec<String>,
+    pub exclude_books: Vec<String>,
+    pub exclude_authors: Vec<String>,
+    /// Only books of this document type, see `doc_format`.
+    pub doc_format: Option<String>,
+}
+
+impl BookFilter {
+    pub fn matches(&self, book: &BookData) -> bool {
+        let contains_any = |value: &str, needles: &[String]| {
+            let value = value.to_lowercase();
+            needles.iter().any(|n| value.contains(&n.to_lowercase()))
+        };
+
+        let included = (self.books.is_empty() || contains_any(&book.title, &self.books))
+            && (self.authors.is_empty() || contains_any(&book.author, &self.authors));
+        let excluded = contains_any(&book.title, &self.exclude_books)
+            || contains_any(&book.author, &self.exclude_authors);
+
+        let format_ok = self.doc_format.as_ref().is_none_or(|wanted| {
+            book.doc_format.as_ref().is_some_and(|f| f.eq_ignore_ascii_case(wanted))
+        });
+
+        included && !excluded && format_ok
+    }
+}
+
+/// Which pages to keep, both ends inclusive and optional. Page 0 means the
+/// page isn't known, so those highlights are dropped once a bound is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageRange {
+    pub from: Option<i32>,
+    pub to: Option<i32>,
+}
+
+impl PageRange {
+    pub fn matches(&self, highlight: &Highlight) -> bool {
+        if self.from.is_none() && self.to.is_none() {
+            return true;
+        }
+
+        highlight.page > 0
+            && self.from.is_none_or(|from| highlight.page >= from)
+            && self.to.is_none_or(|to| highlight.page <= to)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// Most recent highlight first
+    Newest,
+    /// Oldest highlight first
+    Oldest,
+    /// By page number
+    Page,
+}
+
+/// Order of the books in an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BookSort {
+    /// By title, A to Z
+    Title,
+    /// By author, then title
+    Author,
+    /// Most highlights first
+    Count,
+    /// Newest highlight first
+    Recent,
+}
+
+pub fn sort_books(books: &mut [BookData], order: BookSort) {
+    let newest = |b: &BookData| b.highlights.iter().map(|h| h.datetime).max();
+    match order {
+        BookSort::Title => books.sort_by(|a, b| a.title.cmp(&b.title)),
+        BookSort::Author => {
+            books.sort_by(|a, b| a.author.cmp(&b.author).then_with(|| a.title.cmp(&b.title)))
+        }
+        BookSort::Count => books.sort_by_key(|b| std::cmp::Reverse(b.highlights.len())),
+        BookSort::Recent => books.sort_by_key(|b| std::cmp::Reverse(newest(b))),
+    }
+}
+
+pub fn sort_highlights(highlights: &mut [Highlight], order: SortOrder) {
+    match order {
+        SortOrder::Newest => highlights.sort_by_key(|h| std::cmp::Reverse(h.datetime)),
+        SortOrder::Oldest => highlights.sort_by_key(|h| h.datetime),
+        SortOrder::Page => highlights.sort_by_key(|h| h.page),
+    }
+}
+
+/// Reads a metadata file, transparently decompressing `.gz` backups.
+pub fn read_metadata_file(path: &Path) -> std::io::Result<String> {
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        let mut content = String::new();
+        GzDecoder::new(File::open(path)?).read_to_string(&mut content)?;
+        Ok(content)
+    } else {
+        std::fs::read_to_string(path)
+    }
+}
+
+/// Reads and parses the metadata file at `path`.
+pub fn parse_metadata_file(path: &Path) -> Result<BookData, ProcessError> {
+    parse_metadata_file_checked(path, &ParseOptions::default()).map(|(book, _)| book)
+}
+
+/// Like `parse_metadata_file`, with `parse_metadata_checked`'s options and
+/// skipped annotations.
+pub fn parse_metadata_file_checked(
+    path: &Path,
+    options: &ParseOptions,
+) -> Result<(BookData, Vec<SkippedAnnotation>), ProcessError> {
+    let content = read_metadata_file(path)
+        .map_err(|e| ProcessError::Read(path.to_path_buf(), e.to_string()))?;
+    parse_metadata_checked(&content, &path.to_string_lossy(), options)
+        .map_err(|e| ProcessError::Parse(path.to_path_buf(), e))
+}
+
+/// Whether `path` names a KOReader metadata file, plain or gzipped.
+pub fn is_metadata_file(path: &Path) -> bool {
+    doc_format(path).is_some()
+}
+
+/// The document type KOReader puts in the metadata file name, so
+/// `metadata.pdf.lua` (or `metadata.pdf.lua.gz`) gives `"pdf"`.
+pub fn doc_format(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    let format = name.strip_prefix("metadata.")?.strip_suffix(".lua")?;
+    let valid = !format.is_empty() && format.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| format.to_lowercase())
+}
+
 pub fn find_metadata_files(books_path: &Path) -> Vec<PathBuf> {
     WalkDir::new(books_path)
         .into_iter()
         .filter_map(|e| e.ok())
-        .filter(|e| {
-            e.path()
-                .file_name()
-                .map(|n| n.to_string_lossy() == "metadata.epub.lua")
-                .unwrap_or(false)
-        })
+        .filter(|e| is_metadata_file(e.path()))
         .map(|e| e.path().to_path_buf())
         .collect()
 }
@@ -256,10 +1231,162 @@ return {
 }
 "#;
 
-    #[test]
-    fn test_parse_valid_metadata() {
-        let result = parse_metadata(SAMPLE_LUA, "test.lua").unwrap();
-
+    const LUA_WITH_BOOKMARKS: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "Chapter 1",
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 42,
+            ["text"] = "A regular highlight",
+        },
+    },
+    ["bookmarks"] = {
+        [1] = {
+            ["chapter"] = "Chapter 2",
+            ["datetime"] = "2026-01-26 09:00:00",
+            ["pageno"] = 80,
+            ["note"] = "come back to this page",
+        },
+        [2] = {
+            ["chapter"] = "Chapter 3",
+            ["datetime"] = "2026-01-26 09:05:00",
+            ["pageno"] = 90,
+            ["text"] = "",
+            ["note"] = "and this one",
+        },
+        [3] = {
+            ["datetime"] = "2026-01-26 09:10:00",
+            ["pageno"] = 95,
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Bookmarked Book",
+        ["authors"] = "Some Author",
+    },
+}
+"#;
+
+    const LUA_BARE_KEYS: &str = r#"
+return {
+    annotations = {
+        [1] = {
+            chapter = "Chapter 1",
+            datetime = "2026-01-25 10:30:00",
+            pageno = 7,
+            text = "Bare keys work too",
+            note = "hand-written",
+        },
+    },
+    doc_props = {
+        title = "Book",
+        authors = "Hand Writer",
+    },
+}
+"#;
+
+    const LUA_WITH_DUPLICATES: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 42,
+            ["text"] = "Highlighted twice",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-25 10:31:00",
+            ["pageno"] = 42,
+            ["text"] = "Highlighted twice",
+        },
+        [3] = {
+            ["datetime"] = "2026-01-25 10:32:00",
+            ["pageno"] = 43,
+            ["text"] = "Highlighted twice",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Test Book",
+    },
+}
+"#;
+
+    const LUA_WITH_EMPTY_TEXT: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 10,
+            ["text"] = "",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-25 10:31:00",
+            ["pageno"] = 11,
+            ["text"] = "   ",
+            ["note"] = "",
+        },
+        [3] = {
+            ["datetime"] = "2026-01-25 10:32:00",
+            ["pageno"] = 12,
+            ["text"] = " ",
+            ["note"] = "a real note",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Test Book",
+    },
+}
+"#;
+
+    const LUA_NOTE_WITHOUT_TEXT: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 5,
+            ["note"] = "first thought on this page",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-25 10:35:00",
+            ["pageno"] = 5,
+            ["note"] = "second thought on this page",
+        },
+        [3] = {
+            ["datetime"] = "2026-01-25 10:40:00",
+            ["pageno"] = 6,
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Test Book",
+    },
+}
+"#;
+
+    const LUA_WITH_UPDATED: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["datetime_updated"] = "2026-01-27 21:05:12",
+            ["pageno"] = 10,
+            ["text"] = "Edited",
+            ["note"] = "changed my mind",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-25 10:31:00",
+            ["pageno"] = 11,
+            ["text"] = "Never edited",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Test Book",
+    },
+}
+"#;
+
+    #[test]
+    fn test_parse_valid_metadata() {
+        let result = parse_metadata(SAMPLE_LUA, "test.lua").unwrap();
+
         assert_eq!(result.title, "Test Book");
         assert_eq!(result.author, "Test Author");
         assert_eq!(result.highlights.len(), 2);
@@ -277,6 +1404,76 @@ return {
         assert!(matches!(result, Err(ParseError::MissingTitle(_))));
     }
 
+    #[test]
+    fn test_parse_not_koreader_metadata() {
+        let not_metadata = [
+            "local settings = { title = \"x\" }\nprint(settings.title)\n",
+            "-- a comment first\nreturn 42\n",
+            "\u{1}\u{8b}binary\u{0}junk",
+            "--[[ not a header\nreturn {\n]] local x = {\n",
+        ];
+
+        for content in not_metadata {
+            let result = parse_metadata(content, "odd.lua");
+            assert_eq!(result, Err(ParseError::NotKoreaderMetadata("odd.lua".to_string())));
+        }
+    }
+
+    #[test]
+    fn test_block_comment_before_return() {
+        let headers = [
+            "--[[\n  a header comment\n]]\n",
+            "--[==[ with ]] inside ]==]\n-- and more\n",
+        ];
+        for header in headers {
+            let lua = format!("{}{}", header, SAMPLE_LUA);
+            assert!(parse_metadata(&lua, "test.lua").is_ok(), "{:?}", header);
+
+            let cut = lua.find("[\"doc_props\"]").unwrap();
+            assert_eq!(
+                parse_metadata(&lua[..cut], "x"),
+                Err(ParseError::TruncatedFile("x".to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_store_raw_keeps_unknown_keys() {
+        let lua = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["color"] = "yellow",
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["drawer"] = "lighten",
+            ["pageno"] = 12,
+            ["pos0"] = { ["page"] = 12, ["x"] = 10.5 },
+            ["some_future_field"] = true,
+            ["text"] = "Kept raw",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Raw",
+    },
+}
+"#;
+        assert_eq!(parse_metadata(lua, "test.lua").unwrap().highlights[0].raw_json, None);
+
+        let options = ParseOptions {
+            store_raw: true,
+            ..Default::default()
+        };
+        let book = parse_metadata_with(lua, "test.lua", &options).unwrap();
+        let raw: serde_json::Value =
+            serde_json::from_str(book.highlights[0].raw_json.as_deref().unwrap()).unwrap();
+
+        assert_eq!(raw["some_future_field"], true);
+        assert_eq!(raw["color"], "yellow");
+        assert_eq!(raw["pageno"], 12);
+        assert_eq!(raw["pos0"]["x"], 10.5);
+        assert_eq!(raw["text"], "Kept raw");
+    }
+
     #[test]
     fn test_parse_invalid_lua() {
         let result = parse_metadata(LUA_INVALID, "broken.lua");
@@ -284,6 +1481,91 @@ return {
         assert!(matches!(result, Err(ParseError::InvalidLua(_))));
     }
 
+    #[test]
+    fn test_truncated_file_is_reported_as_such() {
+        let cut = SAMPLE_LUA.find("[\"doc_props\"]").unwrap();
+        let unclosed_table = &SAMPLE_LUA[..cut];
+        let text_start = SAMPLE_LUA.find("Another highlight").unwrap();
+        let unclosed_string = &SAMPLE_LUA[..text_start + 3];
+
+        for truncated in [unclosed_table, unclosed_string] {
+            let result = parse_metadata(truncated, "Book.sdr/metadata.epub.lua");
+            assert_eq!(
+                result,
+                Err(ParseError::TruncatedFile("Book.sdr/metadata.epub.lua".to_string()))
+            );
+        }
+        // a typo in the middle isn't mistaken for a cut-off file
+        let typo = SAMPLE_LUA.replacen("] = \"", "] = \"\n", 1);
+        assert!(matches!(parse_metadata(&typo, "x"), Err(ParseError::InvalidLua(_))));
+    }
+
+    #[test]
+    fn test_annotations_follow_index_despite_holes() {
+        let lua = r#"
+return {
+    ["annotations"] = {
+        [1] = { ["datetime"] = "2026-01-25 10:00:00", ["pageno"] = 5, ["text"] = "One" },
+        [5] = { ["datetime"] = "2026-01-25 10:02:00", ["pageno"] = 30, ["text"] = "Three" },
+        [3] = { ["datetime"] = "2026-01-25 10:01:00", ["pageno"] = 20, ["text"] = "Two" },
+    },
+    ["doc_props"] = { ["title"] = "Holes" },
+}
+"#;
+        let book = parse_metadata(lua, "test.lua").unwrap();
+
+        let texts: Vec<&str> = book.highlights.iter().map(|h| h.text.as_str()).collect();
+        assert_eq!(texts, vec!["One", "Two", "Three"]);
+    }
+
+    #[test]
+    fn test_normalize_newlines_option() {
+        let lua = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 1,
+            ["text"] = "one\r\ntwo\rthree\\r",
+            ["note"] = [[RAW]],
+        },
+    },
+    ["doc_props"] = { ["title"] = "Lines" },
+}
+"#
+        .replace("RAW", "raw\r\nbreak");
+        let options = ParseOptions {
+            normalize_newlines: true,
+            ..Default::default()
+        };
+
+        let book = parse_metadata_with(&lua, "test.lua", &options).unwrap();
+        let h = &book.highlights[0];
+        assert_eq!(h.text, r"one\ntwo\nthree\\r");
+        assert_eq!(h.note.as_deref(), Some("raw\nbreak"));
+
+        let untouched = parse_metadata(&lua, "test.lua").unwrap();
+        assert_eq!(untouched.highlights[0].text, r"one\r\ntwo\rthree\\r");
+    }
+
+    #[test]
+    fn test_count_by_day() {
+        let at = |day: u32, hour: u32| Highlight {
+            datetime: NaiveDate::from_ymd_opt(2026, 1, day)
+                .unwrap()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap(),
+            ..Default::default()
+        };
+        let highlights = [at(26, 9), at(25, 23), at(27, 0), at(25, 1), at(27, 12), at(27, 23)];
+
+        let counts = count_by_day(&highlights);
+
+        let day = |d: u32| NaiveDate::from_ymd_opt(2026, 1, d).unwrap();
+        let expected: Vec<(NaiveDate, usize)> = vec![(day(25), 2), (day(26), 1), (day(27), 3)];
+        assert_eq!(counts.into_iter().collect::<Vec<_>>(), expected);
+    }
+
     #[test]
     fn test_filter_by_date_includes_range() {
         let book = parse_metadata(SAMPLE_LUA, "test.lua").unwrap();
@@ -336,4 +1618,763 @@ return {
         let h = &result.highlights[0];
         assert!(h.note.is_none());
     }
+
+    #[test]
+    fn test_bookmarks_ignored_by_default() {
+        let result = parse_metadata(LUA_WITH_BOOKMARKS, "test.lua").unwrap();
+
+        assert_eq!(result.highlights.len(), 1);
+        assert_eq!(result.highlights[0].kind, HighlightKind::Highlight);
+    }
+
+    #[test]
+    fn test_include_bookmarks_with_notes() {
+        let options = ParseOptions {
+            include_bookmarks: true,
+            ..Default::default()
+        };
+        let result = parse_metadata_with(LUA_WITH_BOOKMARKS, "test.lua", &options).unwrap();
+
+        let count = |kind| result.highlights.iter().filter(|h| h.kind == kind).count();
+        assert_eq!(count(HighlightKind::Highlight), 1);
+        assert_eq!(count(HighlightKind::Bookmark), 2); // the note-less one is skipped
+
+        let bookmark = &result.highlights[1];
+        assert_eq!(bookmark.page, 80);
+        assert_eq!(bookmark.text, "");
+        assert_eq!(bookmark.note, Some("come back to this page".to_string()));
+    }
+
+    #[test]
+    fn test_bookmark_blank_text_and_note_are_trimmed() {
+        let options = ParseOptions {
+            include_bookmarks: true,
+            ..Default::default()
+        };
+        let lua = LUA_WITH_BOOKMARKS
+            .replace("[\"text\"] = \"\",", "[\"text\"] = \"   \",")
+            .replace("\"come back to this page\"", "\"   \"");
+        let result = parse_metadata_with(&lua, "test.lua", &options).unwrap();
+
+        let notes: Vec<&str> = result
+            .highlights
+            .iter()
+            .filter(|h| h.kind == HighlightKind::Bookmark)
+            .filter_map(|h| h.note.as_deref())
+            .collect();
+        assert_eq!(notes, vec!["and this one"]);
+    }
+
+    #[test]
+    fn test_parse_bare_name_keys() {
+        let result = parse_metadata(LUA_BARE_KEYS, "test.lua").unwrap();
+
+        assert_eq!(result.title, "Book");
+        assert_eq!(result.author, "Hand Writer");
+        assert_eq!(result.highlights.len(), 1);
+
+        let h = &result.highlights[0];
+        assert_eq!(h.chapter, Some("Chapter 1".to_string()));
+        assert_eq!(h.page, 7);
+        assert_eq!(h.text, "Bare keys work too");
+        assert_eq!(h.note, Some("hand-written".to_string()));
+    }
+
+    const LUA_IN_DOC_SETTINGS: &str = r#"
+return {
+    ["doc_settings"] = {
+        ["annotations"] = {
+            [1] = {
+                ["chapter"] = "Nested",
+                ["datetime"] = "2026-01-25 10:30:00",
+                ["pageno"] = 12,
+                ["text"] = "Found one level down",
+            },
+        },
+        ["doc_props"] = {
+            ["authors"] = "Inner Author",
+            ["title"] = "Inner Title",
+        },
+    },
+}
+"#;
+
+    #[test]
+    fn test_annotations_under_doc_settings() {
+        let result = parse_metadata(LUA_IN_DOC_SETTINGS, "test.lua").unwrap();
+
+        assert_eq!(result.title, "Inner Title");
+        assert_eq!(result.author, "Inner Author");
+        assert_eq!(result.highlights.len(), 1);
+        assert_eq!(result.highlights[0].text, "Found one level down");
+        assert_eq!(result.highlights[0].page, 12);
+    }
+
+    const LUA_HALF_VALID: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 3,
+            ["text"] = "Fine",
+        },
+        [2] = {
+            ["datetime"] = "last tuesday",
+            ["pageno"] = 4,
+            ["text"] = "Bad time",
+        },
+        [3] = {
+            ["datetime"] = "2026-01-25 10:32:00",
+            ["pageno"] = "five",
+            ["text"] = "Bad page",
+        },
+        [4] = {
+            ["datetime"] = "2026-01-25 10:33:00",
+            ["pageno"] = 6,
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Half Valid",
+    },
+}
+"#;
+
+    #[test]
+    fn test_checked_parse_reports_skipped_annotations() {
+        let (book, skipped) =
+            parse_metadata_checked(LUA_HALF_VALID, "test.lua", &ParseOptions::default()).unwrap();
+        // without --strict a bad pageno still reads as page 0
+        assert_eq!(book.highlights.len(), 2);
+        assert_eq!(skipped.len(), 2);
+
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let (book, skipped) = parse_metadata_checked(LUA_HALF_VALID, "test.lua", &options).unwrap();
+
+        assert_eq!(book.highlights.len(), 1);
+        assert_eq!(book.highlights[0].text, "Fine");
+        let reasons: Vec<(usize, SkipReason)> =
+            skipped.iter().map(|s| (s.index, s.reason.clone())).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (2, SkipReason::InvalidDatetime("last tuesday".to_string())),
+                (3, SkipReason::InvalidPageno),
+                (4, SkipReason::MissingText),
+            ]
+        );
+        assert_eq!(skipped[0].to_string(), "annotation [2]: unreadable datetime 'last tuesday'");
+    }
+
+    #[test]
+    fn test_doc_format_from_file_name() {
+        let format = |name: &str| doc_format(Path::new(name));
+
+        assert_eq!(format("Book.sdr/metadata.pdf.lua"), Some("pdf".to_string()));
+        assert_eq!(format("Book.sdr/metadata.epub.lua.gz"), Some("epub".to_string()));
+        assert_eq!(format("Book.sdr/metadata.lua"), None);
+        assert_eq!(format("Book.sdr/notes.txt"), None);
+        assert!(is_metadata_file(Path::new("Book.sdr/metadata.djvu.lua")));
+    }
+
+    #[test]
+    fn test_title_from_path_fallback() {
+        let lua = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 1,
+            ["text"] = "Untitled but kept",
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Someone",
+    },
+}
+"#;
+        let path = "/mnt/onboard/Books/My Book.sdr/metadata.epub.lua";
+        assert!(matches!(parse_metadata(lua, path), Err(ParseError::MissingTitle(_))));
+
+        let options = ParseOptions {
+            title_from_path: true,
+            ..Default::default()
+        };
+        let book = parse_metadata_with(lua, path, &options).unwrap();
+        assert_eq!(book.title, "My Book");
+        assert_eq!(book.highlights.len(), 1);
+
+        assert_eq!(title_from_path("/Books/notes/metadata.epub.lua"), None);
+    }
+
+    #[test]
+    fn test_page_key_fallback() {
+        let lua = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["page"] = 55,
+            ["text"] = "Old layout",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-25 10:31:00",
+            ["page"] = "/body/DocFragment[4]/body/p[1]/text().0",
+            ["pageno"] = 56,
+            ["text"] = "New layout",
+        },
+        [3] = {
+            ["datetime"] = "2026-01-25 10:32:00",
+            ["page"] = 99,
+            ["pageno"] = 57,
+            ["text"] = "Both numbers",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Pages",
+    },
+}
+"#;
+        let book = parse_metadata(lua, "test.lua").unwrap();
+        let pages: Vec<i32> = book.highlights.iter().map(|h| h.page).collect();
+
+        assert_eq!(pages, vec![55, 56, 57]);
+    }
+
+    #[test]
+    fn test_parse_with_byte_order_mark() {
+        let with_bom = format!("\u{FEFF}{}", SAMPLE_LUA);
+
+        assert_eq!(
+            parse_metadata(&with_bom, "test.lua").unwrap(),
+            parse_metadata(SAMPLE_LUA, "test.lua").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_duplicate_annotations_in_file_collapse() {
+        let result = parse_metadata(LUA_WITH_DUPLICATES, "test.lua").unwrap();
+
+        assert_eq!(result.highlights.len(), 2);
+        assert_eq!(result.highlights[0].page, 42);
+        assert_eq!(
+            result.highlights[0].datetime.format("%H:%M").to_string(),
+            "10:30" // the first one wins
+        );
+        assert_eq!(result.highlights[1].page, 43);
+    }
+
+    #[test]
+    fn test_dedup_by_datetime_collapses_sync_copies() {
+        let lua = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 42,
+            ["pos0"] = "/body/DocFragment[3]/body/p[2]/text().0",
+            ["text"] = "A sentence that was synced twice.",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 43,
+            ["pos0"] = "/body/DocFragment[3]/body/p[2]/text().1",
+            ["text"] = " A sentence  that was   synced twice. ",
+        },
+        [3] = {
+            ["datetime"] = "2026-01-25 10:31:00",
+            ["pageno"] = 44,
+            ["text"] = "A sentence that was synced twice.",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Synced Book",
+    },
+}
+"#;
+        assert_eq!(parse_metadata(lua, "test.lua").unwrap().highlights.len(), 3);
+
+        let options = ParseOptions {
+            dedup_by: DedupStrategy::Datetime,
+            ..Default::default()
+        };
+        let book = parse_metadata_with(lua, "test.lua", &options).unwrap();
+
+        assert_eq!(book.highlights.len(), 2);
+        assert_eq!(book.highlights[0].page, 42);
+        assert_eq!(book.highlights[1].datetime.format("%H:%M").to_string(), "10:31");
+    }
+
+    #[test]
+    fn test_dedup_by_datetime_keeps_distinct_notes() {
+        let datetime =
+            NaiveDateTime::parse_from_str("2026-01-26 09:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let note_only = |page: i32, note: &str| Highlight {
+            page,
+            note: Some(note.to_string()),
+            datetime,
+            ..Default::default()
+        };
+        let highlights = vec![
+            note_only(80, "come back to this page"),
+            note_only(81, "and this one"),
+            note_only(82, " and  this one "),
+        ];
+
+        let notes: Vec<_> = dedupe_by_datetime(highlights)
+            .into_iter()
+            .map(|h| h.note)
+            .collect();
+        assert_eq!(
+            notes,
+            vec![Some("come back to this page".to_string()), Some("and this one".to_string())]
+        );
+    }
+
+    fn make_sortable(page: i32, datetime: &str) -> Highlight {
+        Highlight {
+            page,
+            text: format!("p{}", page),
+            datetime: parse_datetime(datetime).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn sorted_pages(order: SortOrder) -> Vec<i32> {
+        let mut highlights = vec![
+            make_sortable(50, "2026-01-26 09:00:00"),
+            make_sortable(10, "2026-01-27 09:00:00"),
+            make_sortable(30, "2026-01-25 09:00:00"),
+        ];
+        sort_highlights(&mut highlights, order);
+        highlights.iter().map(|h| h.page).collect()
+    }
+
+    #[test]
+    fn test_page_range_is_inclusive() {
+        let on_page = |page| Highlight {
+            page,
+            ..Default::default()
+        };
+        let range = PageRange {
+            from: Some(10),
+            to: Some(20),
+        };
+
+        assert!(range.matches(&on_page(10)));
+        assert!(range.matches(&on_page(20)));
+        assert!(!range.matches(&on_page(9)));
+        assert!(!range.matches(&on_page(21)));
+
+        let open_ended = PageRange {
+            from: None,
+            to: Some(5),
+        };
+        assert!(open_ended.matches(&on_page(1)));
+        assert!(!open_ended.matches(&on_page(0)));
+        assert!(PageRange::default().matches(&on_page(0)));
+    }
+
+    #[test]
+    fn test_sort_newest_first() {
+        assert_eq!(sorted_pages(SortOrder::Newest), vec![10, 50, 30]);
+    }
+
+    #[test]
+    fn test_sort_oldest_first() {
+        assert_eq!(sorted_pages(SortOrder::Oldest), vec![30, 50, 10]);
+    }
+
+    #[test]
+    fn test_sort_by_page() {
+        assert_eq!(sorted_pages(SortOrder::Page), vec![10, 30, 50]);
+    }
+
+    fn sorted_titles(order: BookSort) -> Vec<String> {
+        let book = |title: &str, author: &str, datetimes: &[&str]| BookData {
+            title: title.to_string(),
+            author: author.to_string(),
+            highlights: datetimes.iter().map(|d| make_sortable(1, d)).collect(),
+            ..Default::default()
+        };
+        let mut books = vec![
+            book("Middle", "Zed", &["2026-01-20 09:00:00"]),
+            book("Zebra", "Adams", &["2026-01-05 09:00:00", "2026-01-06 09:00:00"]),
+            book(
+                "Apple",
+                "Moss",
+                &["2026-01-01 09:00:00", "2026-01-02 09:00:00", "2026-01-03 09:00:00"],
+            ),
+        ];
+        sort_books(&mut books, order);
+        books.into_iter().map(|b| b.title).collect()
+    }
+
+    #[test]
+    fn test_sort_books() {
+        assert_eq!(sorted_titles(BookSort::Title), vec!["Apple", "Middle", "Zebra"]);
+        assert_eq!(sorted_titles(BookSort::Author), vec!["Zebra", "Apple", "Middle"]);
+        assert_eq!(sorted_titles(BookSort::Count), vec!["Apple", "Zebra", "Middle"]);
+        assert_eq!(sorted_titles(BookSort::Recent), vec!["Middle", "Zebra", "Apple"]);
+    }
+
+    #[test]
+    fn test_read_gzipped_metadata() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let sdr = dir.path().join("Test Book.sdr");
+        std::fs::create_dir(&sdr).unwrap();
+        let path = sdr.join("metadata.epub.lua.gz");
+
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(SAMPLE_LUA.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        assert_eq!(find_metadata_files(dir.path()), vec![path.clone()]);
+
+        let content = read_metadata_file(&path).unwrap();
+        let gzipped = parse_metadata(&content, "test.lua.gz").unwrap();
+        let plain = parse_metadata(SAMPLE_LUA, "test.lua").unwrap();
+
+        assert_eq!(gzipped, plain);
+    }
+
+    #[test]
+    fn test_parse_metadata_file_reads_and_parses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata.epub.lua");
+        std::fs::write(&path, format!("\u{FEFF}{}", SAMPLE_LUA)).unwrap();
+
+        let book = parse_metadata_file(&path).unwrap();
+        assert_eq!(book, parse_metadata(SAMPLE_LUA, &path.to_string_lossy()).unwrap());
+
+        let missing = dir.path().join("missing.lua");
+        let read_error = parse_metadata_file(&missing);
+        assert!(matches!(read_error, Err(ProcessError::Read(p, _)) if p == missing));
+        std::fs::write(&path, "not lua").unwrap();
+        assert!(matches!(parse_metadata_file(&path), Err(ProcessError::Parse(..))));
+    }
+
+    #[test]
+    fn test_raw_datetime_preserved() {
+        let lua = SAMPLE_LUA.replace("2026-01-26 14:00:00", "2026-01-26T14:00:00");
+        let result = parse_metadata(&lua, "test.lua").unwrap();
+
+        let h = &result.highlights[1];
+        assert_eq!(h.raw_datetime, "2026-01-26T14:00:00");
+        assert_eq!(h.datetime, parse_datetime("2026-01-26 14:00:00").unwrap());
+    }
+
+    #[test]
+    fn test_epoch_datetime() {
+        // the moment KOReader would write as this local time
+        let local = parse_datetime("2026-01-25 10:30:00").unwrap();
+        let seconds = Local.from_local_datetime(&local).unwrap().timestamp().to_string();
+        let lua = SAMPLE_LUA.replace("\"2026-01-26 14:00:00\"", &seconds);
+        let result = parse_metadata(&lua, "test.lua").unwrap();
+
+        let h = &result.highlights[1];
+        assert_eq!(h.datetime, local);
+        assert_eq!(h.raw_datetime, seconds);
+    }
+
+    #[test]
+    fn test_normalize_chapter_prefixes() {
+        assert_eq!(normalize_chapter("1.2.3 Actual Title"), "Actual Title");
+        assert_eq!(normalize_chapter("3. Introduction"), "Introduction");
+        assert_eq!(normalize_chapter("12) Twelve"), "Twelve");
+        assert_eq!(normalize_chapter("  Chapter 5  "), "Chapter 5");
+        assert_eq!(normalize_chapter("• 4. Bulleted"), "Bulleted");
+        assert_eq!(normalize_chapter("• 4 Bulleted"), "4 Bulleted");
+        assert_eq!(normalize_chapter("1984"), "1984");
+        assert_eq!(normalize_chapter("1984 Revisited"), "1984 Revisited");
+        assert_eq!(normalize_chapter("2001: A Space Odyssey"), "2001: A Space Odyssey");
+        assert_eq!(normalize_chapter("3rd Act"), "3rd Act");
+    }
+
+    #[test]
+    fn test_authors_preferred_over_author() {
+        let both = SAMPLE_LUA.replace(
+            "[\"authors\"] = \"Test Author\",",
+            "[\"author\"] = \"Hand Edited\",\n        [\"authors\"] = \"Test Author\",",
+        );
+        assert_eq!(parse_metadata(&both, "test.lua").unwrap().author, "Test Author");
+
+        let singular = SAMPLE_LUA.replace("[\"authors\"]", "[\"author\"]");
+        assert_eq!(parse_metadata(&singular, "test.lua").unwrap().author, "Test Author");
+    }
+
+    #[test]
+    fn test_format_author_last_first() {
+        let last_first = |a: &str| format_author(a, AuthorFormat::LastFirst);
+
+        assert_eq!(last_first("Plato"), "Plato");
+        assert_eq!(last_first("Frank Herbert"), "Herbert, Frank");
+        assert_eq!(last_first("Ursula K. Le Guin"), "Le Guin, Ursula K.");
+        assert_eq!(last_first("Ludwig van Beethoven"), "van Beethoven, Ludwig");
+        assert_eq!(last_first("Herbert, Frank"), "Herbert, Frank");
+        assert_eq!(last_first("Neil Gaiman & Terry Pratchett"), "Neil Gaiman & Terry Pratchett");
+        let sicp = r"Harold Abelson\nGerald Jay Sussman\nJulie Sussman";
+        assert_eq!(last_first(sicp), sicp);
+        assert_eq!(last_first("Harold Abelson\nJulie Sussman"), "Harold Abelson\nJulie Sussman");
+        assert_eq!(format_author("Frank Herbert", AuthorFormat::AsIs), "Frank Herbert");
+    }
+
+    #[test]
+    fn test_clean_chapters_option() {
+        let lua = SAMPLE_LUA.replace("\"Chapter 2\"", "\"  2.1 The Middle \"");
+        let options = ParseOptions {
+            clean_chapters: true,
+            ..Default::default()
+        };
+
+        let cleaned = parse_metadata_with(&lua, "test.lua", &options).unwrap();
+        let raw = parse_metadata(&lua, "test.lua").unwrap();
+
+        assert_eq!(cleaned.highlights[1].chapter, Some("The Middle".to_string()));
+        assert_eq!(raw.highlights[1].chapter, Some("  2.1 The Middle ".to_string()));
+    }
+
+    #[test]
+    fn test_parse_positions() {
+        let lua = SAMPLE_LUA.replace(
+            "[\"pageno\"] = 42,",
+            "[\"pageno\"] = 42,\n            [\"pos0\"] = \"/body/DocFragment[3]/body/p[2]/text().0\",\n            [\"pos1\"] = \"/body/DocFragment[3]/body/p[2]/text().25\",",
+        );
+        let result = parse_metadata(&lua, "test.lua").unwrap();
+
+        let h = &result.highlights[0];
+        assert_eq!(h.pos0.as_deref(), Some("/body/DocFragment[3]/body/p[2]/text().0"));
+        assert_eq!(h.pos1.as_deref(), Some("/body/DocFragment[3]/body/p[2]/text().25"));
+        assert!(result.highlights[1].pos0.is_none());
+    }
+
+    #[test]
+    fn test_parse_description() {
+        let lua = SAMPLE_LUA.replace(
+            "[\"authors\"] = \"Test Author\",",
+            "[\"authors\"] = \"Test Author\",\n        [\"description\"] = \"A book about tests.\",",
+        );
+
+        let result = parse_metadata(&lua, "test.lua").unwrap();
+
+        assert_eq!(result.description.as_deref(), Some("A book about tests."));
+    }
+
+    #[test]
+    fn test_parse_description_table_skipped() {
+        let lua = SAMPLE_LUA.replace(
+            "[\"authors\"] = \"Test Author\",",
+            "[\"authors\"] = \"Test Author\",\n        [\"description\"] = { [\"en\"] = \"Nested\" },",
+        );
+
+        let result = parse_metadata(&lua, "test.lua").unwrap();
+
+        assert_eq!(result.description, None);
+        assert_eq!(result.author, "Test Author");
+        assert_eq!(result.highlights.len(), 2);
+    }
+
+    #[test]
+    fn test_note_without_text_key_kept() {
+        let result = parse_metadata(LUA_NOTE_WITHOUT_TEXT, "test.lua").unwrap();
+
+        let notes: Vec<Option<&str>> =
+            result.highlights.iter().map(|h| h.note.as_deref()).collect();
+        assert_eq!(
+            notes,
+            vec![Some("first thought on this page"), Some("second thought on this page")]
+        );
+        assert!(result
+            .highlights
+            .iter()
+            .all(|h| h.text.is_empty() && h.kind == HighlightKind::NoteOnly));
+    }
+
+    #[test]
+    fn test_parse_text_edited() {
+        let lua = LUA_WITH_UPDATED
+            .replace(
+                "[\"text\"] = \"Edited\",",
+                "[\"text\"] = \"Edited\",\n            [\"text_edited\"] = true,",
+            )
+            .replace(
+                "[\"text\"] = \"Never edited\",",
+                "[\"text\"] = \"Never edited\",\n            [\"text_edited\"] = false,",
+            );
+
+        let result = parse_metadata(&lua, "test.lua").unwrap();
+        let missing = parse_metadata(SAMPLE_LUA, "test.lua").unwrap();
+
+        assert!(result.highlights[0].text_edited);
+        assert!(!result.highlights[1].text_edited);
+        assert!(!missing.highlights[0].text_edited);
+    }
+
+    #[test]
+    fn test_parse_datetime_updated() {
+        let result = parse_metadata(LUA_WITH_UPDATED, "test.lua").unwrap();
+
+        let edited = &result.highlights[0];
+        assert_eq!(edited.raw_datetime, "2026-01-25 10:30:00");
+        assert_eq!(edited.updated, parse_datetime("2026-01-27 21:05:12"));
+        assert!(edited.updated.is_some());
+        assert_eq!(result.highlights[1].updated, None);
+    }
+
+    fn at(page: i32, text: &str, pos0: &str, pos1: &str) -> Highlight {
+        Highlight {
+            page,
+            text: text.to_string(),
+            pos0: Some(pos0.to_string()),
+            pos1: Some(pos1.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_chapter_from_pos() {
+        assert_eq!(
+            chapter_from_pos("/body/DocFragment[12]/body/div/p[3]/text().5"),
+            Some("Section 12".to_string())
+        );
+        assert_eq!(
+            chapter_from_pos("/body/DocFragment[3]/body/section/h2/text()"),
+            Some("Section 3".to_string())
+        );
+        assert_eq!(chapter_from_pos("/body/div/p[3]/text().5"), None);
+        assert_eq!(chapter_from_pos("/body/DocFragment[]/p"), None);
+        assert_eq!(chapter_from_pos("42"), None);
+    }
+
+    #[test]
+    fn test_infer_chapters_only_fills_missing() {
+        let lua = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 4,
+            ["pos0"] = "/body/DocFragment[7]/body/p[1]/text().0",
+            ["text"] = "No chapter here",
+        },
+        [2] = {
+            ["chapter"] = "Named",
+            ["datetime"] = "2026-01-25 10:31:00",
+            ["pageno"] = 5,
+            ["pos0"] = "/body/DocFragment[8]/body/p[1]/text().0",
+            ["text"] = "Chapter given",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Book",
+    },
+}
+"#;
+        let plain = parse_metadata(lua, "test.lua").unwrap();
+        assert_eq!(plain.highlights[0].chapter, None);
+
+        let options = ParseOptions {
+            infer_chapters: true,
+            ..Default::default()
+        };
+        let book = parse_metadata_with(lua, "test.lua", &options).unwrap();
+        assert_eq!(book.highlights[0].chapter.as_deref(), Some("Section 7"));
+        assert_eq!(book.highlights[1].chapter.as_deref(), Some("Named"));
+    }
+
+    #[test]
+    fn test_merge_adjacent_joins_contiguous_selections() {
+        let highlights = vec![
+            at(7, "second half.", "/body/p[10]/text().0", "/body/p[10]/text().12"),
+            at(7, "Elsewhere", "/body/p[2]/text().0", "/body/p[2]/text().9"),
+            at(7, "The first half", "/body/p[9]/text().40", "/body/p[10]/text().0"),
+        ];
+
+        let merged = merge_adjacent(highlights);
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].text, "Elsewhere");
+        assert_eq!(merged[1].text, "The first half second half.");
+        assert_eq!(merged[1].pos0.as_deref(), Some("/body/p[9]/text().40"));
+        assert_eq!(merged[1].pos1.as_deref(), Some("/body/p[10]/text().12"));
+    }
+
+    #[test]
+    fn test_merge_adjacent_keeps_gaps_apart() {
+        let highlights = vec![
+            at(7, "One", "/body/p[1]/text().0", "/body/p[1]/text().3"),
+            at(7, "Two", "/body/p[1]/text().4", "/body/p[1]/text().7"),
+            at(8, "Three", "/body/p[1]/text().7", "/body/p[1]/text().12"),
+        ];
+
+        assert_eq!(merge_adjacent(highlights).len(), 3);
+    }
+
+    #[test]
+    fn test_empty_text_without_note_skipped() {
+        let result = parse_metadata(LUA_WITH_EMPTY_TEXT, "test.lua").unwrap();
+
+        assert!(result.highlights.iter().all(|h| h.page == 12));
+    }
+
+    #[test]
+    fn test_empty_text_with_note_kept_as_note() {
+        let result = parse_metadata(LUA_WITH_EMPTY_TEXT, "test.lua").unwrap();
+
+        assert_eq!(result.highlights.len(), 1);
+        let h = &result.highlights[0];
+        assert_eq!(h.kind, HighlightKind::NoteOnly);
+        assert_eq!(h.text, "");
+        assert_eq!(h.note, Some("a real note".to_string()));
+    }
+
+    fn make_book(title: &str, author: &str) -> BookData {
+        BookData {
+            title: title.to_string(),
+            author: author.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_book_filter_multiple_exclusions() {
+        let filter = BookFilter {
+            exclude_books: strings(&["manual", "DICTIONARY"]),
+            exclude_authors: strings(&["tolkien"]),
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&make_book("Dune", "Frank Herbert")));
+        assert!(!filter.matches(&make_book("Kindle User's Manual", "Amazon")));
+        assert!(!filter.matches(&make_book("Oxford Dictionary", "Various")));
+        assert!(!filter.matches(&make_book("The Hobbit", "J.R.R. Tolkien")));
+    }
+
+    #[test]
+    fn test_book_filter_exclusion_wins_over_inclusion() {
+        let filter = BookFilter {
+            authors: strings(&["herbert"]),
+            exclude_books: strings(&["children"]),
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&make_book("Dune", "Frank Herbert")));
+        assert!(!filter.matches(&make_book("Children of Dune", "Frank Herbert")));
+        assert!(!filter.matches(&make_book("Neuromancer", "William Gibson")));
+    }
+
+    #[test]
+    fn test_book_filter_empty_matches_everything() {
+        assert!(BookFilter::default().matches(&make_book("Anything", "Anyone")));
+    }
 }
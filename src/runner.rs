@@ -0,0 +1,1111 @@
+use crate::color::{self, Paint};
+use crate::config::{Config, ConfigError};
+use crate::db::{self, DbError};
+use crate::models::{BookData, Highlight, HighlightKind};
+use crate::parser::{self, DateFilter, ParseError, ProcessError};
+use chrono::NaiveDate;
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// Totals from one import run, plus the filtered books when an export was
+/// requested.
+#[derive(Debug, Default)]
+pub struct RunStats {
+    pub files_scanned: usize,
+    pub highlights_found: usize,
+    pub highlights_inserted: usize,
+    pub highlights_duplicate: usize,
+    /// New highlights left out because `--limit` was reached.
+    pub highlights_over_limit: usize,
+    pub report: ProcessReport,
+    /// Highlights found per day, see `parser::count_by_day`.
+    pub by_day: BTreeMap<NaiveDate, usize>,
+    pub books: Vec<BookData>,
+}
+
+/// Files skipped during a run, split by why they were skipped: a read error
+/// points at permissions or encoding, a parse error at a corrupt Lua file.
+/// With `--strict`, single annotations left out of otherwise good files are
+/// counted too.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProcessReport {
+    pub read_errors: usize,
+    pub parse_errors: usize,
+    pub invalid_annotations: usize,
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Config(ConfigError),
+    Database(DbError),
+    BooksPathMissing(PathBuf),
+    ReadFailed(PathBuf, String),
+    ParseFailed(PathBuf, ParseError),
+    WatchFailed(String),
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Config(e) => write!(f, "{}", e),
+            RunError::Database(e) => write!(f, "Database error: {}", e),
+            RunError::BooksPathMissing(path) => {
+                write!(f, "Books path does not exist: {}", path.display())
+            }
+            RunError::ReadFailed(path, e) => write!(f, "Failed to read {}: {}", path.display(), e),
+            RunError::ParseFailed(path, e) => {
+                write!(f, "Failed to parse {}: {}", path.display(), e)
+            }
+            RunError::WatchFailed(e) => write!(f, "Failed to watch for changes: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<ProcessError> for RunError {
+    fn from(e: ProcessError) -> Self {
+        match e {
+            ProcessError::Read(path, e) => RunError::ReadFailed(path, e),
+            ProcessError::Parse(path, e) => RunError::ParseFailed(path, e),
+        }
+    }
+}
+
+impl From<ConfigError> for RunError {
+    fn from(e: ConfigError) -> Self {
+        RunError::Config(e)
+    }
+}
+
+impl From<DbError> for RunError {
+    fn from(e: DbError) -> Self {
+        RunError::Database(e)
+    }
+}
+
+/// How many files pass between two progress updates.
+const PROGRESS_EVERY: usize = 50;
+
+/// Emits `Scanned X/Y` lines while the file loop advances. The last file is
+/// always reported, so short scans still end on a complete count.
+pub struct Progress<'a> {
+    sink: &'a mut dyn Write,
+    total: usize,
+    every: usize,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(sink: &'a mut dyn Write, total: usize, every: usize) -> Self {
+        Progress {
+            sink,
+            total,
+            every: every.max(1),
+        }
+    }
+
+    pub fn tick(&mut self, scanned: usize) {
+        if scanned.is_multiple_of(self.every) || scanned == self.total {
+            let _ = writeln!(self.sink, "Scanned {}/{}", scanned, self.total);
+        }
+    }
+}
+
+/// Scans the books path, stores new highlights and reports progress to `log`.
+///
+/// Unreadable or unparseable files are reported and skipped, unless
+/// `config.fail_fast` is set, in which case the first one aborts the run.
+pub fn run(config: &Config, log: &mut dyn Write) -> Result<RunStats, RunError> {
+    let mut stderr = std::io::stderr();
+    let show_progress = !config.quiet && stderr.is_terminal();
+    let log_is_terminal = if config.logs_to_stderr() {
+        stderr.is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    let paint = Paint::new(config.color, log_is_terminal, color::no_color_requested());
+    if let Some(notice) = default_range_notice(config) {
+        let _ = writeln!(stderr, "{}", notice);
+    }
+    let progress: Option<&mut dyn Write> = show_progress.then_some(&mut stderr);
+
+    run_with_progress(config, log, progress, paint)
+}
+
+/// The reminder shown when no date flag was given, since importing only the
+/// past week surprises people on their first run. `None` when dates were
+/// given or the notice was turned off.
+pub fn default_range_notice(config: &Config) -> Option<String> {
+    if !config.date_defaulted || config.quiet || config.no_notice {
+        return None;
+    }
+    Some(format!(
+        "Note: no dates given, so only highlights from {} are imported. \
+         Use --from/--to or --last to pick another range.",
+        config.date_filter
+    ))
+}
+
+/// Like `run`, with the scan counter written to `progress` (if any) and the
+/// summary colored by `paint`.
+pub fn run_with_progress(
+    config: &Config,
+    log: &mut dyn Write,
+    progress: Option<&mut dyn Write>,
+    paint: Paint,
+) -> Result<RunStats, RunError> {
+    let _ = writeln!(log, "Books path: {}", config.books_path);
+    let _ = writeln!(log, "Database: {}", config.database_path);
+    let _ = writeln!(log, "Period: {}", config.date_filter);
+    if config.discards_import() {
+        let _ = writeln!(
+            log,
+            "Warning: the database is in memory, so nothing imported will be kept. \
+             Pass a file to --database-path, or an export --format."
+        );
+    }
+    let _ = writeln!(log);
+
+    let conn = if config.no_db {
+        None
+    } else {
+        // a directory that couldn't be created is reported by validate
+        let _ = config.create_database_dir();
+        config.validate()?;
+        let path = Path::new(&config.database_path);
+        // before anything is migrated, imported or pruned, and fatal when it fails
+        if let Some(dest) = &config.backup {
+            db::backup_file(path, Path::new(dest))?;
+            let _ = writeln!(log, "Backed up the database to {}", dest);
+        }
+        Some(db::init_db(path, &config.table)?)
+    };
+
+    // an empty directory is fine, a missing one is almost certainly a typo
+    // or an unmounted device
+    let books_path = Path::new(&config.books_path);
+    if !books_path.exists() {
+        return Err(RunError::BooksPathMissing(books_path.to_path_buf()));
+    }
+
+    let files = parser::find_metadata_files(books_path);
+    let _ = writeln!(log, "Found {} metadata files", files.len());
+
+    let total = files.len();
+    let mut progress = progress.map(|sink| Progress::new(sink, total, PROGRESS_EVERY));
+    let mut stats = RunStats::default();
+
+    for file in files {
+        stats.files_scanned += 1;
+        if let Some(p) = progress.as_mut() {
+            p.tick(stats.files_scanned);
+        }
+
+        process_file(conn.as_ref(), config, &file, &mut stats, log)?;
+    }
+
+    let _ = writeln!(log, "\n---");
+    let _ = writeln!(log, "Highlights found: {}", stats.highlights_found);
+    let _ = writeln!(log, "New highlights saved: {}", paint.green(stats.highlights_inserted));
+    let _ = writeln!(
+        log,
+        "{}",
+        paint.dim(format!("Already saved: {}", stats.highlights_duplicate))
+    );
+    if config.limit.is_some() {
+        let _ = writeln!(log, "Skipped by --limit: {}", stats.highlights_over_limit);
+    }
+    let _ = writeln!(log, "Read errors: {}", stats.report.read_errors);
+    let _ = writeln!(log, "Parse errors: {}", stats.report.parse_errors);
+    if config.strict {
+        let _ = writeln!(log, "Invalid annotations: {}", stats.report.invalid_annotations);
+    }
+    if config.by_day {
+        let _ = writeln!(log, "\nHighlights per day:");
+        for (day, count) in days_in_period(&config.date_filter, &stats.by_day) {
+            let _ = writeln!(log, "  {}  {}", day, count);
+        }
+    }
+
+    let Some(conn) = conn else {
+        return Ok(stats);
+    };
+
+    if let Some(before) = config.prune_before {
+        match db::prune(&conn, &config.table, before) {
+            Ok(removed) => {
+                let _ = writeln!(log, "Pruned highlights before {}: {}", before, removed);
+            }
+            Err(e) => eprintln!("Failed to prune: {}", e),
+        }
+    }
+
+    if config.prune_missing {
+        match db::prune_missing(&conn, &config.table) {
+            Ok(removed) => {
+                let _ = writeln!(log, "Pruned highlights of removed books: {}", removed);
+            }
+            Err(e) => eprintln!("Failed to prune: {}", e),
+        }
+    }
+
+    if config.reindex {
+        if let Err(e) = db::reindex_fts(&conn, &config.table) {
+            eprintln!("Failed to rebuild the search index: {}", e);
+        }
+    }
+
+    // there's no file to shrink behind an in-memory database
+    if config.vacuum && config.database_path != ":memory:" {
+        if let Err(e) = db::vacuum(&conn) {
+            eprintln!("Failed to vacuum: {}", e);
+        }
+    }
+
+    if config.stats {
+        match db::stats(&conn, &config.table) {
+            Ok(totals) => {
+                let _ = writeln!(log, "\nStored highlights: {}", totals.highlights);
+                let _ = writeln!(log, "Books: {}", totals.books);
+                let _ = writeln!(log, "Highlighted words: {}", totals.words);
+            }
+            Err(e) => eprintln!("Failed to read stats: {}", e),
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Creates or migrates the database for `--init-only` without scanning
+/// anything, returning the schema version.
+pub fn init_only(config: &Config, log: &mut dyn Write) -> Result<i32, RunError> {
+    let _ = config.create_database_dir();
+    config.validate()?;
+    let conn = db::init_db(Path::new(&config.database_path), &config.table)?;
+    let version = db::schema_version(&conn)?;
+
+    let _ = writeln!(log, "Database: {}", config.database_path);
+    let _ = writeln!(log, "Schema version: {}", version);
+    Ok(version)
+}
+
+/// Prints the last `n` stored highlights for `--recent`, newest first and in
+/// the same format as an import.
+pub fn print_recent(config: &Config, n: usize, log: &mut dyn Write) -> Result<(), RunError> {
+    let conn = db::open_read_only(Path::new(&config.database_path))?;
+    let mut current: Option<(String, String)> = None;
+
+    for stored in db::recent(&conn, &config.table, n)? {
+        let book = (stored.book_title, stored.book_author);
+        if current.as_ref() != Some(&book) {
+            let _ = writeln!(log, "\n{} by {}", book.0, book.1);
+        }
+        let h = &stored.highlight;
+        let _ = writeln!(log, "  + p.{}: {}", h.page, preview(shown_text(h), config.preview_len));
+        current = Some(book);
+    }
+
+    Ok(())
+}
+
+/// Reads the stored highlights back as books for `--from-db`, applying the
+/// same book, date and sort options as an import. With `--since-id` only
+/// newer rows are read, the date range is ignored, and the highest id read
+/// (or the given one, if nothing is newer) comes back alongside the books.
+pub fn books_from_db(config: &Config) -> Result<(Vec<BookData>, Option<i64>), RunError> {
+    let conn = db::open_read_only(Path::new(&config.database_path))?;
+    let mut books: Vec<BookData> = Vec::new();
+
+    let stored = match (config.since_id, config.date_filter) {
+        (Some(id), _) => db::highlights_after_id(&conn, &config.table, id)?,
+        (None, DateFilter::Range(from, to)) => {
+            db::highlights_between(&conn, &config.table, from, to)?
+        }
+        (None, DateFilter::SinceDatetime(_)) => db::export_all(&conn, &config.table)?,
+    };
+    let max_id = config
+        .since_id
+        .map(|since| stored.iter().map(|s| s.id).fold(since, i64::max));
+
+    for stored in stored {
+        let same_book = books
+            .last()
+            .is_some_and(|b| b.title == stored.book_title && b.author == stored.book_author);
+        if !same_book {
+            books.push(BookData {
+                title: stored.book_title,
+                author: stored.book_author,
+                doc_format: stored.book_doc_format,
+                ..Default::default()
+            });
+        }
+        if let Some(book) = books.last_mut() {
+            book.highlights.push(stored.highlight);
+        }
+    }
+
+    books.retain(|book| config.book_filter.matches(book));
+    for book in &mut books {
+        if config.since_id.is_none() {
+            book.highlights = config.date_filter.apply(std::mem::take(&mut book.highlights));
+        }
+        book.highlights.retain(|h| config.page_range.matches(h));
+        if let Some(order) = config.sort {
+            parser::sort_highlights(&mut book.highlights, order);
+        }
+    }
+    books.retain(|book| !book.highlights.is_empty());
+
+    Ok((books, max_id))
+}
+
+/// Every day of the period with its count, quiet days included. A period
+/// that starts at a moment runs from that day to the last day with highlights.
+pub fn days_in_period(
+    filter: &DateFilter,
+    counts: &BTreeMap<NaiveDate, usize>,
+) -> Vec<(NaiveDate, usize)> {
+    let (first, last) = match *filter {
+        DateFilter::Range(from, to) => (from, to),
+        DateFilter::SinceDatetime(since) => match counts.keys().next_back() {
+            Some(&last) => (since.date(), last),
+            None => return Vec::new(),
+        },
+    };
+
+    first
+        .iter_days()
+        .take_while(|day| *day <= last)
+        .map(|day| (day, counts.get(&day).copied().unwrap_or(0)))
+        .collect()
+}
+
+/// The order a book's highlights are listed in on the console: as sorted,
+/// or the other way round with `--reverse`. Exports keep the sorted order.
+pub fn console_order(highlights: &[Highlight], reverse: bool) -> Vec<&Highlight> {
+    let mut ordered: Vec<&Highlight> = highlights.iter().collect();
+    if reverse {
+        ordered.reverse();
+    }
+    ordered
+}
+
+fn parse_options(config: &Config) -> parser::ParseOptions {
+    parser::ParseOptions {
+        include_bookmarks: config.include_bookmarks,
+        clean_chapters: config.clean_chapters,
+        merge_adjacent: config.merge_adjacent,
+        infer_chapters: config.infer_chapters,
+        dedup_by: config.dedup_by,
+        author_format: config.author_format,
+        normalize_newlines: config.normalize_newlines,
+        detect_language: config.detect_language,
+        strict: config.strict,
+        title_from_path: config.title_from_path,
+        store_raw: config.store_raw,
+    }
+}
+
+/// Imports one metadata file, adding what happened to `stats`. Only returns an
+/// error when `config.fail_fast` is set; otherwise problems are reported and
+/// the file is skipped. Without a `conn` (`--no-db`) nothing is stored and
+/// the book is only collected into `stats.books`.
+pub fn process_file(
+    conn: Option<&Connection>,
+    config: &Config,
+    file: &Path,
+    stats: &mut RunStats,
+    log: &mut dyn Write,
+) -> Result<(), RunError> {
+    let mut book = match parser::parse_metadata_file_checked(file, &parse_options(config)) {
+        Ok((b, skipped)) => {
+            if config.strict {
+                for s in &skipped {
+                    eprintln!("Invalid {} in {}", s, file.display());
+                }
+                stats.report.invalid_annotations += skipped.len();
+            }
+            b
+        }
+        Err(e) => {
+            match e {
+                ProcessError::Read(..) => stats.report.read_errors += 1,
+                ProcessError::Parse(..) => stats.report.parse_errors += 1,
+            }
+            let error = RunError::from(e);
+            if config.fail_fast {
+                return Err(error);
+            }
+            eprintln!("{}", error);
+            return Ok(());
+        }
+    };
+
+    if !config.book_filter.matches(&book) {
+        return Ok(());
+    }
+
+    let highlights = std::mem::take(&mut book.highlights);
+    let mut filtered = config.date_filter.apply(highlights);
+    filtered.retain(|h| config.page_range.matches(h));
+    if let Some(order) = config.sort {
+        parser::sort_highlights(&mut filtered, order);
+    }
+
+    if filtered.is_empty() {
+        return Ok(());
+    }
+    for (day, count) in parser::count_by_day(&filtered) {
+        *stats.by_day.entry(day).or_insert(0) += count;
+    }
+
+    let _ = writeln!(log, "\n{} by {}", book.title, book.author);
+
+    match conn {
+        Some(conn) => {
+            match store_highlights(conn, config, file, &book, &filtered, stats, log) {
+                Ok(()) => {}
+                // asked for with --on-duplicate error, so it ends the run
+                Err(e @ DbError::DuplicateHighlight(_)) => return Err(RunError::Database(e)),
+                Err(e) => {
+                    eprintln!("  Failed to store book: {}", e);
+                    return Ok(());
+                }
+            }
+        }
+        None => stats.highlights_found += filtered.len(),
+    }
+
+    if config.format.is_some() || conn.is_none() {
+        stats.books.push(BookData {
+            highlights: filtered,
+            ..book
+        });
+    }
+
+    Ok(())
+}
+
+/// What the console shows for `h`: bookmarks and page notes have no text, so
+/// it's their note instead.
+fn shown_text(h: &Highlight) -> &str {
+    match h.kind {
+        HighlightKind::NoteOnly | HighlightKind::Bookmark => h.note.as_deref().unwrap_or_default(),
+        HighlightKind::Highlight => &h.text,
+    }
+}
+
+/// The first `len` characters of `text`, with an ellipsis only when
+/// something was cut off. A `len` of 0 keeps the whole text.
+pub fn preview(text: &str, len: usize) -> String {
+    match text.char_indices().nth(len) {
+        Some((end, _)) if len > 0 => format!("{}...", &text[..end]),
+        _ => text.to_string(),
+    }
+}
+
+/// Saves the book and its new highlights, logging a preview of each.
+fn store_highlights(
+    conn: &Connection,
+    config: &Config,
+    file: &Path,
+    book: &BookData,
+    highlights: &[Highlight],
+    stats: &mut RunStats,
+    log: &mut dyn Write,
+) -> Result<(), DbError> {
+    let book_id = db::upsert_book(conn, book, config.book_match)?;
+
+    for h in console_order(highlights, config.reverse) {
+        stats.highlights_found += 1;
+
+        if config.limit.is_some_and(|limit| stats.highlights_inserted >= limit) {
+            stats.highlights_over_limit += 1;
+            continue;
+        }
+
+        let full = h;
+        let cut;
+        let h = match config.max_text_len {
+            Some(max_len) => {
+                let mut long = h.clone();
+                long.truncate_text(max_len);
+                cut = long;
+                &cut
+            }
+            None => h,
+        };
+
+        let shown = shown_text(h);
+
+        // the database cuts the text itself, keeping the full text's identity
+        let (table, policy) = (&config.table, config.on_duplicate);
+        let max_len = config.max_text_len;
+        match db::insert_highlight_cut(conn, table, book_id, full, max_len, Some(file), policy) {
+            Ok(true) => {
+                stats.highlights_inserted += 1;
+                let _ = writeln!(log, "  + p.{}: {}", h.page, preview(shown, config.preview_len));
+            }
+            Ok(false) => {
+                stats.highlights_duplicate += 1;
+                if config.show_duplicates {
+                    let shown = preview(shown, config.preview_len);
+                    let _ = writeln!(log, "  = p.{}: {}", h.page, shown);
+                }
+            }
+            Err(e @ DbError::DuplicateHighlight(_)) => return Err(e),
+            Err(e) => {
+                eprintln!("  Failed to insert: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CliArgs;
+    use chrono::NaiveDate;
+    use std::fs;
+
+    const GOOD_LUA: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 42,
+            ["text"] = "A good highlight",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Good Book",
+        ["authors"] = "Good Author",
+    },
+}
+"#;
+
+    fn write_book(root: &Path, name: &str, content: &str) {
+        let sdr = root.join(format!("{}.sdr", name));
+        fs::create_dir_all(&sdr).unwrap();
+        fs::write(sdr.join("metadata.epub.lua"), content).unwrap();
+    }
+
+    fn make_config(books_path: &Path) -> Config {
+        let cli = CliArgs {
+            books_path: Some(books_path.to_string_lossy().into_owned()),
+            database_path: Some(":memory:".to_string()),
+            from: Some("2026-01-01".to_string()),
+            to: Some("2026-01-31".to_string()),
+            ..Default::default()
+        };
+        Config::from_args(cli, NaiveDate::from_ymd_opt(2026, 2, 1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_days_in_period_fills_quiet_days() {
+        let day = |d: u32| NaiveDate::from_ymd_opt(2026, 1, d).unwrap();
+        let counts = BTreeMap::from([(day(2), 4), (day(4), 1)]);
+
+        let days = days_in_period(&DateFilter::Range(day(1), day(4)), &counts);
+
+        assert_eq!(days, vec![(day(1), 0), (day(2), 4), (day(3), 0), (day(4), 1)]);
+    }
+
+    #[test]
+    fn test_preview_only_marks_truncated_text() {
+        assert_eq!(preview("Short", 10), "Short");
+        assert_eq!(preview("Exactly10!", 10), "Exactly10!");
+        assert_eq!(preview("Ça va très bien", 7), "Ça va t...");
+        assert_eq!(preview("Kept in full", 0), "Kept in full");
+    }
+
+    #[test]
+    fn test_run_skips_malformed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write_book(dir.path(), "Good", GOOD_LUA);
+        write_book(dir.path(), "Broken", "return { this is not lua [[[");
+        let config = make_config(dir.path());
+
+        let stats = run(&config, &mut Vec::new()).unwrap();
+
+        assert_eq!(stats.files_scanned, 2);
+        assert_eq!(stats.highlights_inserted, 1);
+    }
+
+    #[test]
+    fn test_run_tallies_read_and_parse_errors_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        write_book(dir.path(), "Good", GOOD_LUA);
+        write_book(dir.path(), "Broken", "return { this is not lua [[[");
+        let sdr = dir.path().join("Latin1.sdr");
+        fs::create_dir_all(&sdr).unwrap();
+        fs::write(sdr.join("metadata.epub.lua"), b"return { [\"title\"] = \"Caf\xe9\" }").unwrap();
+        let config = make_config(dir.path());
+        let mut log = Vec::new();
+
+        let stats = run(&config, &mut log).unwrap();
+
+        assert_eq!(
+            stats.report,
+            ProcessReport {
+                read_errors: 1,
+                parse_errors: 1,
+                invalid_annotations: 0,
+            }
+        );
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.contains("Read errors: 1\nParse errors: 1\n"));
+    }
+
+    #[test]
+    fn test_strict_counts_invalid_annotations() {
+        let dir = tempfile::tempdir().unwrap();
+        let sdr = dir.path().join("Half.sdr");
+        fs::create_dir_all(&sdr).unwrap();
+        fs::write(
+            sdr.join("metadata.epub.lua"),
+            r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 3,
+            ["text"] = "Fine",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-25 10:31:00",
+            ["pageno"] = "three",
+            ["text"] = "Bad page",
+        },
+        [3] = {
+            ["pageno"] = 4,
+            ["text"] = "No time",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Half",
+    },
+}
+"#,
+        )
+        .unwrap();
+        let mut config = make_config(dir.path());
+        config.strict = true;
+
+        let mut log = Vec::new();
+        let stats = run(&config, &mut log).unwrap();
+
+        assert_eq!(stats.highlights_inserted, 1);
+        assert_eq!(stats.report.invalid_annotations, 2);
+        assert!(String::from_utf8(log).unwrap().contains("Invalid annotations: 2\n"));
+    }
+
+    #[test]
+    fn test_default_range_notice() {
+        let dir = tempfile::tempdir().unwrap();
+        let defaulted = Config::from_args(
+            CliArgs {
+                books_path: Some(dir.path().to_string_lossy().into_owned()),
+                ..Default::default()
+            },
+            NaiveDate::from_ymd_opt(2026, 2, 1).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            default_range_notice(&defaulted).as_deref(),
+            Some(
+                "Note: no dates given, so only highlights from 2026-01-25 to 2026-01-31 are \
+                 imported. Use --from/--to or --last to pick another range."
+            )
+        );
+        assert_eq!(default_range_notice(&Config { quiet: true, ..defaulted.clone() }), None);
+        assert_eq!(default_range_notice(&Config { no_notice: true, ..defaulted }), None);
+        // make_config passes explicit dates
+        assert_eq!(default_range_notice(&make_config(dir.path())), None);
+    }
+
+    #[test]
+    fn test_no_db_collects_books_without_a_database() {
+        let dir = tempfile::tempdir().unwrap();
+        write_book(dir.path(), "Good", GOOD_LUA);
+        let db_path = dir.path().join("highlights.db");
+        let mut config = make_config(dir.path());
+        config.database_path = db_path.to_string_lossy().into_owned();
+        config.no_db = true;
+        config.format = Some(crate::export::Format::Json);
+
+        let stats = run(&config, &mut Vec::new()).unwrap();
+
+        assert!(!db_path.exists());
+        assert_eq!(stats.highlights_found, 1);
+        assert_eq!(stats.highlights_inserted, 0);
+        let json = crate::export::render(crate::export::Format::Json, &stats.books, &config);
+        assert!(json.contains("A good highlight"));
+    }
+
+    #[test]
+    fn test_run_missing_books_path_is_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = make_config(&dir.path().join("not-mounted"));
+
+        let result = run(&config, &mut Vec::new());
+
+        match result {
+            Err(e @ RunError::BooksPathMissing(_)) => {
+                assert!(e.to_string().starts_with("Books path does not exist: "));
+                assert!(e.to_string().ends_with("not-mounted"));
+            }
+            other => panic!("expected a missing path error, got {:?}", other),
+        }
+        // an existing but empty directory is not an error
+        assert_eq!(run(&make_config(dir.path()), &mut Vec::new()).unwrap().files_scanned, 0);
+    }
+
+    #[test]
+    fn test_run_fail_fast_aborts_on_malformed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write_book(dir.path(), "Good", GOOD_LUA);
+        write_book(dir.path(), "Broken", "return { this is not lua [[[");
+        let mut config = make_config(dir.path());
+        config.fail_fast = true;
+
+        let result = run(&config, &mut Vec::new());
+
+        match result {
+            Err(RunError::ParseFailed(path, ParseError::InvalidLua(_))) => {
+                assert!(path.ends_with("Broken.sdr/metadata.epub.lua"));
+            }
+            other => panic!("expected a parse failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_limit_caps_insertions() {
+        let dir = tempfile::tempdir().unwrap();
+        write_book(dir.path(), "One", GOOD_LUA);
+        write_book(dir.path(), "Two", &GOOD_LUA.replace("Good Book", "Other Book"));
+        write_book(dir.path(), "Three", &GOOD_LUA.replace("Good Book", "Third Book"));
+        let mut config = make_config(dir.path());
+        config.limit = Some(2);
+        let mut log = Vec::new();
+
+        let stats = run(&config, &mut log).unwrap();
+
+        assert_eq!(stats.highlights_found, 3);
+        assert_eq!(stats.highlights_inserted, 2);
+        assert_eq!(stats.highlights_over_limit, 1);
+        assert!(String::from_utf8(log).unwrap().contains("Skipped by --limit: 1\n"));
+    }
+
+    #[test]
+    fn test_show_duplicates_lists_skipped_highlights() {
+        let books_dir = tempfile::tempdir().unwrap();
+        let db_dir = tempfile::tempdir().unwrap();
+        write_book(books_dir.path(), "Good", GOOD_LUA);
+        let mut config = make_config(books_dir.path());
+        config.database_path = db_dir.path().join("h.db").to_string_lossy().into_owned();
+
+        let mut log = Vec::new();
+        run(&config, &mut log).unwrap();
+        run(&config, &mut log).unwrap();
+        assert!(!String::from_utf8(log).unwrap().contains("  = p.42"));
+
+        config.show_duplicates = true;
+        let mut log = Vec::new();
+        let stats = run(&config, &mut log).unwrap();
+
+        assert_eq!(stats.highlights_duplicate, 1);
+        assert!(String::from_utf8(log).unwrap().contains("  = p.42: A good highlight\n"));
+    }
+
+    #[test]
+    fn test_max_text_len_truncates_stored_text() {
+        let books_dir = tempfile::tempdir().unwrap();
+        let db_dir = tempfile::tempdir().unwrap();
+        let short = r#"        [2] = {
+            ["datetime"] = "2026-01-26 10:30:00",
+            ["pageno"] = 43,
+            ["text"] = "Short",
+        },
+    },
+    ["doc_props"]"#;
+        let lua = GOOD_LUA.replacen("    },\n    [\"doc_props\"]", short, 1);
+        write_book(books_dir.path(), "Good", &lua);
+        let mut config = make_config(books_dir.path());
+        config.database_path = db_dir.path().join("h.db").to_string_lossy().into_owned();
+        config.max_text_len = Some(6);
+
+        run(&config, &mut Vec::new()).unwrap();
+
+        let conn = db::open_read_only(Path::new(&config.database_path)).unwrap();
+        let stored = db::export_all(&conn, &config.table).unwrap();
+        assert_eq!(stored[0].highlight.text, "A good...");
+        assert!(stored[0].highlight.truncated);
+        assert_eq!(stored[1].highlight.text, "Short");
+        assert!(!stored[1].highlight.truncated);
+    }
+
+    #[test]
+    fn test_detect_language_stores_book_language() {
+        let books_dir = tempfile::tempdir().unwrap();
+        let db_dir = tempfile::tempdir().unwrap();
+        let lua = GOOD_LUA.replace(
+            "[\"authors\"] = \"Good Author\",",
+            "[\"authors\"] = \"Good Author\",\n        [\"language\"] = \"pt-BR\",",
+        );
+        write_book(books_dir.path(), "Good", &lua);
+        let mut config = make_config(books_dir.path());
+        config.database_path = db_dir.path().join("h.db").to_string_lossy().into_owned();
+        config.detect_language = true;
+
+        run(&config, &mut Vec::new()).unwrap();
+
+        let conn = db::open_read_only(Path::new(&config.database_path)).unwrap();
+        let stored = db::export_all(&conn, &config.table).unwrap();
+        assert_eq!(stored[0].highlight.lang.as_deref(), Some("pt-BR"));
+
+        // a quote in another language gets its own tag
+        let quote = lua.replace("A good highlight", "It was the best of times, it was the worst");
+        let book = parser::parse_metadata_with(&quote, "x", &parse_options(&config)).unwrap();
+        assert_eq!(book.highlights[0].lang.as_deref(), Some("en"));
+
+        config.detect_language = false;
+        let book = parser::parse_metadata_with(&lua, "x", &parse_options(&config)).unwrap();
+        assert_eq!(book.highlights[0].lang, None);
+    }
+
+    #[test]
+    fn test_books_from_db_since_id_exports_newer_rows() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut config = make_config(db_dir.path());
+        config.database_path = db_dir.path().join("h.db").to_string_lossy().into_owned();
+        let conn = db::init_db(Path::new(&config.database_path), &config.table).unwrap();
+        let book = BookData {
+            title: "Stored".to_string(),
+            author: "Author".to_string(),
+            ..Default::default()
+        };
+        let book_id = db::upsert_book(&conn, &book, config.book_match).unwrap();
+        for page in 1..=4 {
+            let h = Highlight {
+                page,
+                text: format!("Page {}", page),
+                ..Default::default()
+            };
+            db::insert_highlight(&conn, &config.table, book_id, &h, None).unwrap();
+        }
+        config.from_db = true;
+        config.since_id = Some(2);
+
+        let (books, max_id) = books_from_db(&config).unwrap();
+
+        let texts: Vec<&str> = books[0].highlights.iter().map(|h| h.text.as_str()).collect();
+        assert_eq!(texts, vec!["Page 3", "Page 4"]);
+        assert_eq!(max_id, Some(4));
+
+        config.since_id = Some(4);
+        let (books, max_id) = books_from_db(&config).unwrap();
+        assert!(books.is_empty());
+        assert_eq!(max_id, Some(4));
+    }
+
+    #[test]
+    fn test_doc_format_is_stored_and_filtered() {
+        let books_dir = tempfile::tempdir().unwrap();
+        write_book(books_dir.path(), "Epub", GOOD_LUA);
+        let pdf = books_dir.path().join("Scan.sdr");
+        fs::create_dir_all(&pdf).unwrap();
+        let pdf_lua = GOOD_LUA.replace("Good Book", "Scanned Book");
+        fs::write(pdf.join("metadata.pdf.lua"), pdf_lua).unwrap();
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut config = make_config(books_dir.path());
+        config.database_path = db_dir.path().join("h.db").to_string_lossy().into_owned();
+        let stats = run(&config, &mut Vec::new()).unwrap();
+        assert_eq!(stats.highlights_inserted, 2);
+
+        config.from_db = true;
+        config.book_filter.doc_format = Some("pdf".to_string());
+        let (books, _) = books_from_db(&config).unwrap();
+
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].title, "Scanned Book");
+        assert_eq!(books[0].doc_format.as_deref(), Some("pdf"));
+    }
+
+    #[test]
+    fn test_init_only_creates_empty_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fresh.db");
+        let mut config = make_config(&dir.path().join("no-books-here"));
+        config.database_path = path.to_string_lossy().into_owned();
+        let mut log = Vec::new();
+
+        let version = init_only(&config, &mut log).unwrap();
+
+        assert_eq!(version, db::SCHEMA_VERSION);
+        assert!(path.exists());
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.contains(&format!("Database: {}", path.display())));
+        assert!(log.contains(&format!("Schema version: {}", db::SCHEMA_VERSION)));
+
+        let conn = Connection::open(&path).unwrap();
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(tables.contains(&"books".to_string()));
+        assert!(tables.contains(&"highlights".to_string()));
+        let count: i64 =
+            conn.query_row("SELECT COUNT(*) FROM highlights", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_duplicate_error_ends_the_run() {
+        let books = tempfile::tempdir().unwrap();
+        write_book(books.path(), "Good", GOOD_LUA);
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut config = make_config(books.path());
+        config.database_path = db_dir.path().join("h.db").to_string_lossy().into_owned();
+        config.on_duplicate = db::DuplicatePolicy::Error;
+
+        run(&config, &mut Vec::new()).unwrap();
+        let result = run(&config, &mut Vec::new());
+
+        assert!(matches!(result, Err(RunError::Database(DbError::DuplicateHighlight(_)))));
+    }
+
+    #[test]
+    fn test_backup_is_taken_before_migrating() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE highlights (
+                    id INTEGER PRIMARY KEY,
+                    book_title TEXT NOT NULL,
+                    book_author TEXT NOT NULL,
+                    chapter TEXT,
+                    page INTEGER NOT NULL,
+                    text TEXT NOT NULL,
+                    note TEXT,
+                    datetime TEXT NOT NULL,
+                    processed INTEGER DEFAULT 0,
+                    created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+                    UNIQUE(book_title, page, text)
+                );
+                INSERT INTO highlights (book_title, book_author, page, text, datetime)
+                VALUES ('Old Book', 'Author', 1, 'old', '2026-01-01 10:00:00');",
+            )
+            .unwrap();
+        let books = dir.path().join("books");
+        fs::create_dir_all(&books).unwrap();
+        let dest = dir.path().join("backup.db");
+        let mut config = make_config(&books);
+        config.database_path = path.to_string_lossy().into_owned();
+        config.backup = Some(dest.to_string_lossy().into_owned());
+
+        run(&config, &mut Vec::new()).unwrap();
+
+        let copy = db::open_read_only(&dest).unwrap();
+        let title: String = copy
+            .query_row("SELECT book_title FROM highlights", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(title, "Old Book");
+        assert_eq!(db::schema_version(&copy).unwrap(), 0);
+        // while the database itself was migrated
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(db::schema_version(&conn).unwrap(), db::SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_books_from_db_exports_stored_highlights() {
+        let books_dir = tempfile::tempdir().unwrap();
+        write_book(books_dir.path(), "Good", GOOD_LUA);
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut config = make_config(books_dir.path());
+        config.database_path = db_dir.path().join("h.db").to_string_lossy().into_owned();
+        run(&config, &mut Vec::new()).unwrap();
+        // the file is gone, but its highlight is still in the database
+        fs::remove_dir_all(books_dir.path().join("Good.sdr")).unwrap();
+        config.from_db = true;
+        config.format = Some(crate::export::Format::Json);
+
+        let (books, max_id) = books_from_db(&config).unwrap();
+        assert_eq!(max_id, None);
+        let json = crate::export::render(crate::export::Format::Json, &books, &config);
+
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.as_array().unwrap().len(), 1);
+        assert_eq!(parsed[0]["title"], "Good Book");
+        assert_eq!(parsed[0]["author"], "Good Author");
+        assert_eq!(parsed[0]["text"], "A good highlight");
+    }
+
+    #[test]
+    fn test_run_warns_about_import_into_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = make_config(dir.path());
+        let mut log = Vec::new();
+
+        run(&config, &mut log).unwrap();
+        assert!(String::from_utf8(log).unwrap().contains("Warning: the database is in memory"));
+
+        config.format = Some(crate::export::Format::Csv);
+        let mut log = Vec::new();
+        run(&config, &mut log).unwrap();
+        assert!(!String::from_utf8(log).unwrap().contains("Warning"));
+    }
+
+    #[test]
+    fn test_console_order_reverses_after_sorting() {
+        let mut highlights: Vec<Highlight> = [30, 10, 20]
+            .iter()
+            .map(|&page| Highlight {
+                page,
+                ..Default::default()
+            })
+            .collect();
+        parser::sort_highlights(&mut highlights, parser::SortOrder::Page);
+
+        let pages = |shown: Vec<&Highlight>| shown.iter().map(|h| h.page).collect::<Vec<_>>();
+        assert_eq!(pages(console_order(&highlights, false)), vec![10, 20, 30]);
+        assert_eq!(pages(console_order(&highlights, true)), vec![30, 20, 10]);
+    }
+
+    #[test]
+    fn test_progress_every_n_and_last() {
+        let mut sink = Vec::new();
+        let mut progress = Progress::new(&mut sink, 5, 2);
+
+        for scanned in 1..=5 {
+            progress.tick(scanned);
+        }
+
+        let printed = String::from_utf8(sink).unwrap();
+        assert_eq!(printed, "Scanned 2/5\nScanned 4/5\nScanned 5/5\n");
+    }
+
+    #[test]
+    fn test_run_reports_progress_to_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        write_book(dir.path(), "One", GOOD_LUA);
+        write_book(dir.path(), "Two", GOOD_LUA);
+        let config = make_config(dir.path());
+        let mut sink = Vec::new();
+
+        run_with_progress(&config, &mut Vec::new(), Some(&mut sink), Paint::plain()).unwrap();
+
+        assert_eq!(String::from_utf8(sink).unwrap(), "Scanned 2/2\n");
+    }
+}
@@ -0,0 +1,335 @@
+use crate::models::{BookData, Highlight, HighlightKey};
+use crate::parser::{self, ParseError, ParseOptions};
+use full_moon::ast::{Expression, Field, LastStmt, TableConstructor};
+use full_moon::node::Node;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The file KOReader keeps an EPUB's annotations in, inside its `.sdr`.
+pub const METADATA_FILE: &str = "metadata.epub.lua";
+
+/// Quotes `s` as a Lua string. The parser keeps string literals exactly as
+/// written, escapes included, so existing `\x` pairs are passed through and
+/// only bare quotes and line breaks are escaped.
+fn lua_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.push('\\');
+                out.push(chars.next().unwrap_or('\\'));
+            }
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Writes one `["key"] = value,` line of an annotation entry.
+fn push_field(out: &mut String, key: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "            [\"{}\"] = {},", key, value);
+}
+
+/// Writes annotation `index` for `h`, in KOReader's layout and indented to
+/// sit inside the `["annotations"]` table.
+fn push_entry(out: &mut String, index: usize, h: &Highlight) {
+    // epoch seconds are written back in KOReader's own format
+    let datetime = if parser::parse_datetime(&h.raw_datetime).is_some() {
+        h.raw_datetime.clone()
+    } else {
+        h.datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+    };
+
+    let _ = writeln!(out, "        [{}] = {{", index);
+    if let Some(chapter) = &h.chapter {
+        push_field(out, "chapter", lua_string(chapter));
+    }
+    push_field(out, "datetime", lua_string(&datetime));
+    if let Some(updated) = h.updated {
+        let updated = updated.format("%Y-%m-%d %H:%M:%S").to_string();
+        push_field(out, "datetime_updated", lua_string(&updated));
+    }
+    if let Some(note) = &h.note {
+        push_field(out, "note", lua_string(note));
+    }
+    push_field(out, "pageno", h.page);
+    if let Some(pos0) = &h.pos0 {
+        push_field(out, "pos0", lua_string(pos0));
+    }
+    if let Some(pos1) = &h.pos1 {
+        push_field(out, "pos1", lua_string(pos1));
+    }
+    if !h.text.is_empty() {
+        push_field(out, "text", lua_string(&h.text));
+    }
+    if h.text_edited {
+        push_field(out, "text_edited", true);
+    }
+    out.push_str("        },\n");
+}
+
+/// The `["annotations"]` table for `highlights`, in KOReader's layout and
+/// indented to sit directly under the returned table.
+pub fn serialize_annotations(highlights: &[Highlight]) -> String {
+    let mut out = String::from("{\n");
+    for (i, h) in highlights.iter().enumerate() {
+        push_entry(&mut out, i + 1, h);
+    }
+    out.push_str("    }");
+    out
+}
+
+/// A fresh metadata file holding just `book`'s annotations and properties.
+pub fn serialize_metadata(book: &BookData) -> String {
+    let mut out = String::from("return {\n");
+    let _ = writeln!(
+        out,
+        "    [\"annotations\"] = {},",
+        serialize_annotations(&book.highlights)
+    );
+    out.push_str("    [\"doc_props\"] = {\n");
+    let _ = writeln!(out, "        [\"authors\"] = {},", lua_string(&book.author));
+    if let Some(language) = &book.language {
+        let _ = writeln!(out, "        [\"language\"] = {},", lua_string(language));
+    }
+    let _ = writeln!(out, "        [\"title\"] = {},", lua_string(&book.title));
+    out.push_str("    },\n}\n");
+    out
+}
+
+/// The `[N]` index after the largest one in an annotations table, so an
+/// appended entry can't take the place of one that's there.
+fn next_index(entries: &TableConstructor) -> usize {
+    entries
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(position, field)| match field {
+            Field::ExpressionKey { key, .. } => parser::extract_number_from_expr(key)
+                .and_then(|n| usize::try_from(n).ok())
+                .unwrap_or(position + 1),
+            _ => position + 1,
+        })
+        .max()
+        .unwrap_or(0)
+        + 1
+}
+
+/// Adds `book`'s highlights to an existing metadata file. Annotations the
+/// file already has are kept byte for byte, with every field KOReader wrote
+/// (`drawer`, `color`, the `page` xpointer...), and only the highlights it
+/// lacks are appended. Everything else in `original` is kept too; a file
+/// without annotations gets them added at the top of the returned table.
+pub fn merge_metadata(
+    original: &str,
+    book: &BookData,
+    source_file: &str,
+) -> Result<String, ParseError> {
+    let content = original.strip_prefix('\u{FEFF}').unwrap_or(original);
+    let ast = full_moon::parse(content)
+        .map_err(|e| ParseError::InvalidLua(format!("{}: {}", source_file, e)))?;
+    let not_metadata = || ParseError::NotKoreaderMetadata(source_file.to_string());
+
+    let table = match ast.nodes().last_stmt() {
+        Some(LastStmt::Return(ret)) => ret.returns().iter().find_map(|e| match e {
+            Expression::TableConstructor(table) => Some(table),
+            _ => None,
+        }),
+        _ => None,
+    }
+    .ok_or_else(not_metadata)?;
+
+    let existing = table
+        .fields()
+        .iter()
+        .find_map(|field| match parser::field_key_value(field) {
+            Some((key, value)) if key == "annotations" => Some(value),
+            _ => None,
+        });
+
+    let (start, end, replacement) = match existing {
+        Some(Expression::TableConstructor(entries)) if !entries.fields().is_empty() => {
+            let options = ParseOptions::default();
+            let stored: HashSet<HighlightKey> =
+                parser::extract_annotations(entries, &options, &mut Vec::new())
+                    .iter()
+                    .map(Highlight::key)
+                    .collect();
+            let added: Vec<&Highlight> = book
+                .highlights
+                .iter()
+                .filter(|h| !stored.contains(&h.key()))
+                .collect();
+            if added.is_empty() {
+                return Ok(content.to_string());
+            }
+
+            let last = entries.fields().last().ok_or_else(not_metadata)?;
+            let at = last
+                .end_position()
+                .map(|p| p.bytes())
+                .ok_or_else(not_metadata)?;
+            let mut out = String::from(if last.punctuation().is_some() {
+                "\n"
+            } else {
+                ",\n"
+            });
+            let next = next_index(entries);
+            for (i, h) in added.into_iter().enumerate() {
+                push_entry(&mut out, next + i, h);
+            }
+            // the newline before the closing brace is already there
+            out.truncate(out.len() - 1);
+            (at, at, out)
+        }
+        Some(value) => {
+            let start = value.start_position().map(|p| p.bytes());
+            let end = value.end_position().map(|p| p.bytes());
+            match (start, end) {
+                (Some(start), Some(end)) => (start, end, serialize_annotations(&book.highlights)),
+                _ => return Err(not_metadata()),
+            }
+        }
+        None => {
+            let (open, _) = table.braces().tokens();
+            let at = open
+                .end_position()
+                .map(|p| p.bytes())
+                .ok_or_else(not_metadata)?;
+            (
+                at,
+                at,
+                format!(
+                    "\n    [\"annotations\"] = {},",
+                    serialize_annotations(&book.highlights)
+                ),
+            )
+        }
+    };
+
+    let mut merged = content.to_string();
+    merged.replace_range(start..end, &replacement);
+    Ok(merged)
+}
+
+/// Where to write: the metadata file inside `target` when it's a `.sdr`
+/// directory, otherwise `target` itself.
+pub fn metadata_path(target: &Path) -> PathBuf {
+    if target.is_dir() || target.extension().is_some_and(|ext| ext == "sdr") {
+        target.join(METADATA_FILE)
+    } else {
+        target.to_path_buf()
+    }
+}
+
+/// Writes `book`'s highlights into the metadata file at `target`, merging
+/// into it when it already exists.
+pub fn write_book(target: &Path, book: &BookData) -> io::Result<()> {
+    let path = metadata_path(target);
+    let lua = match fs::read_to_string(&path) {
+        Ok(original) => merge_metadata(&original, book, &path.to_string_lossy())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => serialize_metadata(book),
+        Err(e) => return Err(e),
+    };
+
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, lua)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    const FIXTURE: &str = r#"return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "Chapter 1",
+            ["color"] = "yellow",
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["drawer"] = "lighten",
+            ["page"] = "/body/DocFragment[2]/body/p[4]/text().0",
+            ["pageno"] = 42,
+            ["text"] = "An \"old\" highlight",
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Test Author",
+        ["title"] = "Test Book",
+    },
+    ["percent_finished"] = 0.42,
+}
+"#;
+
+    #[test]
+    fn test_merge_keeps_old_and_new_highlights() {
+        let mut book = parser::parse_metadata(FIXTURE, "Test.sdr/metadata.epub.lua").unwrap();
+        book.highlights.push(Highlight {
+            page: 50,
+            text: "A new\nhighlight".to_string(),
+            note: Some("Added later".to_string()),
+            datetime: NaiveDate::from_ymd_opt(2026, 2, 3)
+                .unwrap()
+                .and_hms_opt(9, 0, 0)
+                .unwrap(),
+            ..Default::default()
+        });
+
+        let merged = merge_metadata(FIXTURE, &book, "Test.sdr/metadata.epub.lua").unwrap();
+        assert!(merged.contains("[\"percent_finished\"] = 0.42,"));
+        let old_entry =
+            &FIXTURE[FIXTURE.find("        [1]").unwrap()..FIXTURE.find("    },").unwrap()];
+        assert!(merged.contains(old_entry));
+        assert!(merged.contains("[\"drawer\"] = \"lighten\","));
+        assert!(merged.contains("[\"color\"] = \"yellow\","));
+        assert!(merged.contains("[\"page\"] = \"/body/DocFragment[2]/body/p[4]/text().0\","));
+        assert!(merged.contains("        [2] = {"));
+
+        let reread = parser::parse_metadata(&merged, "Test.sdr/metadata.epub.lua").unwrap();
+        assert_eq!(reread.title, "Test Book");
+        let texts: Vec<&str> = reread.highlights.iter().map(|h| h.text.as_str()).collect();
+        assert_eq!(texts, vec![r#"An \"old\" highlight"#, r"A new\nhighlight"]);
+        assert_eq!(reread.highlights[0].chapter.as_deref(), Some("Chapter 1"));
+        assert_eq!(reread.highlights[1].note.as_deref(), Some("Added later"));
+        assert_eq!(reread.highlights[1].raw_datetime, "2026-02-03 09:00:00");
+    }
+
+    #[test]
+    fn test_merge_without_new_highlights_changes_nothing() {
+        let book = parser::parse_metadata(FIXTURE, "Test.sdr/metadata.epub.lua").unwrap();
+        let merged = merge_metadata(FIXTURE, &book, "Test.sdr/metadata.epub.lua").unwrap();
+        assert_eq!(merged, FIXTURE);
+    }
+
+    #[test]
+    fn test_write_book_adds_annotations_to_file_without_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let sdr = dir.path().join("Test.sdr");
+        fs::create_dir_all(&sdr).unwrap();
+        let original =
+            "return {\n    [\"doc_props\"] = {\n        [\"title\"] = \"Test Book\",\n    },\n}\n";
+        fs::write(sdr.join(METADATA_FILE), original).unwrap();
+
+        let mut book = parser::parse_metadata(FIXTURE, "x").unwrap();
+        book.title = "Ignored".to_string();
+        write_book(&sdr, &book).unwrap();
+
+        let written = fs::read_to_string(sdr.join(METADATA_FILE)).unwrap();
+        let reread = parser::parse_metadata(&written, "x").unwrap();
+        assert_eq!(reread.title, "Test Book");
+        assert_eq!(reread.highlights.len(), 1);
+        assert_eq!(reread.highlights[0].page, 42);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::config::Config;
+use crate::db;
+use crate::parser;
+use crate::runner::{self, RunError, RunStats};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long a file has to stay untouched before it's imported again. KOReader
+/// rewrites the metadata several times in a row, e.g. when closing a book.
+pub const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Collects the metadata files touched by filesystem events and hands them
+/// out once they've stopped changing.
+#[derive(Debug)]
+pub struct Debouncer {
+    delay: Duration,
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Debouncer {
+    pub fn new(delay: Duration) -> Self {
+        Debouncer {
+            delay,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Notes the metadata files written by `event`; other files and event
+    /// kinds are ignored.
+    pub fn record(&mut self, event: &Event, now: Instant) {
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+
+        for path in event.paths.iter().filter(|p| parser::is_metadata_file(p)) {
+            self.pending.insert(path.clone(), now);
+        }
+    }
+
+    /// Removes and returns the files left alone for at least the delay.
+    pub fn take_ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut ready: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, &changed)| now.duration_since(changed) >= self.delay)
+            .map(|(path, _)| path.clone())
+            .collect();
+        ready.sort();
+
+        for path in &ready {
+            self.pending.remove(path);
+        }
+        ready
+    }
+
+    /// How long until the next pending file settles, if anything is pending.
+    pub fn next_wait(&self, now: Instant) -> Option<Duration> {
+        self.pending
+            .values()
+            .map(|&changed| (changed + self.delay).saturating_duration_since(now))
+            .min()
+    }
+}
+
+/// Imports a batch of settled files, the same way the initial scan does,
+/// except that the period has no end: it was resolved at startup, and by
+/// default ends yesterday, before anything made while watching.
+pub fn process_changes(
+    conn: &Connection,
+    config: &Config,
+    files: &[PathBuf],
+    log: &mut dyn Write,
+) -> Result<RunStats, RunError> {
+    let config = Config {
+        date_filter: config.date_filter.open_ended(),
+        ..config.clone()
+    };
+    let mut stats = RunStats::default();
+
+    for file in files {
+        stats.files_scanned += 1;
+        runner::process_file(Some(conn), &config, file, &mut stats, log)?;
+    }
+
+    if stats.highlights_inserted > 0 {
+        let _ = writeln!(log, "New highlights saved: {}", stats.highlights_inserted);
+    }
+
+    Ok(stats)
+}
+
+/// Watches the books path and imports changed metadata files until the
+/// process is stopped.
+pub fn watch(config: &Config, log: &mut dyn Write) -> Result<(), RunError> {
+    let conn = db::init_db(Path::new(&config.database_path), &config.table)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(tx).map_err(|e| RunError::WatchFailed(e.to_string()))?;
+    watcher
+        .watch(Path::new(&config.books_path), RecursiveMode::Recursive)
+        .map_err(|e| RunError::WatchFailed(e.to_string()))?;
+
+    let _ = writeln!(
+        log,
+        "\nWatching {} for changes (Ctrl-C to stop)",
+        config.books_path
+    );
+
+    let mut debouncer = Debouncer::new(DEBOUNCE);
+    loop {
+        let wait = debouncer
+            .next_wait(Instant::now())
+            .unwrap_or(Duration::from_secs(60));
+
+        match rx.recv_timeout(wait) {
+            Ok(Ok(event)) => debouncer.record(&event, Instant::now()),
+            Ok(Err(e)) => eprintln!("Watch error: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let ready = debouncer.take_ready(Instant::now());
+        if !ready.is_empty() {
+            process_changes(&conn, config, &ready, log)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CliArgs;
+    use chrono::{Local, NaiveDate};
+    use notify::event::{CreateKind, DataChange, ModifyKind};
+    use std::fs;
+
+    const LUA: &str = r#"
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-25 10:30:00",
+            ["pageno"] = 42,
+            ["text"] = "Written while watching",
+        },
+    },
+    ["doc_props"] = {
+        ["title"] = "Watched Book",
+        ["authors"] = "Some Author",
+    },
+}
+"#;
+
+    #[test]
+    fn test_change_event_is_debounced_then_imported() {
+        let dir = tempfile::tempdir().unwrap();
+        let sdr = dir.path().join("Watched.sdr");
+        fs::create_dir_all(&sdr).unwrap();
+        let file = sdr.join("metadata.epub.lua");
+        fs::write(&file, LUA).unwrap();
+
+        let cli = CliArgs {
+            books_path: Some(dir.path().to_string_lossy().into_owned()),
+            database_path: Some(":memory:".to_string()),
+            from: Some("2026-01-01".to_string()),
+            to: Some("2026-01-31".to_string()),
+            ..Default::default()
+        };
+        let config = Config::from_args(cli, NaiveDate::from_ymd_opt(2026, 2, 1).unwrap()).unwrap();
+        let conn = db::init_db(Path::new(":memory:"), &config.table).unwrap();
+
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DEBOUNCE);
+        let write = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+            .add_path(file.clone());
+        let unrelated =
+            Event::new(EventKind::Create(CreateKind::File)).add_path(sdr.join("notes.txt"));
+        debouncer.record(&write, start);
+        debouncer.record(&unrelated, start);
+        // a second write pushes the import back
+        debouncer.record(&write, start + Duration::from_millis(300));
+
+        assert!(debouncer.take_ready(start + DEBOUNCE).is_empty());
+        let ready = debouncer.take_ready(start + Duration::from_millis(300) + DEBOUNCE);
+        assert_eq!(ready, vec![file]);
+        assert_eq!(debouncer.next_wait(start), None);
+
+        let stats = process_changes(&conn, &config, &ready, &mut Vec::new()).unwrap();
+        assert_eq!(stats.highlights_inserted, 1);
+    }
+
+    #[test]
+    fn test_highlight_made_while_watching_is_imported() {
+        let dir = tempfile::tempdir().unwrap();
+        let sdr = dir.path().join("Watched.sdr");
+        fs::create_dir_all(&sdr).unwrap();
+        let file = sdr.join("metadata.epub.lua");
+        let now = Local::now()
+            .naive_local()
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string();
+        fs::write(&file, LUA.replace("2026-01-25 10:30:00", &now)).unwrap();
+
+        // no date flags: the default week, which ends yesterday
+        let cli = CliArgs {
+            books_path: Some(dir.path().to_string_lossy().into_owned()),
+            database_path: Some(":memory:".to_string()),
+            ..Default::default()
+        };
+        let config = Config::from_args(cli, Local::now().date_naive()).unwrap();
+        let conn = db::init_db(Path::new(":memory:"), &config.table).unwrap();
+
+        let stats = process_changes(&conn, &config, &[file], &mut Vec::new()).unwrap();
+        assert_eq!(stats.highlights_inserted, 1);
+    }
+}
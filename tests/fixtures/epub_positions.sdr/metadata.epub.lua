@@ -0,0 +1,55 @@
+-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "I. The Beginning",
+            ["datetime"] = "2026-01-10 21:14:03",
+            ["drawer"] = "lighten",
+            ["color"] = "yellow",
+            ["page"] = "/body/DocFragment[4]/body/p[3]/text().0",
+            ["pageno"] = 7,
+            ["pos0"] = "/body/DocFragment[4]/body/p[3]/text().0",
+            ["pos1"] = "/body/DocFragment[4]/body/p[3]/text().58",
+            ["text"] = "It was a bright cold day in April, and the clocks struck.",
+        },
+        [2] = {
+            ["chapter"] = "II. The Middle",
+            ["datetime"] = "2026-01-12 08:02:44",
+            ["datetime_updated"] = "2026-01-13 19:30:00",
+            ["drawer"] = "underscore",
+            ["note"] = "Compare with chapter one.",
+            ["page"] = "/body/DocFragment[9]/body/p[12]/text().16",
+            ["pageno"] = 31,
+            ["pos0"] = "/body/DocFragment[9]/body/p[12]/text().16",
+            ["pos1"] = "/body/DocFragment[9]/body/p[12]/text().97",
+            ["text"] = "Nothing was your own except the few cubic centimetres inside your skull.",
+        },
+        [3] = {
+            ["chapter"] = "II. The Middle",
+            ["datetime"] = "2026-01-12 08:05:10",
+            ["page"] = "/body/DocFragment[9]/body/p[14]/text().0",
+            ["pageno"] = 32,
+            ["pos0"] = "/body/DocFragment[9]/body/p[14]/text().0",
+            ["pos1"] = "/body/DocFragment[9]/body/p[14]/text().40",
+            ["text"] = "Who controls the past controls the future.",
+            ["text_edited"] = true,
+        },
+    },
+    ["cre_dom_version"] = 20240114,
+    ["doc_pages"] = 412,
+    ["doc_props"] = {
+        ["authors"] = "George Orwell",
+        ["description"] = "A dystopian novel.",
+        ["identifiers"] = "uuid:0b6d5c0e-8a2f-4d7e-b1e3-4c2f8a9d7e61",
+        ["keywords"] = "Fiction\nClassics",
+        ["language"] = "en",
+        ["pages"] = 412,
+        ["series"] = "",
+        ["title"] = "Nineteen Eighty-Four",
+    },
+    ["percent_finished"] = 0.0825,
+    ["summary"] = {
+        ["modified"] = "2026-01-13",
+        ["status"] = "reading",
+    },
+}
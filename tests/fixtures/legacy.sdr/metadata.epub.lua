@@ -0,0 +1,23 @@
+-- we can read Lua syntax here!
+return {
+    ["doc_settings"] = {
+        ["annotations"] = {
+            [1] = {
+                ["chapter"] = "Chapter 3",
+                ["datetime"] = "2019-06-01 12:00:00",
+                ["page"] = 55,
+                ["text"] = "Stored by an old KOReader, one level down.",
+            },
+            [2] = {
+                ["datetime"] = "2019-06-02 07:45:30",
+                ["page"] = 61,
+                ["text"] = "Only the page field, no pageno.",
+            },
+        },
+        ["doc_props"] = {
+            ["authors"] = "Old Writer",
+            ["title"] = "A Legacy Book",
+        },
+    },
+    ["last_xpointer"] = "/body/DocFragment[5]/body/p[1]/text().0",
+}
@@ -0,0 +1,22 @@
+-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "Preface",
+            ["datetime"] = "2026-02-01 10:00:00",
+            ["pageno"] = 3,
+            ["text"] = "Programs must be written for people to read.",
+        },
+        [2] = {
+            ["chapter"] = "1.1 The Elements of Programming",
+            ["datetime"] = "2026-02-02 11:30:00",
+            ["note"] = "Primitive expressions, combination, abstraction.",
+            ["pageno"] = 0,
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Harold Abelson\nGerald Jay Sussman\nJulie Sussman",
+        ["language"] = "en",
+        ["title"] = "Structure and Interpretation of Computer Programs",
+    },
+}
@@ -0,0 +1,57 @@
+-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-05 14:00:12",
+            ["drawer"] = "lighten",
+            ["page"] = 12,
+            ["pageno"] = 12,
+            ["pboxes"] = {
+                [1] = {
+                    ["h"] = 11.52,
+                    ["w"] = 402.75,
+                    ["x"] = 72.0,
+                    ["y"] = 318.4375,
+                },
+            },
+            ["pos0"] = {
+                ["page"] = 12,
+                ["rotation"] = 0,
+                ["x"] = 72.5,
+                ["y"] = 320.25,
+                ["zoom"] = 1.3333333333333,
+            },
+            ["pos1"] = {
+                ["page"] = 12,
+                ["rotation"] = 0,
+                ["x"] = 474.75,
+                ["y"] = 329.0,
+                ["zoom"] = 1.3333333333333,
+            },
+            ["text"] = "The gradient vanishes as the depth of the network grows.",
+        },
+        [2] = {
+            ["datetime"] = "2026-01-06 09:41:55",
+            ["note"] = "Check the appendix proof.",
+            ["page"] = 48,
+            ["pageno"] = 48,
+            ["pos0"] = {
+                ["page"] = 48,
+                ["x"] = 90.125,
+                ["y"] = 101.5,
+            },
+            ["pos1"] = {
+                ["page"] = 48,
+                ["x"] = 310.0,
+                ["y"] = 114.75,
+            },
+            ["text"] = "Theorem 3 holds for any bounded activation.",
+        },
+    },
+    ["doc_pages"] = 96,
+    ["doc_props"] = {
+        ["authors"] = "A. Researcher",
+        ["title"] = "Deep Networks, Shallow Proofs",
+    },
+    ["percent_finished"] = 0.5,
+}
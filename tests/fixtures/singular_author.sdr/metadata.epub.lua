@@ -0,0 +1,14 @@
+-- converted with a third-party tool
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-04-02 20:15:00",
+            ["pageno"] = 9,
+            ["text"] = "Written by a converter that says author, not authors.",
+        },
+    },
+    ["doc_props"] = {
+        ["author"] = "Single Author",
+        ["title"] = "A Converted Book",
+    },
+}
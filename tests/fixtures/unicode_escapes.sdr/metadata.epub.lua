@@ -0,0 +1,23 @@
+-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "Première partie",
+            ["datetime"] = "2026-03-14 16:20:00",
+            ["pageno"] = 18,
+            ["text"] = "\u{00AB}Longtemps, je me suis couché de bonne heure.\u{00BB}",
+        },
+        [2] = {
+            ["chapter"] = "Première partie",
+            ["datetime"] = "2026-03-14 16:25:00",
+            ["note"] = "Line one\nline two \"quoted\"",
+            ["pageno"] = 19,
+            ["text"] = "Caf\195\169 cr\195\168me, \u{2014} et voil\u{E0}.",
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Marcel Proust",
+        ["language"] = "fr",
+        ["title"] = "Du côté de chez Swann",
+    },
+}
@@ -0,0 +1,177 @@
+use koreader_highlights::models::{BookData, HighlightKind};
+use koreader_highlights::parser;
+use std::path::{Path, PathBuf};
+
+/// What a fixture should parse to: the book, its highlight count, and the
+/// page and text of the first highlight.
+struct Expected {
+    title: &'static str,
+    author: &'static str,
+    doc_format: &'static str,
+    highlights: usize,
+    first_page: i32,
+    first_text: &'static str,
+}
+
+const EXPECTED: &[(&str, Expected)] = &[
+    (
+        "epub_positions.sdr",
+        Expected {
+            title: "Nineteen Eighty-Four",
+            author: "George Orwell",
+            doc_format: "epub",
+            highlights: 3,
+            first_page: 7,
+            first_text: "It was a bright cold day in April, and the clocks struck.",
+        },
+    ),
+    (
+        "legacy.sdr",
+        Expected {
+            title: "A Legacy Book",
+            author: "Old Writer",
+            doc_format: "epub",
+            highlights: 2,
+            first_page: 55,
+            first_text: "Stored by an old KOReader, one level down.",
+        },
+    ),
+    (
+        "multi_author.sdr",
+        Expected {
+            title: "Structure and Interpretation of Computer Programs",
+            author: r"Harold Abelson\nGerald Jay Sussman\nJulie Sussman",
+            doc_format: "epub",
+            highlights: 2,
+            first_page: 3,
+            first_text: "Programs must be written for people to read.",
+        },
+    ),
+    (
+        "pdf_floats.sdr",
+        Expected {
+            title: "Deep Networks, Shallow Proofs",
+            author: "A. Researcher",
+            doc_format: "pdf",
+            highlights: 2,
+            first_page: 12,
+            first_text: "The gradient vanishes as the depth of the network grows.",
+        },
+    ),
+    (
+        "singular_author.sdr",
+        Expected {
+            title: "A Converted Book",
+            author: "Single Author",
+            doc_format: "epub",
+            highlights: 1,
+            first_page: 9,
+            first_text: "Written by a converter that says author, not authors.",
+        },
+    ),
+    (
+        "unicode_escapes.sdr",
+        Expected {
+            title: "Du côté de chez Swann",
+            author: "Marcel Proust",
+            doc_format: "epub",
+            highlights: 2,
+            first_page: 18,
+            first_text: r"\u{00AB}Longtemps, je me suis couché de bonne heure.\u{00BB}",
+        },
+    ),
+];
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures")
+}
+
+/// Every metadata file under `tests/fixtures`, keyed by its `.sdr` directory.
+fn fixtures() -> Vec<(String, PathBuf)> {
+    parser::find_metadata_files(&fixtures_dir())
+        .into_iter()
+        .map(|path| {
+            let sdr = path.parent().and_then(Path::file_name).unwrap_or_default();
+            (sdr.to_string_lossy().into_owned(), path)
+        })
+        .collect()
+}
+
+fn parse_fixture(name: &str) -> BookData {
+    let path = fixtures_dir().join(name);
+    let file = parser::find_metadata_files(&path).pop().expect("fixture has a metadata file");
+    parser::parse_metadata_file(&file).unwrap()
+}
+
+#[test]
+fn test_every_fixture_parses_as_expected() {
+    let found = fixtures();
+    let mut names: Vec<&str> = found.iter().map(|(name, _)| name.as_str()).collect();
+    names.sort();
+    let listed: Vec<&str> = EXPECTED.iter().map(|(name, _)| *name).collect();
+    assert_eq!(names, listed, "every fixture needs an entry in EXPECTED");
+
+    for (name, path) in &found {
+        let (_, expected) = EXPECTED.iter().find(|(n, _)| n == name).unwrap();
+        let book = parser::parse_metadata_file(path)
+            .unwrap_or_else(|e| panic!("{} failed to parse: {}", name, e));
+
+        assert_eq!(book.title, expected.title, "{}", name);
+        assert_eq!(book.author, expected.author, "{}", name);
+        assert_eq!(book.doc_format.as_deref(), Some(expected.doc_format), "{}", name);
+        assert_eq!(book.highlights.len(), expected.highlights, "{}", name);
+        assert_eq!(book.highlights[0].page, expected.first_page, "{}", name);
+        assert_eq!(book.highlights[0].text, expected.first_text, "{}", name);
+    }
+}
+
+#[test]
+fn test_epub_positions_are_kept() {
+    let book = parse_fixture("epub_positions.sdr");
+
+    let first = &book.highlights[0];
+    assert_eq!(first.chapter.as_deref(), Some("I. The Beginning"));
+    assert_eq!(first.pos0.as_deref(), Some("/body/DocFragment[4]/body/p[3]/text().0"));
+    assert_eq!(first.pos1.as_deref(), Some("/body/DocFragment[4]/body/p[3]/text().58"));
+    assert_eq!(book.highlights[1].note.as_deref(), Some("Compare with chapter one."));
+    assert!(book.highlights[1].updated.is_some());
+    assert!(book.highlights[2].text_edited);
+    assert_eq!(book.language.as_deref(), Some("en"));
+}
+
+#[test]
+fn test_pdf_position_tables_are_skipped() {
+    let book = parse_fixture("pdf_floats.sdr");
+
+    assert!(book.highlights.iter().all(|h| h.pos0.is_none() && h.pos1.is_none()));
+    assert_eq!(book.highlights[1].page, 48);
+    assert_eq!(book.highlights[1].note.as_deref(), Some("Check the appendix proof."));
+}
+
+#[test]
+fn test_legacy_page_field_is_used() {
+    let book = parse_fixture("legacy.sdr");
+
+    assert_eq!(book.highlights[0].chapter.as_deref(), Some("Chapter 3"));
+    assert_eq!(book.highlights[1].page, 61);
+    assert_eq!(book.highlights[1].raw_datetime, "2019-06-02 07:45:30");
+}
+
+#[test]
+fn test_note_without_text_is_note_only() {
+    let book = parse_fixture("multi_author.sdr");
+
+    let note = &book.highlights[1];
+    assert_eq!(note.kind, HighlightKind::NoteOnly);
+    assert_eq!(note.page, 0);
+    assert!(note.text.is_empty());
+}
+
+#[test]
+fn test_escapes_are_kept_as_written() {
+    let book = parse_fixture("unicode_escapes.sdr");
+
+    assert_eq!(book.highlights[0].chapter.as_deref(), Some("Première partie"));
+    assert_eq!(book.highlights[1].text, r"Caf\195\169 cr\195\168me, \u{2014} et voil\u{E0}.");
+    assert_eq!(book.highlights[1].note.as_deref(), Some(r#"Line one\nline two \"quoted\""#));
+}